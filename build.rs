@@ -0,0 +1,7 @@
+// Generate the gRPC service from its protobuf definition, with a bundled protoc so no system install is needed
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    tonic_build::compile_protos("proto/clara.proto")?;
+
+    Ok(())
+}
@@ -0,0 +1,163 @@
+// Import time handling
+use std::time::Duration;
+
+// Import date handling
+use chrono::{DateTime, NaiveDate};
+// Import error handling
+use anyhow::{anyhow, Result};
+
+// Import the pause between backfilled mentions
+use tokio::time::sleep;
+
+// Import the handler, ledger states and the queue holding the dead letters
+use crate::{
+    handler::{Handler, Stores},
+    ledger::MentionStatus,
+    queue::MentionQueue,
+};
+
+// Usage of the admin commands
+const USAGE: &str = "Usage: clara [serve] | clara replay <tweet_id> [--force] | \
+                     clara backfill --since <YYYY-MM-DD> [--per-hour <n>] | clara dead-letters list | \
+                     retry <tweet_id|--all> | discard <tweet_id|--all>";
+// Backfilled mentions handled per hour unless --per-hour is given
+const DEFAULT_BACKFILL_PER_HOUR: u32 = 60;
+
+// Run an admin command given on the command line, e.g. `clara dead-letters list`
+pub fn run(args: &[String], queue_file: &str) -> Result<()> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let queue = MentionQueue::open(queue_file)?;
+
+    match args.as_slice() {
+        ["dead-letters", "list"] => list_dead_letters(&queue),
+        ["dead-letters", "retry", target] => {
+            for tweet_id in dead_letter_ids(&queue, target)? {
+                if queue.revive(&tweet_id)? {
+                    println!("Queued {} again", tweet_id);
+                }
+            }
+            Ok(())
+        }
+        ["dead-letters", "discard", target] => {
+            for tweet_id in dead_letter_ids(&queue, target)? {
+                if queue.discard(&tweet_id)? {
+                    println!("Discarded {}", tweet_id);
+                }
+            }
+            Ok(())
+        }
+        _ => Err(anyhow!(USAGE)),
+    }
+}
+
+// Handle a mention again right away, e.g. `clara replay 1234 --force`. Meant for when the bot is stopped, a running
+// bot replays mentions through its admin API instead
+pub async fn replay(args: &[String], stores: Stores) -> Result<()> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (tweet_id, force) = match args.as_slice() {
+        [tweet_id] => (*tweet_id, false),
+        [tweet_id, "--force"] => (*tweet_id, true),
+        _ => return Err(anyhow!(USAGE)),
+    };
+
+    let handler = Handler::new(stores).await?;
+    let tweet = handler.replay(tweet_id, force).await?;
+    handler.process_mention(&tweet).await?;
+
+    match handler.mention_record(tweet_id)? {
+        Some(record) if record.status == MentionStatus::Completed => println!("Answered {}", tweet_id),
+        Some(record) if record.status == MentionStatus::Failed => {
+            let error = record.error.as_deref().unwrap_or_default();
+            let next = match handler.queue().is_dead(tweet_id)? {
+                true => "moved to the dead letters",
+                false => "retried later",
+            };
+            println!(
+                "Replay of {} failed, {}: {}",
+                tweet_id,
+                next,
+                error.lines().next().unwrap_or_default()
+            );
+        }
+        _ => println!("{} is still queued, e.g. while over budget", tweet_id),
+    }
+
+    Ok(())
+}
+
+// Answer mentions made since a date, e.g. after turning the bot on late, at a pace that spares the rate limits
+// and the budget. Failed mentions stay queued for the bot. Meant for when the bot is stopped, like replay
+pub async fn backfill(args: &[String], stores: Stores) -> Result<()> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let (since, per_hour) = match args.as_slice() {
+        ["--since", since] => (*since, None),
+        ["--since", since, "--per-hour", per_hour] => (*since, Some(*per_hour)),
+        _ => return Err(anyhow!(USAGE)),
+    };
+    let since = NaiveDate::parse_from_str(since, "%Y-%m-%d")
+        .map_err(|err| anyhow!("--since must be a date like 2024-01-31: {}", err))?;
+    let per_hour = match per_hour {
+        Some(per_hour) => per_hour
+            .parse()
+            .map_err(|err| anyhow!("--per-hour must be a whole number: {}", err))?,
+        None => DEFAULT_BACKFILL_PER_HOUR,
+    };
+    if per_hour == 0 {
+        return Err(anyhow!("--per-hour must be at least 1"));
+    }
+    let pause = Duration::from_secs(3600) / per_hour;
+
+    let handler = Handler::new(stores).await?;
+    let queued = handler.backfill_mentions(since).await?;
+    println!("Queued {} mentions since {}", queued, since);
+
+    let due = handler.due_mentions()?;
+    for (index, tweet) in due.iter().enumerate() {
+        if index > 0 {
+            sleep(pause).await;
+        }
+        handler.process_mention(tweet).await?;
+        println!("Handled {} of {}", index + 1, due.len());
+    }
+
+    Ok(())
+}
+
+// Print every dead letter with its last error
+fn list_dead_letters(queue: &MentionQueue) -> Result<()> {
+    let letters = queue.dead_letters()?;
+    if letters.is_empty() {
+        println!("No dead letters");
+    }
+
+    for letter in letters {
+        let failed_at = DateTime::from_timestamp(letter.failed_at, 0).map(|time| time.to_rfc3339());
+        let error = letter.error.as_deref().unwrap_or_default();
+        println!(
+            "{}\t@{}\t{} attempts\t{}\t{}",
+            letter.tweet_id,
+            letter.tweet.username.as_deref().unwrap_or_default(),
+            letter.attempts,
+            failed_at.unwrap_or_default(),
+            error.lines().next().unwrap_or_default(),
+        );
+    }
+
+    Ok(())
+}
+
+// Tweet IDs a command applies to, every dead letter for --all
+fn dead_letter_ids(queue: &MentionQueue, target: &str) -> Result<Vec<String>> {
+    if target != "--all" {
+        if !queue.is_dead(target)? {
+            return Err(anyhow!("No dead letter for tweet {}", target));
+        }
+        return Ok(vec![target.to_string()]);
+    }
+
+    Ok(queue
+        .dead_letters()?
+        .into_iter()
+        .map(|letter| letter.tweet_id)
+        .collect())
+}
@@ -0,0 +1,262 @@
+// Import networking and synchronization handling
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr, TcpListener},
+    sync::Arc,
+};
+
+// Import base64 decoding to check uploaded images
+use base64::{engine::general_purpose, Engine};
+// Import error handling
+use anyhow::{anyhow, Result};
+// Import the HTTP server
+use hyper::{
+    body::HttpBody,
+    header::{HeaderValue, RETRY_AFTER},
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+// Import serialization traits
+use serde::{Deserialize, Serialize};
+// Import JSON building
+use serde_json::json;
+// Import logging
+use tracing::{error, info};
+
+// Import the breaker error, handler, health check responses and token checks, images, secrets and blocking calls
+use crate::{
+    breaker::BreakerOpen,
+    config,
+    handler::{GenerationRefused, Handler},
+    health::{bind, json_response, unauthorized},
+    image::Image,
+    secrets::secrets,
+    utils::blocking,
+};
+
+// Address served when API_ADDR isn't set
+const DEFAULT_API_ADDR: &str = "127.0.0.1:8000";
+// Largest request body accepted, enough for a base64 encoded avatar
+const MAX_BODY_BYTES: usize = 10 * 1024 * 1024;
+
+// How the generated image is returned
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageFormat {
+    // Base64 encoded PNG in the response
+    #[default]
+    B64,
+    // Path the image was saved to on the server
+    Path,
+}
+
+// Body of POST /v1/generate, with either an image URL or a base64 encoded image
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenerateRequest {
+    pub image_url: Option<String>,
+    pub image_b64: Option<String>,
+    // Art style added to the image prompt, e.g. "watercolor"
+    pub style: Option<String>,
+    // Language the image description is written in, e.g. "Spanish" (default English)
+    pub language: Option<String>,
+    #[serde(default)]
+    pub image_format: ImageFormat,
+}
+
+// Response to POST /v1/generate
+#[derive(Debug, Serialize)]
+pub struct GenerateResponse {
+    // Image description GPT-4 wrote for DALL-E
+    pub description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_b64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image_path: Option<String>,
+}
+
+// Read the address to serve the API on from API_ADDR
+pub fn api_addr_from_env() -> Result<SocketAddr> {
+    let addr = config::var("API_ADDR")
+        .ok()
+        .filter(|addr| !addr.is_empty())
+        .unwrap_or_else(|| DEFAULT_API_ADDR.to_string());
+
+    addr.parse()
+        .map_err(|err| anyhow!("API_ADDR must be an address like 127.0.0.1:8000: {}", err))
+}
+
+// Read the bearer token the generation APIs require from API_TOKEN through the secrets provider. None when unset,
+// which leaves the APIs off as every request spends money
+pub fn api_token_from_env() -> Option<String> {
+    secrets().get("API_TOKEN").ok().filter(|token| !token.is_empty())
+}
+
+// Serve generation requests until the server fails. Uses the listener bound at startup, a restarted server binds
+// the address again
+pub async fn serve(addr: SocketAddr, listener: Option<TcpListener>, handler: Arc<Handler>) -> Result<()> {
+    let listener = match listener {
+        Some(listener) => listener,
+        None => bind(addr)?,
+    };
+    let token = api_token_from_env();
+    let make_service = make_service_fn(move |conn: &AddrStream| {
+        let (handler, token, client) = (handler.clone(), token.clone(), conn.remote_addr().ip());
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                respond(handler.clone(), token.clone(), client, request)
+            }))
+        }
+    });
+    let server = Server::from_tcp(listener)?.serve(make_service);
+    info!(%addr, "Serving generation requests");
+
+    Ok(server.await?)
+}
+
+// Answer an API request from a client, which needs the API token
+async fn respond(
+    handler: Arc<Handler>,
+    token: Option<String>,
+    client: IpAddr,
+    request: Request<Body>,
+) -> Result<Response<Body>, Infallible> {
+    if request.uri().path() != "/v1/generate" {
+        return Ok(json_response(StatusCode::NOT_FOUND, json!({ "error": "Not found" })));
+    }
+    if let Some(response) = unauthorized(token.as_deref(), "API_TOKEN", &request) {
+        return Ok(response);
+    }
+    if request.method() != Method::POST {
+        return Ok(json_response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            json!({ "error": "Use POST" }),
+        ));
+    }
+
+    let request = match read_request(request.into_body()).await {
+        Ok(request) => request,
+        Err(response) => return Ok(response),
+    };
+
+    Ok(match generate(&handler, &client.to_string(), request).await {
+        Ok(response) => json_response(StatusCode::OK, json!(response)),
+        Err(err) => error_response(err),
+    })
+}
+
+// Read and parse a generation request, answering with the problem if it isn't valid
+async fn read_request(mut body: Body) -> Result<GenerateRequest, Response<Body>> {
+    let bad_request = |error: String| json_response(StatusCode::BAD_REQUEST, json!({ "error": error }));
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk.map_err(|err| bad_request(err.to_string()))?);
+        if bytes.len() > MAX_BODY_BYTES {
+            return Err(json_response(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                json!({ "error": "Request body too large" }),
+            ));
+        }
+    }
+
+    let request: GenerateRequest = serde_json::from_slice(&bytes).map_err(|err| bad_request(err.to_string()))?;
+    if request.image_url.is_some() == request.image_b64.is_some() {
+        return Err(bad_request("Give exactly one of image_url and image_b64".to_string()));
+    }
+
+    Ok(request)
+}
+
+// Draw a cat for a request of a client
+async fn generate(handler: &Handler, client: &str, request: GenerateRequest) -> Result<GenerateResponse> {
+    // Downloading and decoding block, so they run off the server's threads
+    let (image_url, image_b64) = (request.image_url, request.image_b64);
+    let image = blocking(move || read_image(image_url, image_b64)).await?;
+    let generation = handler
+        .generate(client, image, request.style.as_deref(), request.language.as_deref())
+        .await?;
+
+    Ok(match request.image_format {
+        ImageFormat::B64 => GenerateResponse {
+            description: generation.description,
+            image_b64: Some(generation.image.base64),
+            image_path: None,
+        },
+        ImageFormat::Path => GenerateResponse {
+            description: generation.description,
+            image_b64: None,
+            image_path: Some(generation.image_path),
+        },
+    })
+}
+
+// Download or decode the image of a request
+fn read_image(image_url: Option<String>, image_b64: Option<String>) -> Result<Image> {
+    Ok(match (image_url, image_b64) {
+        (Some(url), _) => Image::from_url(&url).map_err(|err| GenerationRefused::InvalidImage(err.to_string()))?,
+        (_, Some(base64)) => {
+            // Images assume valid base64, so check it before handing it over
+            general_purpose::STANDARD
+                .decode(&base64)
+                .map_err(|err| GenerationRefused::InvalidImage(err.to_string()))?;
+            Image::from_base64(base64)
+        }
+        (None, None) => return Err(anyhow!("No image given")),
+    })
+}
+
+// Answer with a failed generation, refusals are the client's problem and open breakers a temporary one
+fn error_response(err: anyhow::Error) -> Response<Body> {
+    let status = match err.downcast_ref::<GenerationRefused>() {
+        Some(GenerationRefused::InvalidImage(_)) => StatusCode::BAD_REQUEST,
+        Some(GenerationRefused::Declined(_)) => StatusCode::UNPROCESSABLE_ENTITY,
+        Some(GenerationRefused::OverBudget(_)) => StatusCode::SERVICE_UNAVAILABLE,
+        Some(GenerationRefused::RateLimited(wait)) => {
+            let mut response = json_response(StatusCode::TOO_MANY_REQUESTS, json!({ "error": format!("{:#}", err) }));
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(wait.as_secs().max(1)));
+            return response;
+        }
+        None if err.chain().any(|cause| cause.is::<BreakerOpen>()) => StatusCode::SERVICE_UNAVAILABLE,
+        None => {
+            error!(error = ?err, "Failed to generate");
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    };
+
+    json_response(status, json!({ "error": format!("{:#}", err) }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_takes_a_language() {
+        let request: GenerateRequest = serde_json::from_str(
+            r#"{"image_url": "https://example.com/avatar.png", "style": "watercolor", "language": "Spanish"}"#,
+        )
+        .unwrap();
+
+        assert_eq!(request.language.as_deref(), Some("Spanish"));
+        assert_eq!(request.image_format, ImageFormat::B64);
+    }
+
+    #[test]
+    fn request_rejects_unknown_fields() {
+        let request = serde_json::from_str::<GenerateRequest>(r#"{"image_url": "https://example.com/a.png", "x": 1}"#);
+
+        assert!(request.is_err());
+    }
+
+    #[test]
+    fn rate_limited_client_is_told_when_to_come_back() {
+        let response = error_response(GenerationRefused::RateLimited(std::time::Duration::from_secs(90)).into());
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "90");
+    }
+}
@@ -0,0 +1,151 @@
+// Import collections, file and synchronization handling
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    sync::Mutex,
+};
+
+// Import date handling
+use chrono::Utc;
+// Import error handling
+use anyhow::Result;
+// Import serialization traits
+use serde::{Deserialize, Serialize};
+
+// Import safety verdicts for moderation events
+use crate::vision::SafetyVerdict;
+
+// What happened to a mention
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    // Safety verdict on the avatar and what was done about it
+    Moderation {
+        verdict: String,
+        categories: Vec<String>,
+        action: String,
+    },
+    // Prompt sent to the model and the image description it returned
+    Prompt {
+        prompt: String,
+        response: String,
+    },
+    // Image sent in the reply
+    Image {
+        path: String,
+        reused: bool,
+    },
+    // Reply posted to the user
+    Reply {
+        reply_id: Option<String>,
+        text: String,
+    },
+}
+
+// One line of the audit log
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AuditRecord {
+    // Unix timestamp of the event
+    pub timestamp: i64,
+    // Tweet ID of the mention
+    pub tweet_id: Option<String>,
+    // Handle of the user who mentioned the bot
+    pub user: Option<String>,
+    // The event itself
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+// Append-only log of all generated content, one JSON record per line
+pub struct AuditLog {
+    // Path to the log file
+    file_path: String,
+    // Log file opened for appending
+    file: Mutex<File>,
+}
+
+impl AuditEvent {
+    // Moderation event for a safety verdict and the action taken on it, e.g. "declined"
+    pub fn moderation(safety: &SafetyVerdict, action: &str) -> Self {
+        let (verdict, categories) = match safety {
+            SafetyVerdict::Safe => ("safe", Vec::new()),
+            SafetyVerdict::Faces(_) => ("faces", Vec::new()),
+            SafetyVerdict::Unsafe(categories) => ("unsafe", categories.clone()),
+        };
+
+        AuditEvent::Moderation {
+            verdict: verdict.to_string(),
+            categories,
+            action: action.to_string(),
+        }
+    }
+}
+
+impl AuditLog {
+    // Open the log, creating it if it doesn't exist
+    pub fn open(file_path: &str) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(file_path)?;
+
+        Ok(Self {
+            file_path: file_path.to_string(),
+            file: Mutex::new(file),
+        })
+    }
+
+    // Append an event of a mention
+    pub fn record(&self, tweet_id: Option<&str>, user: Option<&str>, event: AuditEvent) -> Result<()> {
+        let record = AuditRecord {
+            timestamp: Utc::now().timestamp(),
+            tweet_id: tweet_id.map(str::to_string),
+            user: user.map(str::to_string),
+            event,
+        };
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+
+        // One write per record so concurrent writers never interleave lines
+        let mut file = self.file.lock().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.flush()?;
+
+        Ok(())
+    }
+
+    // Read the records of the most recently active mentions, most recent first and each mention's records oldest
+    // first
+    pub fn recent(&self, mentions: usize) -> Result<Vec<Vec<AuditRecord>>> {
+        let reader = BufReader::new(File::open(&self.file_path)?);
+        // Records of each mention with the line of its latest record
+        let mut by_tweet: HashMap<Option<String>, (usize, Vec<AuditRecord>)> = HashMap::new();
+        for (index, line) in reader.lines().enumerate() {
+            let record: AuditRecord = serde_json::from_str(&line?)?;
+            let (latest, records) = by_tweet.entry(record.tweet_id.clone()).or_default();
+            *latest = index;
+            records.push(record);
+        }
+
+        let mut by_tweet: Vec<_> = by_tweet.into_values().collect();
+        by_tweet.sort_by(|(a, _), (b, _)| b.cmp(a));
+
+        Ok(by_tweet
+            .into_iter()
+            .take(mentions)
+            .map(|(_, records)| records)
+            .collect())
+    }
+
+    // Read all records of a mention, oldest first
+    pub fn by_tweet(&self, tweet_id: &str) -> Result<Vec<AuditRecord>> {
+        let reader = BufReader::new(File::open(&self.file_path)?);
+        let mut records = Vec::new();
+        for line in reader.lines() {
+            let record: AuditRecord = serde_json::from_str(&line?)?;
+            if record.tweet_id.as_deref() == Some(tweet_id) {
+                records.push(record);
+            }
+        }
+
+        Ok(records)
+    }
+}
@@ -0,0 +1,277 @@
+// Import error handling
+use anyhow::{anyhow, Result};
+// Import date handling for request signing
+use chrono::Utc;
+// Import hashing for AWS Signature Version 4
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+// Import JSON values
+use serde_json::Value;
+
+// Import local modules
+use crate::{config, http_client::HttpClient, secrets::secrets};
+
+// Region used when AWS_REGION is not set
+const DEFAULT_REGION: &str = "us-east-1";
+// Secrets every AWS client needs
+pub const AWS_SECRETS: &[&str] = &["AWS_ACCESS_KEY_ID", "AWS_SECRET_ACCESS_KEY"];
+
+// Client making signed calls to AWS JSON protocol APIs
+#[derive(Debug)]
+pub struct AwsClient {
+    // AWS access key ID
+    access_key: String,
+    // AWS secret access key
+    secret_key: String,
+    // Optional session token for temporary credentials
+    session_token: Option<String>,
+    // AWS region hosting the endpoints
+    region: String,
+    // HTTP client instance
+    http_client: HttpClient,
+}
+
+impl AwsClient {
+    // Initialize new AWS client
+    pub fn new() -> Result<Self> {
+        // Get AWS credentials from the secrets provider
+        let access_key = secrets()
+            .get("AWS_ACCESS_KEY_ID")
+            .map_err(|err| anyhow!("Missing AWS_ACCESS_KEY_ID {}", err))?;
+
+        let secret_key = secrets()
+            .get("AWS_SECRET_ACCESS_KEY")
+            .map_err(|err| anyhow!("Missing AWS_SECRET_ACCESS_KEY {}", err))?;
+
+        let session_token = secrets().get("AWS_SESSION_TOKEN").ok();
+        let region = config::var("AWS_REGION")
+            .ok()
+            .filter(|region| !region.is_empty())
+            .unwrap_or_else(|| DEFAULT_REGION.to_string());
+
+        Ok(Self {
+            access_key,
+            secret_key,
+            session_token,
+            region,
+            http_client: HttpClient::new(),
+        })
+    }
+
+    // Make a signed call to an action of a service, e.g. "RekognitionService.DetectLabels" of "rekognition"
+    pub fn call(&self, service: &str, content_type: &str, target: &str, body: Value) -> Result<String> {
+        let host = format!("{}.{}.amazonaws.com", service, self.region);
+        let body = body.to_string();
+        let now = Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date_stamp = now.format("%Y%m%d").to_string();
+
+        // Headers included in the signature, sorted by name
+        let mut signed_headers = vec![
+            ("content-type", content_type),
+            ("host", host.as_str()),
+            ("x-amz-date", amz_date.as_str()),
+        ];
+        if let Some(token) = &self.session_token {
+            signed_headers.push(("x-amz-security-token", token.as_str()));
+        }
+        signed_headers.push(("x-amz-target", target));
+
+        let authorization = self.authorization(service, &signed_headers, &body, &amz_date, &date_stamp)?;
+
+        let mut headers = signed_headers
+            .into_iter()
+            .filter(|(name, _)| *name != "host")
+            .collect::<Vec<_>>();
+        headers.push(("authorization", authorization.as_str()));
+
+        self.http_client
+            .post_with_headers(&format!("https://{}/", host), &headers, &body)
+    }
+
+    // Build the AWS Signature Version 4 authorization header
+    fn authorization(
+        &self,
+        service: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+        amz_date: &str,
+        date_stamp: &str,
+    ) -> Result<String> {
+        let scope = format!("{}/{}/{}/aws4_request", date_stamp, self.region, service);
+        let string_to_sign = string_to_sign(amz_date, &scope, &canonical_request(headers, body));
+
+        // Derive the signing key from the secret and the scope
+        let date_key = hmac_sha256(format!("AWS4{}", self.secret_key).as_bytes(), date_stamp.as_bytes())?;
+        let region_key = hmac_sha256(&date_key, self.region.as_bytes())?;
+        let service_key = hmac_sha256(&region_key, service.as_bytes())?;
+        let signing_key = hmac_sha256(&service_key, b"aws4_request")?;
+        let signature = to_hex(&hmac_sha256(&signing_key, string_to_sign.as_bytes())?);
+
+        Ok(format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key,
+            scope,
+            signed_header_names(headers),
+            signature
+        ))
+    }
+}
+
+// Canonical request of a POST to / with the given headers, sorted by name: method, path, query, headers, signed
+// headers and payload hash
+fn canonical_request(headers: &[(&str, &str)], body: &str) -> String {
+    let canonical_headers: String = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}\n", name, value.trim()))
+        .collect();
+
+    format!(
+        "POST\n/\n\n{}\n{}\n{}",
+        canonical_headers,
+        signed_header_names(headers),
+        hex_digest(body.as_bytes())
+    )
+}
+
+// Names of the signed headers, separated by semicolons
+fn signed_header_names(headers: &[(&str, &str)]) -> String {
+    headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";")
+}
+
+// String to sign for a canonical request made at `amz_date` within the credential scope
+fn string_to_sign(amz_date: &str, scope: &str, canonical_request: &str) -> String {
+    format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex_digest(canonical_request.as_bytes())
+    )
+}
+
+// Compute HMAC-SHA256 of data with the given key
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key)?;
+    mac.update(data);
+    Ok(mac.finalize().into_bytes().to_vec())
+}
+
+// Compute hex-encoded SHA-256 digest
+fn hex_digest(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+// Encode bytes as lowercase hex
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Vectors of the AWS Signature Version 4 test suite, all signed with its example credentials for the "service"
+// service in us-east-1 on 2015-08-30
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AMZ_DATE: &str = "20150830T123600Z";
+    const DATE_STAMP: &str = "20150830";
+    const SCOPE: &str = "20150830/us-east-1/service/aws4_request";
+    const SESSION_TOKEN: &str = "\
+        AQoDYXdzEPT//////////wEXAMPLEtc764bNrC9SAPBSM22wDOk4x4HIZ8j4FZTwdQWLWsKWHGBuFqwAeMicRXmxfpSPfIeoIYRq\
+        TflfKD8YUuwthAx7mSEI/qkPpKPi/kMcGdQrmGdeehM4IC1NtBmUpp2wUE8phUZampKsburEDy0KPkyQDYwT7WZ0wq5VSXDvp75Y\
+        U9HFvlRd8Tx6q6fE8YQcHNVXAkiY9q6d+xo0rKwT38xVqr7ZD0u0iPPkUL64lIZbqBAz+scqKmlzm8FDrypNC9Yjc8fPOLn9FX9K\
+        SYvKTr4rvx3iSIlTJabIQwj2ICCR/oLxBA==";
+
+    fn client(session_token: Option<&str>) -> AwsClient {
+        AwsClient {
+            access_key: "AKIDEXAMPLE".to_string(),
+            secret_key: "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_string(),
+            session_token: session_token.map(str::to_string),
+            region: "us-east-1".to_string(),
+            http_client: HttpClient::new(),
+        }
+    }
+
+    // Check each step of signing a vector
+    fn check(
+        headers: &[(&str, &str)],
+        body: &str,
+        expected_canonical_request: &str,
+        expected_string_to_sign: &str,
+        expected_authorization: &str,
+    ) {
+        let canonical_request = canonical_request(headers, body);
+        assert_eq!(canonical_request, expected_canonical_request);
+        assert_eq!(
+            string_to_sign(AMZ_DATE, SCOPE, &canonical_request),
+            expected_string_to_sign
+        );
+
+        let token = headers
+            .iter()
+            .find(|(name, _)| *name == "x-amz-security-token")
+            .map(|(_, token)| *token);
+        let authorization = client(token)
+            .authorization("service", headers, body, AMZ_DATE, DATE_STAMP)
+            .unwrap();
+        assert_eq!(authorization, expected_authorization);
+    }
+
+    #[test]
+    fn post_vanilla() {
+        check(
+            &[("host", "example.amazonaws.com"), ("x-amz-date", AMZ_DATE)],
+            "",
+            "POST\n/\n\nhost:example.amazonaws.com\nx-amz-date:20150830T123600Z\n\nhost;x-amz-date\n\
+             e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            "AWS4-HMAC-SHA256\n20150830T123600Z\n20150830/us-east-1/service/aws4_request\n\
+             553f88c9e4d10fc9e109e2aeb65f030801b70c2f6468faca261d401ae622fc87",
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, \
+             Signature=5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b",
+        );
+    }
+
+    #[test]
+    fn post_x_www_form_urlencoded() {
+        check(
+            &[
+                ("content-type", "application/x-www-form-urlencoded"),
+                ("host", "example.amazonaws.com"),
+                ("x-amz-date", AMZ_DATE),
+            ],
+            "Param1=value1",
+            "POST\n/\n\ncontent-type:application/x-www-form-urlencoded\nhost:example.amazonaws.com\n\
+             x-amz-date:20150830T123600Z\n\ncontent-type;host;x-amz-date\n\
+             9095672bbd1f56dfc5b65f3e153adc8731a4a654192329106275f4c7b24d0b6e",
+            "AWS4-HMAC-SHA256\n20150830T123600Z\n20150830/us-east-1/service/aws4_request\n\
+             42a5e5bb34198acb3e84da4f085bb7927f2bc277ca766e6d19c73c2154021281",
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=content-type;host;x-amz-date, \
+             Signature=ff11897932ad3f4e8b18135d722051e5ac45fc38421b1da7b9d196a0fe09473a",
+        );
+    }
+
+    #[test]
+    fn post_sts_header_before() {
+        let canonical_request = format!(
+            "POST\n/\n\nhost:example.amazonaws.com\nx-amz-date:20150830T123600Z\nx-amz-security-token:{}\n\n\
+             host;x-amz-date;x-amz-security-token\n\
+             e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            SESSION_TOKEN
+        );
+        check(
+            &[
+                ("host", "example.amazonaws.com"),
+                ("x-amz-date", AMZ_DATE),
+                ("x-amz-security-token", SESSION_TOKEN),
+            ],
+            "",
+            &canonical_request,
+            "AWS4-HMAC-SHA256\n20150830T123600Z\n20150830/us-east-1/service/aws4_request\n\
+             c237e1b440d4c63c32ca95b5b99481081cb7b13c7e40434868e71567c1a882f6",
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date;x-amz-security-token, \
+             Signature=85d96828115b5dc0cfc3bd16ad9e210dd772bbebba041836c64533a82be05ead",
+        );
+    }
+}
@@ -0,0 +1,184 @@
+// Import Arc and Mutex from the standard modules
+use std::sync::{Arc, Mutex};
+
+// Import error handling
+use anyhow::Result;
+// Import the handler lock and sleep function from tokio
+use tokio::{sync::RwLock, time::sleep};
+// Import structured logging
+use tracing::{info, warn};
+
+// Import the APIs, config watcher, dashboard, health checks, Handler, ledger, queue, stores, metrics, rate limits,
+// spend tracking, audit log, mention sources, supervisor and workers
+use crate::{
+    api::{self, api_addr_from_env, api_token_from_env},
+    audit::AuditLog,
+    config::ConfigWatcher,
+    cost::CostTracker,
+    dashboard::{dashboard_addr_from_env, dashboard_from_env},
+    embedding::EmbeddingStore,
+    grpc::{self, grpc_addr_from_env},
+    handler::{Handler, Stores},
+    health::{self, admin_token_from_env, health_addr_from_env, Health},
+    ledger::Ledger,
+    metrics::metrics,
+    prefs::PreferenceStore,
+    queue::MentionQueue,
+    source::{create_mention_source, MentionSource},
+    storage::Storage,
+    supervisor::{RestartPolicy, Supervisor},
+    utils::FileRateStore,
+    workers::{workers_from_env, WorkerPool},
+};
+
+// File path for the legacy processed tweets storage, imported into the ledger
+pub const STORAGE_FILE: &str = "storage.json";
+// File path for the processed mentions ledger
+pub const LEDGER_FILE: &str = "ledger.db";
+// File path for avatar embeddings
+pub const EMBEDDINGS_FILE: &str = "embeddings.json";
+// File path for user preferences
+pub const PREFS_FILE: &str = "prefs.json";
+// File path for per-user rate limit counts
+pub const RATE_LIMITS_FILE: &str = "rate_limits.json";
+// File path for estimated spend
+pub const COSTS_FILE: &str = "costs.json";
+// File path for the audit log of generated content
+pub const AUDIT_FILE: &str = "audit.jsonl";
+// File path for the queue of accepted mentions
+pub const QUEUE_FILE: &str = "queue.db";
+
+// Open the ledger, queue and stores from their files in the working directory
+pub fn open_stores() -> Result<Stores> {
+    // Open the ledger and carry over tweets processed before it existed
+    let ledger = Ledger::open(LEDGER_FILE)?;
+    let storage = Storage::load_from_file(STORAGE_FILE)?;
+    let imported = ledger.import_storage(&storage)?;
+    if imported > 0 {
+        info!(imported, "Imported processed tweets into the ledger");
+    }
+    // Open the queue, mentions accepted before a restart are still in it
+    let queue = MentionQueue::open(QUEUE_FILE)?;
+    let queued = queue.len()?;
+    if queued > 0 {
+        info!(queued, "Resuming queued mentions");
+    }
+
+    Ok(Stores {
+        ledger,
+        queue,
+        embeddings: EmbeddingStore::load_from_file(EMBEDDINGS_FILE)?,
+        prefs: PreferenceStore::load_from_file(PREFS_FILE)?,
+        rate_limits: FileRateStore::load_from_file(RATE_LIMITS_FILE)?,
+        costs: CostTracker::load_from_file(COSTS_FILE)?,
+        audit_log: AuditLog::open(AUDIT_FILE)?,
+    })
+}
+
+// Serve generation requests at API_ADDR, and over gRPC at GRPC_ADDR if set, instead of answering mentions, without
+// logging in to Twitter. An address that can't be bound fails right away, a server that fails once running is
+// restarted by the supervisor
+pub async fn serve(stores: Stores) -> Result<()> {
+    let handler = Arc::new(Handler::without_twitter(stores)?);
+    let mut supervisor = Supervisor::new(RestartPolicy::default());
+    if api_token_from_env().is_none() {
+        warn!("API_TOKEN is not set, every generation request will be refused");
+    }
+
+    let addr = api_addr_from_env()?;
+    let mut listener = Some(health::bind(addr)?);
+    let api_handler = handler.clone();
+    supervisor.spawn("api", move || api::serve(addr, listener.take(), api_handler.clone()));
+    if let Some(addr) = grpc_addr_from_env()? {
+        let mut listener = Some(health::bind(addr)?);
+        supervisor.spawn("grpc", move || grpc::serve(addr, listener.take(), handler.clone()));
+    }
+
+    supervisor.wait().await
+}
+
+// Run the bot, restarting the poller, workers, health and dashboard servers with backoff whenever one of them panics, fails or
+// exits. Expects the secrets provider to be set and the configuration validated, as the binary does before calling
+// it
+pub async fn run(stores: Stores, watcher: Option<ConfigWatcher>) -> Result<()> {
+    let mut supervisor = Supervisor::new(RestartPolicy::default());
+
+    // Answer health checks from the start, readiness follows once the handler logged in and polled. An address that
+    // can't be bound fails startup
+    let health = Arc::new(Health::new().with_admin_token(admin_token_from_env()));
+    if let Some(addr) = health_addr_from_env()? {
+        let mut listener = Some(health::bind(addr)?);
+        let health = health.clone();
+        supervisor.spawn("health", move || health::serve(addr, listener.take(), health.clone()));
+    }
+    if dashboard_from_env()? {
+        let addr = dashboard_addr_from_env()?;
+        let mut listener = Some(health::bind(addr)?);
+        let health = health.clone();
+        supervisor.spawn("dashboard", move || {
+            health::serve_dashboard(addr, listener.take(), health.clone())
+        });
+    }
+
+    // Share the handler with a fixed pool of workers, reloading settings waits for the mentions in progress
+    let handler = Arc::new(RwLock::new(Handler::new(stores).await?));
+    let pool = Arc::new(WorkerPool::start(handler.clone(), workers_from_env()?, &mut supervisor));
+    health.set_handler(handler.clone());
+
+    // Connect to the mention source before supervising the poller so a broken setup fails at startup, restarts of
+    // the poller connect again
+    let mut source = Some(create_mention_source().await?);
+    let watcher = Arc::new(Mutex::new(watcher));
+    supervisor.spawn("poller", move || {
+        poll(
+            source.take(),
+            handler.clone(),
+            pool.clone(),
+            health.clone(),
+            watcher.clone(),
+        )
+    });
+
+    supervisor.wait().await
+}
+
+// Queue new mentions from the source and hand everything due to the workers until receiving fails
+async fn poll(
+    source: Option<Box<dyn MentionSource>>,
+    handler: Arc<RwLock<Handler>>,
+    pool: Arc<WorkerPool>,
+    health: Arc<Health>,
+    watcher: Arc<Mutex<Option<ConfigWatcher>>>,
+) -> Result<()> {
+    let mut source = match source {
+        Some(source) => source,
+        None => create_mention_source().await?,
+    };
+
+    // Infinite loop to continuously process tweets
+    loop {
+        // Log status message for each iteration
+        info!("Starting a new iteration...");
+        // Apply config file changes made since the last iteration
+        let changed = watcher
+            .lock()
+            .unwrap()
+            .as_mut()
+            .map(ConfigWatcher::poll)
+            .unwrap_or_default();
+        if !changed.is_empty() {
+            handler.write().await.reload(&changed);
+        }
+        // Queue new mentions, then hand everything due to the workers
+        source.receive(&handler).await?;
+        let due = handler.read().await.due_mentions()?;
+        for tweet in due {
+            pool.submit(tweet).await?;
+        }
+        health.record_poll();
+        // Log metrics collected so far
+        info!(metrics = %metrics().summary(), "Iteration finished");
+        // Sleep before the next iteration, message queue sources wait for messages instead
+        sleep(source.interval()).await;
+    }
+}
@@ -0,0 +1,254 @@
+// Import future, synchronization and time handling
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// Import error handling
+use anyhow::Result;
+// Import error derive
+use thiserror::Error;
+// Import logging
+use tracing::{info, warn};
+
+// Import clock, metrics and error classes
+use crate::{
+    clock::{system_clock, Clock},
+    metrics::metrics,
+    utils::{classify_error, ErrorClass},
+};
+
+// Consecutive transient failures that open a breaker
+const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+// How long an open breaker rejects calls before letting a probe through
+const DEFAULT_OPEN_SECS: u64 = 60;
+
+// Error returned without calling the provider while its breaker is open
+#[derive(Debug, Error)]
+#[error("{name} is unavailable, retry in {retry_in:?}")]
+pub struct BreakerOpen {
+    pub name: String,
+    pub retry_in: Duration,
+}
+
+// State of a breaker
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BreakerState {
+    // Calls go through, counting consecutive failures
+    Closed { failures: u32 },
+    // Calls are rejected until the given time
+    Open { until: Instant },
+    // One probe call is deciding whether to close or open again
+    HalfOpen,
+}
+
+// Circuit breaker failing fast while an external provider keeps failing
+pub struct CircuitBreaker {
+    // Provider name used in errors, logs and metrics
+    name: String,
+    // Consecutive transient failures that open the breaker
+    failure_threshold: u32,
+    // How long the breaker stays open
+    open_for: Duration,
+    // Current state
+    state: Mutex<BreakerState>,
+    // Time source for the open period
+    clock: Arc<dyn Clock>,
+}
+
+impl CircuitBreaker {
+    // Create breaker with the default threshold and open time
+    pub fn new(name: &str) -> Self {
+        Self::with_limits(name, DEFAULT_FAILURE_THRESHOLD, Duration::from_secs(DEFAULT_OPEN_SECS))
+    }
+
+    // Create breaker with a custom threshold and open time
+    pub fn with_limits(name: &str, failure_threshold: u32, open_for: Duration) -> Self {
+        Self {
+            name: name.to_string(),
+            failure_threshold,
+            open_for,
+            state: Mutex::new(BreakerState::Closed { failures: 0 }),
+            clock: system_clock(),
+        }
+    }
+
+    // Use another time source, e.g. a mock clock in tests
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Current state
+    pub fn state(&self) -> BreakerState {
+        *self.state.lock().unwrap()
+    }
+
+    // Provider name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Check whether calls are being rejected, an open breaker past its open time lets a probe through
+    pub fn is_open(&self) -> bool {
+        matches!(self.state(), BreakerState::Open { until } if self.clock.instant() < until)
+    }
+
+    // Run an async call through the breaker
+    pub async fn call<T, Fut>(&self, op: impl FnOnce() -> Fut) -> Result<T>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        self.admit()?;
+        let result = op().await;
+        self.record(&result);
+        result
+    }
+
+    // Run a blocking call through the breaker
+    pub fn call_sync<T>(&self, op: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.admit()?;
+        let result = op();
+        self.record(&result);
+        result
+    }
+
+    // Let a call through unless the breaker is open, an expired open breaker lets one probe through
+    fn admit(&self) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            BreakerState::Closed { .. } => Ok(()),
+            BreakerState::Open { until } if self.clock.instant() >= until => {
+                info!(provider = %self.name, "Circuit breaker probing recovery");
+                *state = BreakerState::HalfOpen;
+                Ok(())
+            }
+            BreakerState::Open { until } => self.reject(until.saturating_duration_since(self.clock.instant())),
+            // Only one probe at a time
+            BreakerState::HalfOpen => self.reject(self.open_for),
+        }
+    }
+
+    // Fail a call without running it
+    fn reject(&self, retry_in: Duration) -> Result<()> {
+        metrics().increment(&format!("breaker.rejected.{}", self.name), 1);
+        Err(BreakerOpen {
+            name: self.name.clone(),
+            retry_in,
+        }
+        .into())
+    }
+
+    // Update the state with the outcome of a call, only transient errors count as the provider being down
+    fn record<T>(&self, result: &Result<T>) {
+        let mut state = self.state.lock().unwrap();
+        let failed = matches!(result, Err(err) if classify_error(err) == ErrorClass::Transient);
+
+        *state = match (*state, failed) {
+            (BreakerState::HalfOpen, false) => {
+                info!(provider = %self.name, "Circuit breaker closed");
+                BreakerState::Closed { failures: 0 }
+            }
+            (_, false) => BreakerState::Closed { failures: 0 },
+            (BreakerState::Closed { failures }, true) if failures + 1 < self.failure_threshold => {
+                BreakerState::Closed { failures: failures + 1 }
+            }
+            (_, true) => {
+                warn!(provider = %self.name, open_secs = self.open_for.as_secs(), "Circuit breaker opened");
+                metrics().increment(&format!("breaker.opened.{}", self.name), 1);
+                BreakerState::Open {
+                    until: self.clock.instant() + self.open_for,
+                }
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use anyhow::anyhow;
+    use chrono::Utc;
+
+    use super::*;
+    use crate::clock::MockClock;
+
+    // Breaker opening after two failures for a minute, driven by a mock clock
+    fn breaker() -> (CircuitBreaker, Arc<MockClock>) {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let breaker = CircuitBreaker::with_limits("test", 2, Duration::from_secs(60)).with_clock(clock.clone());
+        (breaker, clock)
+    }
+
+    fn transient() -> Result<()> {
+        Err(io::Error::new(io::ErrorKind::ConnectionReset, "connection reset").into())
+    }
+
+    fn rejected(result: Result<()>) -> bool {
+        matches!(result, Err(err) if err.downcast_ref::<BreakerOpen>().is_some())
+    }
+
+    #[test]
+    fn opens_after_consecutive_transient_failures() {
+        let (breaker, _) = breaker();
+
+        assert!(breaker.call_sync(transient).is_err());
+        assert_eq!(breaker.state(), BreakerState::Closed { failures: 1 });
+        assert!(breaker.call_sync(transient).is_err());
+
+        assert!(breaker.is_open());
+        assert!(rejected(breaker.call_sync(|| Ok(()))));
+    }
+
+    #[test]
+    fn success_and_permanent_errors_reset_the_count() {
+        let (breaker, _) = breaker();
+
+        assert!(breaker.call_sync(transient).is_err());
+        assert!(breaker.call_sync(|| Ok(())).is_ok());
+        assert!(breaker.call_sync(transient).is_err());
+        assert!(breaker.call_sync::<()>(|| Err(anyhow!("bad request"))).is_err());
+
+        assert_eq!(breaker.state(), BreakerState::Closed { failures: 0 });
+    }
+
+    #[test]
+    fn probe_after_the_open_time_closes_on_success() {
+        let (breaker, clock) = breaker();
+        let _ = breaker.call_sync(transient);
+        let _ = breaker.call_sync(transient);
+
+        clock.advance(Duration::from_secs(59));
+        assert!(rejected(breaker.call_sync(|| Ok(()))));
+
+        clock.advance(Duration::from_secs(1));
+        assert!(!breaker.is_open());
+        let probe = breaker.call_sync(|| {
+            assert_eq!(breaker.state(), BreakerState::HalfOpen);
+            // Only one probe at a time
+            assert!(rejected(breaker.call_sync(|| Ok(()))));
+            Ok(())
+        });
+
+        assert!(probe.is_ok());
+        assert_eq!(breaker.state(), BreakerState::Closed { failures: 0 });
+    }
+
+    #[test]
+    fn failed_probe_opens_again() {
+        let (breaker, clock) = breaker();
+        let _ = breaker.call_sync(transient);
+        let _ = breaker.call_sync(transient);
+
+        clock.advance(Duration::from_secs(60));
+        assert!(breaker.call_sync(transient).is_err());
+
+        assert!(breaker.is_open());
+        clock.advance(Duration::from_secs(59));
+        assert!(rejected(breaker.call_sync(|| Ok(()))));
+        clock.advance(Duration::from_secs(1));
+        assert!(breaker.call_sync(|| Ok(())).is_ok());
+    }
+}
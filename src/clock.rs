@@ -0,0 +1,74 @@
+// Import synchronization and time handling
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// Import date handling
+use chrono::{DateTime, Utc};
+
+// Source of the current time for time-based logic such as rate limit windows and breaker timeouts, so it can be
+// driven by hand in tests
+pub trait Clock: Send + Sync {
+    // Current wall-clock time
+    fn now(&self) -> DateTime<Utc>;
+    // Current monotonic time, for measuring how long something lasts
+    fn instant(&self) -> Instant;
+}
+
+// Clock reading the system time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+// Clock that only moves when told to
+#[derive(Debug)]
+pub struct MockClock {
+    // Wall-clock time it started at
+    start: DateTime<Utc>,
+    // Monotonic time it started at
+    start_instant: Instant,
+    // How far it was moved
+    elapsed: Mutex<Duration>,
+}
+
+// Shared system clock used unless another one is injected
+pub fn system_clock() -> Arc<dyn Clock> {
+    Arc::new(SystemClock)
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl MockClock {
+    // Create clock standing still at a wall-clock time
+    pub fn new(start: DateTime<Utc>) -> Self {
+        Self {
+            start,
+            start_instant: Instant::now(),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    // Move the clock forward
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        let elapsed = *self.elapsed.lock().unwrap();
+        self.start + chrono::Duration::from_std(elapsed).unwrap_or(chrono::Duration::MAX)
+    }
+
+    fn instant(&self) -> Instant {
+        self.start_instant + *self.elapsed.lock().unwrap()
+    }
+}
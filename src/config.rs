@@ -0,0 +1,626 @@
+// Import collections, environment, file, path, synchronization and channel handling
+use std::{
+    collections::HashMap,
+    env::{self, VarError},
+    fs,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{channel, Receiver},
+        OnceLock, RwLock,
+    },
+};
+
+// Import date handling
+use chrono::Local;
+// Import logging and error handling
+use log::{error, info};
+// Import file watching
+use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher};
+// Import serialization traits
+use serde::{Deserialize, Serialize};
+// Import error derive
+use thiserror::Error;
+
+// Import local settings parsers
+use crate::{
+    api::api_addr_from_env,
+    aws::AWS_SECRETS,
+    cost::Budget,
+    dashboard::{dashboard_addr_from_env, dashboard_from_env},
+    grpc::grpc_addr_from_env,
+    health::health_addr_from_env,
+    logging::LogFormat,
+    queue::RequeuePolicy,
+    secrets::secrets,
+    source::{SourceConfig, SourceKind},
+    theme::ThemeCalendar,
+    utils::{rate_limit_from_env, ttl_from_env, StageTimeouts},
+    vision::{max_edge_from_env, max_results_from_env, ContextSource, FacePolicy, TextPolicy, SERVICE_ACCOUNT_FILE},
+    workers::workers_from_env,
+};
+
+// Credentials that must be available from the secrets provider whatever the configuration
+const REQUIRED_SECRETS: &[&str] = &["OPENAI_API_KEY"];
+// Credentials needed to answer mentions, `clara serve` doesn't log in to Twitter
+const TWITTER_SECRETS: &[&str] = &["TWITTER_USERNAME", "TWITTER_PASSWORD", "TWITTER_EMAIL"];
+// Placeholders understood in TRANSLATE_PROMPT
+const PROMPT_PLACEHOLDERS: &[&str] = &["", "subject", "style", "color", "mood"];
+// Label detection models offered by Google Vision
+const VISION_MODELS: &[&str] = &["builtin/stable", "builtin/latest"];
+// Smallest useful longest edge for downscaled images
+const MIN_VISION_MAX_EDGE: u32 = 64;
+
+// Process-wide settings read by every component
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+// Settings read from the config file, each one named after the environment variable it stands in for.
+// Layering is built-in defaults, then the file, then the environment
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AppConfig {
+    // TRANSLATE_PROMPT
+    pub translate_prompt: Option<String>,
+    // THEME_CALENDAR
+    pub theme_calendar: Option<String>,
+    // VISION_PROVIDER
+    pub vision_provider: Option<String>,
+    // VISION_MODEL
+    pub vision_model: Option<String>,
+    // VISION_MAX_RESULTS
+    pub vision_max_results: Option<u8>,
+    // VISION_MAX_EDGE
+    pub vision_max_edge: Option<u32>,
+    // VISION_CONTEXT
+    pub vision_context: Option<String>,
+    // VISION_OCR
+    pub vision_ocr: Option<String>,
+    // FACE_POLICY
+    pub face_policy: Option<String>,
+    // AWS_REGION
+    pub aws_region: Option<String>,
+    // SECRETS_PROVIDER
+    pub secrets_provider: Option<String>,
+    // SECRETS_DIR
+    pub secrets_dir: Option<String>,
+    // VAULT_ADDR
+    pub vault_addr: Option<String>,
+    // VAULT_SECRET_PATH
+    pub vault_secret_path: Option<String>,
+    // LOG_FORMAT
+    pub log_format: Option<String>,
+    // OPENAI_RATE_LIMIT
+    pub openai_rate_limit: Option<u32>,
+    // OPENAI_TOKEN_LIMIT
+    pub openai_token_limit: Option<u32>,
+    // USER_RATE_LIMIT
+    pub user_rate_limit: Option<u32>,
+    // DAILY_REPLY_CAP
+    pub daily_reply_cap: Option<u32>,
+    // DAILY_BUDGET_USD
+    pub daily_budget_usd: Option<f64>,
+    // MONTHLY_BUDGET_USD
+    pub monthly_budget_usd: Option<f64>,
+    // MENTION_MAX_ATTEMPTS
+    pub mention_max_attempts: Option<u32>,
+    // MENTION_WORKERS
+    pub mention_workers: Option<usize>,
+    // MENTION_TTL_DAYS
+    pub mention_ttl_days: Option<u32>,
+    // VISION_TTL_DAYS
+    pub vision_ttl_days: Option<u32>,
+    // STAGE_TIMEOUTS
+    pub stage_timeouts: Option<String>,
+    // HEALTH_ADDR
+    pub health_addr: Option<String>,
+    // DASHBOARD
+    pub dashboard: Option<String>,
+    // DASHBOARD_ADDR
+    pub dashboard_addr: Option<String>,
+    // API_ADDR
+    pub api_addr: Option<String>,
+    // GRPC_ADDR
+    pub grpc_addr: Option<String>,
+    // MENTION_SOURCE
+    pub mention_source: Option<String>,
+    // MENTION_QUEUE_URL
+    pub mention_queue_url: Option<String>,
+    // MENTION_TOPIC
+    pub mention_topic: Option<String>,
+    // MENTION_GROUP
+    pub mention_group: Option<String>,
+}
+
+// The environment as it was at startup layered over the config file. Reloading the file swaps it here instead of
+// writing to the environment, which other threads may be reading at the same time
+pub struct Settings {
+    // Environment variables, read once
+    env: HashMap<String, String>,
+    // Settings of the last loaded config file
+    file: RwLock<AppConfig>,
+}
+
+// Watcher reloading the config file when it changes
+pub struct ConfigWatcher {
+    // Path of the watched config file
+    path: PathBuf,
+    // File system events of the config file's directory
+    events: Receiver<notify::Result<Event>>,
+    // Watcher kept alive for as long as events are needed
+    _watcher: RecommendedWatcher,
+}
+
+// Error loading the config file
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Cannot read config file {path}: {source}")]
+    Read { path: String, source: io::Error },
+    #[error("Invalid config file {path}: {message}")]
+    Parse { path: String, message: String },
+    #[error("Unsupported config file {0}, expected .toml, .yaml or .yml")]
+    Format(String),
+    #[error("Invalid settings: {}", .0.join("; "))]
+    Invalid(Vec<String>),
+}
+
+impl AppConfig {
+    // Load settings from the TOML or YAML file in CLARA_CONFIG, empty when it is not set
+    pub fn load() -> Result<Self, ConfigError> {
+        match var("CLARA_CONFIG").ok().filter(|path| !path.is_empty()) {
+            Some(path) => Self::from_file(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    // Load settings from a TOML or YAML file, picked by extension
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let extension = Path::new(path).extension().and_then(|extension| extension.to_str());
+        let contents = fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_string(),
+            source,
+        })?;
+
+        // Both parsers name the offending key in their messages
+        let parsed = match extension {
+            Some("toml") => toml::from_str(&contents).map_err(|err| err.to_string()),
+            Some("yaml" | "yml") => serde_yaml::from_str(&contents).map_err(|err| err.to_string()),
+            _ => return Err(ConfigError::Format(path.to_string())),
+        };
+
+        parsed.map_err(|message| ConfigError::Parse {
+            path: path.to_string(),
+            message,
+        })
+    }
+
+    // Check the effective settings from defaults, file and environment together, reporting every problem at once.
+    // Twitter credentials are only checked when the bot is going to answer mentions
+    pub fn validate(answers_mentions: bool) -> Result<(), ConfigError> {
+        let mut problems = Vec::new();
+
+        let twitter_secrets = if answers_mentions { TWITTER_SECRETS } else { &[] };
+        for name in REQUIRED_SECRETS.iter().chain(twitter_secrets) {
+            if let Err(err) = secrets().get(name) {
+                problems.push(format!("{} is required: {}", name, err));
+            }
+        }
+
+        match var("TRANSLATE_PROMPT") {
+            Ok(prompt) if !prompt.is_empty() => {
+                if let Err(problem) = check_template(&prompt) {
+                    problems.push(format!("TRANSLATE_PROMPT {}", problem));
+                }
+            }
+            _ => problems.push("TRANSLATE_PROMPT is required".to_string()),
+        }
+
+        // Credentials only matter for the providers in use
+        let providers = var("VISION_PROVIDER").unwrap_or_default();
+        for provider in providers.split(',').map(str::trim) {
+            match provider {
+                "" | "google" => {
+                    if let Err(problem) = check_service_account() {
+                        problems.push(format!("google vision needs {}: {}", SERVICE_ACCOUNT_FILE, problem));
+                    }
+                }
+                "rekognition" => {
+                    for name in AWS_SECRETS {
+                        if let Err(err) = secrets().get(name) {
+                            problems.push(format!("{} is required by the rekognition provider: {}", name, err));
+                        }
+                    }
+                }
+                other => problems.push(format!("VISION_PROVIDER {} is unknown", other)),
+            }
+        }
+
+        if let Ok(model) = var("VISION_MODEL") {
+            if !model.is_empty() && !VISION_MODELS.contains(&model.as_str()) {
+                problems.push(format!("VISION_MODEL {} is unknown", model));
+            }
+        }
+
+        match max_results_from_env() {
+            Ok(0) => problems.push("VISION_MAX_RESULTS must be at least 1".to_string()),
+            Ok(_) => {}
+            Err(err) => problems.push(format!("VISION_MAX_RESULTS {}", err)),
+        }
+
+        match max_edge_from_env() {
+            Ok(edge) if edge != 0 && edge < MIN_VISION_MAX_EDGE => {
+                problems.push(format!("VISION_MAX_EDGE must be 0 or at least {}", MIN_VISION_MAX_EDGE))
+            }
+            Ok(_) => {}
+            Err(err) => problems.push(format!("VISION_MAX_EDGE {}", err)),
+        }
+
+        if let Err(err) = ContextSource::from_env() {
+            problems.push(err.to_string());
+        }
+        if let Err(err) = TextPolicy::from_env() {
+            problems.push(err.to_string());
+        }
+        if let Err(err) = FacePolicy::from_env() {
+            problems.push(err.to_string());
+        }
+        if let Err(err) = LogFormat::from_env() {
+            problems.push(err.to_string());
+        }
+        for name in [
+            "OPENAI_RATE_LIMIT",
+            "OPENAI_TOKEN_LIMIT",
+            "USER_RATE_LIMIT",
+            "DAILY_REPLY_CAP",
+        ] {
+            if let Err(err) = rate_limit_from_env(name, 0) {
+                problems.push(err.to_string());
+            }
+        }
+        if let Err(err) = Budget::from_env() {
+            problems.push(err.to_string());
+        }
+        if let Err(err) = RequeuePolicy::from_env() {
+            problems.push(err.to_string());
+        }
+        if let Err(err) = workers_from_env() {
+            problems.push(err.to_string());
+        }
+        for name in ["MENTION_TTL_DAYS", "VISION_TTL_DAYS"] {
+            if let Err(err) = ttl_from_env(name, 0) {
+                problems.push(err.to_string());
+            }
+        }
+        if let Err(err) = StageTimeouts::from_env() {
+            problems.push(err.to_string());
+        }
+        if let Err(err) = health_addr_from_env() {
+            problems.push(err.to_string());
+        }
+        if let Err(err) = dashboard_from_env() {
+            problems.push(err.to_string());
+        }
+        if let Err(err) = dashboard_addr_from_env() {
+            problems.push(err.to_string());
+        }
+        if let Err(err) = api_addr_from_env() {
+            problems.push(err.to_string());
+        }
+        if let Err(err) = grpc_addr_from_env() {
+            problems.push(err.to_string());
+        }
+        match SourceConfig::from_env() {
+            Ok(source) if source.kind == SourceKind::Sqs => {
+                for name in AWS_SECRETS {
+                    if let Err(err) = secrets().get(name) {
+                        problems.push(format!("{} is required by the sqs mention source: {}", name, err));
+                    }
+                }
+            }
+            Ok(_) => {}
+            Err(err) => problems.push(err.to_string()),
+        }
+
+        // Theme dates are only parsed when checked, so check every theme once
+        match ThemeCalendar::load() {
+            Ok(calendar) => {
+                let today = Local::now().date_naive();
+                for theme in &calendar.themes {
+                    if let Err(err) = theme.is_active(today) {
+                        problems.push(format!("THEME_CALENDAR theme {}: {}", theme.name, err));
+                    }
+                }
+            }
+            Err(err) => problems.push(format!("THEME_CALENDAR {}", err)),
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Invalid(problems))
+        }
+    }
+
+    // Settings present in the file, under their environment variable names
+    pub fn entries(&self) -> Vec<(&'static str, String)> {
+        let entries = [
+            ("TRANSLATE_PROMPT", self.translate_prompt.clone()),
+            ("THEME_CALENDAR", self.theme_calendar.clone()),
+            ("VISION_PROVIDER", self.vision_provider.clone()),
+            ("VISION_MODEL", self.vision_model.clone()),
+            ("VISION_MAX_RESULTS", self.vision_max_results.map(|max| max.to_string())),
+            ("VISION_MAX_EDGE", self.vision_max_edge.map(|max| max.to_string())),
+            ("VISION_CONTEXT", self.vision_context.clone()),
+            ("VISION_OCR", self.vision_ocr.clone()),
+            ("FACE_POLICY", self.face_policy.clone()),
+            ("AWS_REGION", self.aws_region.clone()),
+            ("SECRETS_PROVIDER", self.secrets_provider.clone()),
+            ("SECRETS_DIR", self.secrets_dir.clone()),
+            ("VAULT_ADDR", self.vault_addr.clone()),
+            ("VAULT_SECRET_PATH", self.vault_secret_path.clone()),
+            ("LOG_FORMAT", self.log_format.clone()),
+            ("OPENAI_RATE_LIMIT", self.openai_rate_limit.map(|max| max.to_string())),
+            ("OPENAI_TOKEN_LIMIT", self.openai_token_limit.map(|max| max.to_string())),
+            ("USER_RATE_LIMIT", self.user_rate_limit.map(|max| max.to_string())),
+            ("DAILY_REPLY_CAP", self.daily_reply_cap.map(|max| max.to_string())),
+            ("DAILY_BUDGET_USD", self.daily_budget_usd.map(|usd| usd.to_string())),
+            ("MONTHLY_BUDGET_USD", self.monthly_budget_usd.map(|usd| usd.to_string())),
+            (
+                "MENTION_MAX_ATTEMPTS",
+                self.mention_max_attempts.map(|max| max.to_string()),
+            ),
+            ("MENTION_WORKERS", self.mention_workers.map(|max| max.to_string())),
+            ("MENTION_TTL_DAYS", self.mention_ttl_days.map(|days| days.to_string())),
+            ("VISION_TTL_DAYS", self.vision_ttl_days.map(|days| days.to_string())),
+            ("STAGE_TIMEOUTS", self.stage_timeouts.clone()),
+            ("HEALTH_ADDR", self.health_addr.clone()),
+            ("DASHBOARD", self.dashboard.clone()),
+            ("DASHBOARD_ADDR", self.dashboard_addr.clone()),
+            ("API_ADDR", self.api_addr.clone()),
+            ("GRPC_ADDR", self.grpc_addr.clone()),
+            ("MENTION_SOURCE", self.mention_source.clone()),
+            ("MENTION_QUEUE_URL", self.mention_queue_url.clone()),
+            ("MENTION_TOPIC", self.mention_topic.clone()),
+            ("MENTION_GROUP", self.mention_group.clone()),
+        ];
+
+        entries
+            .into_iter()
+            .filter_map(|(name, value)| value.map(|value| (name, value)))
+            .collect()
+    }
+
+    // Make these the config file settings, variables set in the environment take precedence. Returns the names of
+    // the settings whose value changed
+    pub fn apply(&self) -> Vec<&'static str> {
+        settings().replace(self.clone())
+    }
+}
+
+impl Settings {
+    // Layer the given environment variables over an empty config file
+    pub fn new(env: HashMap<String, String>) -> Self {
+        Self {
+            env,
+            file: RwLock::default(),
+        }
+    }
+
+    // Value of a setting, from the environment when it sets one and the config file otherwise
+    pub fn get(&self, name: &str) -> Result<String, VarError> {
+        if let Some(value) = self.overridden(name) {
+            return Ok(value.clone());
+        }
+
+        let file = self.file.read().unwrap();
+        let value = file
+            .entries()
+            .into_iter()
+            .find(|(entry, _)| *entry == name)
+            .map(|(_, value)| value);
+        value
+            .or_else(|| self.env.get(name).cloned())
+            .ok_or(VarError::NotPresent)
+    }
+
+    // Swap in the settings of a new config file, returning the names of the settings whose value changed.
+    // Settings the environment overrides keep their value
+    pub fn replace(&self, config: AppConfig) -> Vec<&'static str> {
+        let mut file = self.file.write().unwrap();
+        let old = file.entries();
+        let new = config.entries();
+        let value = |entries: &[(&'static str, String)], name: &str| {
+            entries
+                .iter()
+                .find(|(entry, _)| *entry == name)
+                .map(|(_, value)| value.clone())
+        };
+
+        let mut names: Vec<&'static str> = old.iter().chain(&new).map(|(name, _)| *name).collect();
+        names.sort();
+        names.dedup();
+
+        let mut changed = Vec::new();
+        for name in names {
+            if value(&old, name) == value(&new, name) {
+                continue;
+            }
+            if self.overridden(name).is_some() {
+                info!("Ignoring the config file value of {}, the environment sets it", name);
+                continue;
+            }
+            changed.push(name);
+        }
+
+        *file = config;
+        changed
+    }
+
+    // Non-empty value of the variable in the environment
+    fn overridden(&self, name: &str) -> Option<&String> {
+        self.env.get(name).filter(|value| !value.is_empty())
+    }
+}
+
+impl ConfigWatcher {
+    // Watch the file in CLARA_CONFIG, None when no config file is used
+    pub fn from_env() -> anyhow::Result<Option<Self>> {
+        let Some(path) = var("CLARA_CONFIG").ok().filter(|path| !path.is_empty()) else {
+            return Ok(None);
+        };
+        let path = PathBuf::from(path);
+
+        // Watch the directory, editors often replace the file instead of writing to it
+        let (sender, events) = channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        let directory = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        watcher.watch(&directory, RecursiveMode::NonRecursive)?;
+
+        Ok(Some(Self {
+            path,
+            events,
+            _watcher: watcher,
+        }))
+    }
+
+    // Reload the file if it changed since the last call and apply the new settings. Returns the names of the
+    // changed settings, empty when nothing changed or the new file is invalid
+    pub fn poll(&mut self) -> Vec<&'static str> {
+        let file_name = self.path.file_name();
+        let touched = self
+            .events
+            .try_iter()
+            .filter_map(Result::ok)
+            .any(|event| event.paths.iter().any(|path| path.file_name() == file_name));
+        if !touched {
+            return Vec::new();
+        }
+
+        let config = match AppConfig::from_file(&self.path.to_string_lossy()) {
+            Ok(config) => config,
+            Err(err) => {
+                error!("Keeping the previous settings: {}", err);
+                return Vec::new();
+            }
+        };
+
+        config.apply()
+    }
+}
+
+// Settings shared by every component, taking the environment as it is on first use
+pub fn settings() -> &'static Settings {
+    SETTINGS.get_or_init(|| {
+        let env = env::vars_os()
+            .filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)))
+            .collect();
+        Settings::new(env)
+    })
+}
+
+// Read a setting in place of env::var, settings come from the environment at startup and the config file
+pub fn var(name: &str) -> Result<String, VarError> {
+    settings().get(name)
+}
+
+// Check that a prompt template only uses known placeholders and balanced braces
+fn check_template(template: &str) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        if rest[start..].starts_with('}') {
+            return Err("has a } without a matching {".to_string());
+        }
+
+        let end = rest[start..]
+            .find('}')
+            .ok_or_else(|| "has a { without a matching }".to_string())?;
+        let name = &rest[start + 1..start + end];
+        if !PROMPT_PLACEHOLDERS.contains(&name) {
+            return Err(format!(
+                "has unknown placeholder {{{}}}, expected {{}}, {{subject}}, {{style}}, {{color}} or {{mood}}",
+                name
+            ));
+        }
+        rest = &rest[start + end + 1..];
+    }
+
+    Ok(())
+}
+
+// Check that the Google service account file has the fields used for authentication
+fn check_service_account() -> Result<(), String> {
+    let contents = fs::read_to_string(SERVICE_ACCOUNT_FILE).map_err(|err| err.to_string())?;
+    let key: serde_json::Value = serde_json::from_str(&contents).map_err(|err| err.to_string())?;
+
+    for field in ["client_email", "private_key"] {
+        if !key[field].is_string() {
+            return Err(format!("missing {}", field));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings_with(env: &[(&str, &str)]) -> Settings {
+        Settings::new(
+            env.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        )
+    }
+
+    fn config(prompt: Option<&str>, max_edge: Option<u32>) -> AppConfig {
+        AppConfig {
+            translate_prompt: prompt.map(str::to_string),
+            vision_max_edge: max_edge,
+            ..AppConfig::default()
+        }
+    }
+
+    #[test]
+    fn file_fills_in_what_the_environment_does_not_set() {
+        let settings = settings_with(&[("TRANSLATE_PROMPT", "from env"), ("VISION_MAX_EDGE", "")]);
+        settings.replace(config(Some("from file"), Some(512)));
+
+        assert_eq!(settings.get("TRANSLATE_PROMPT").unwrap(), "from env");
+        assert_eq!(settings.get("VISION_MAX_EDGE").unwrap(), "512");
+        assert_eq!(settings.get("VISION_MODEL"), Err(VarError::NotPresent));
+    }
+
+    #[test]
+    fn reload_reports_changes_the_environment_does_not_override() {
+        let settings = settings_with(&[("TRANSLATE_PROMPT", "from env")]);
+        let changed = settings.replace(config(Some("first"), Some(512)));
+        assert_eq!(changed, vec!["VISION_MAX_EDGE"]);
+
+        let changed = settings.replace(config(Some("second"), Some(1024)));
+        assert_eq!(changed, vec!["VISION_MAX_EDGE"]);
+        assert_eq!(settings.get("VISION_MAX_EDGE").unwrap(), "1024");
+        assert_eq!(settings.get("TRANSLATE_PROMPT").unwrap(), "from env");
+    }
+
+    #[test]
+    fn setting_removed_from_the_file_is_unset() {
+        let settings = settings_with(&[]);
+        settings.replace(config(None, Some(512)));
+
+        assert_eq!(settings.replace(config(None, None)), vec!["VISION_MAX_EDGE"]);
+        assert_eq!(settings.get("VISION_MAX_EDGE"), Err(VarError::NotPresent));
+        assert!(settings.replace(config(None, None)).is_empty());
+    }
+
+    #[test]
+    fn reload_does_not_touch_the_environment() {
+        let settings = settings_with(&[]);
+        settings.replace(config(None, Some(777)));
+
+        assert_eq!(settings.get("VISION_MAX_EDGE").unwrap(), "777");
+        assert!(env::var("VISION_MAX_EDGE").map_or(true, |value| value != "777"));
+    }
+}
@@ -0,0 +1,348 @@
+// Import collections, file and synchronization handling
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufWriter},
+    sync::Mutex,
+    time::Duration,
+};
+
+// Import date handling
+use chrono::{DateTime, Datelike, Months, Utc};
+// Import error handling
+use anyhow::{anyhow, Context, Result};
+// Import serialization traits
+use serde::{Deserialize, Serialize};
+
+// Import settings and metrics for spend per operation
+use crate::{config, metrics::metrics};
+
+// Estimated price in USD of one call, by operation
+const PRICES: &[(&str, f64)] = &[
+    // Label, safe search and text detection on one image
+    ("vision.google", 0.0045),
+    // Label, moderation, face and text detection on one image
+    ("vision.rekognition", 0.004),
+    // One GPT-4 prompt rewrite of a few hundred tokens
+    ("openai.prompt", 0.02),
+    // One DALL-E 3 HD image at 1792x1024
+    ("openai.image", 0.12),
+    // One embedding of a short description
+    ("openai.embedding", 0.00001),
+];
+
+// Spending limits in USD, None for no limit
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Budget {
+    pub daily: Option<f64>,
+    pub monthly: Option<f64>,
+}
+
+// A budget that was reached
+#[derive(Debug, Clone, PartialEq)]
+pub struct BudgetExceeded {
+    // Budget period, e.g. "daily"
+    pub period: &'static str,
+    // Period the spend belongs to, e.g. "2026-10-15" or "2026-10"
+    pub key: String,
+    // Estimated spend in the period
+    pub spent: f64,
+    // Configured limit
+    pub limit: f64,
+}
+
+// Persistent tally of estimated spend across providers
+#[derive(Serialize, Deserialize)]
+pub struct CostTracker {
+    // Path to storage file
+    file_path: String,
+    // Estimated spend in USD per day, keyed by date, e.g. "2026-10-15"
+    days: Mutex<BTreeMap<String, f64>>,
+    // Budget period the operator was last alerted about, e.g. "daily 2026-10-15"
+    #[serde(default)]
+    alerted: Mutex<Option<String>>,
+    // Serializes saves, so one can't replace the file while another writes it
+    #[serde(skip)]
+    saving: Mutex<()>,
+}
+
+impl Budget {
+    // Read the limits from DAILY_BUDGET_USD and MONTHLY_BUDGET_USD, unset or empty for no limit
+    pub fn from_env() -> Result<Self> {
+        let limit = |name: &str| -> Result<Option<f64>> {
+            match config::var(name) {
+                Ok(value) if !value.is_empty() => {
+                    let limit: f64 = value.parse().map_err(|err| anyhow!("{} {}", name, err))?;
+                    if !limit.is_finite() || limit <= 0.0 {
+                        return Err(anyhow!("{} must be a positive amount", name));
+                    }
+                    Ok(Some(limit))
+                }
+                _ => Ok(None),
+            }
+        };
+
+        Ok(Self {
+            daily: limit("DAILY_BUDGET_USD")?,
+            monthly: limit("MONTHLY_BUDGET_USD")?,
+        })
+    }
+}
+
+impl BudgetExceeded {
+    // Time until the budget period ends and spending starts over
+    pub fn resets_in(&self) -> Duration {
+        resets_in(self.period, Utc::now())
+    }
+}
+
+// Estimated price in USD of one call of an operation, 0 when unknown
+pub fn estimate(operation: &str) -> f64 {
+    PRICES
+        .iter()
+        .find(|(name, _)| *name == operation)
+        .map_or(0.0, |(_, price)| *price)
+}
+
+impl CostTracker {
+    // Load storage from file, create new if file doesn't exist. A file that can't be read is an error rather
+    // than a fresh start, which would forget the spend and let the bot go over budget
+    pub fn load_from_file(file_path: &str) -> Result<Self> {
+        // Read existing file or create new one
+        let contents = match fs::read_to_string(file_path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                File::create(file_path)?;
+                String::new()
+            }
+            contents => contents?,
+        };
+        if contents.trim().is_empty() {
+            return Ok(CostTracker {
+                file_path: file_path.to_string(),
+                days: Mutex::new(BTreeMap::new()),
+                alerted: Mutex::new(None),
+                saving: Mutex::new(()),
+            });
+        }
+
+        let mut tracker: CostTracker =
+            serde_json::from_str(&contents).with_context(|| format!("Cannot read spend from {}", file_path))?;
+        tracker.file_path = file_path.to_string();
+
+        Ok(tracker)
+    }
+
+    // Save current storage state to file. It is written next to it and then renamed over it, so a crash
+    // mid-write leaves the previous file intact
+    fn save_to_file(&self) -> Result<()> {
+        let _saving = self.saving.lock().unwrap();
+        let temp_path = format!("{}.tmp", self.file_path);
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        serde_json::to_writer(&mut writer, &self)?;
+        writer.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        fs::rename(&temp_path, &self.file_path)?;
+
+        Ok(())
+    }
+
+    // Add the estimated price of one call, e.g. "openai.image"
+    pub fn record(&self, operation: &str) -> Result<()> {
+        let price = estimate(operation);
+        // Counters are whole numbers, so spend is counted in millionths of a dollar
+        let micro_usd = (price * 1_000_000.0).round() as u64;
+        metrics().increment(&format!("cost.microusd.{}", operation), micro_usd);
+
+        *self.days.lock().unwrap().entry(today()).or_default() += price;
+        self.save_to_file()
+    }
+
+    // Estimated spend today
+    pub fn spent_today(&self) -> f64 {
+        self.days.lock().unwrap().get(&today()).copied().unwrap_or_default()
+    }
+
+    // Estimated spend this month
+    pub fn spent_this_month(&self) -> f64 {
+        let month = this_month();
+        self.days
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(day, _)| day.starts_with(&month))
+            .map(|(_, spent)| spent)
+            .sum()
+    }
+
+    // Get the first budget reached, if any
+    pub fn exceeded(&self, budget: &Budget) -> Option<BudgetExceeded> {
+        let daily = budget.daily.map(|limit| BudgetExceeded {
+            period: "daily",
+            key: today(),
+            spent: self.spent_today(),
+            limit,
+        });
+        let monthly = budget.monthly.map(|limit| BudgetExceeded {
+            period: "monthly",
+            key: this_month(),
+            spent: self.spent_this_month(),
+            limit,
+        });
+
+        daily
+            .into_iter()
+            .chain(monthly)
+            .find(|exceeded| exceeded.spent >= exceeded.limit)
+    }
+
+    // Check whether the operator still needs to hear about a reached budget, remembering that they will
+    pub fn needs_alert(&self, exceeded: &BudgetExceeded) -> Result<bool> {
+        let period = format!("{} {}", exceeded.period, exceeded.key);
+        {
+            let mut alerted = self.alerted.lock().unwrap();
+            if alerted.as_deref() == Some(period.as_str()) {
+                return Ok(false);
+            }
+            *alerted = Some(period);
+        }
+        self.save_to_file()?;
+
+        Ok(true)
+    }
+}
+
+// Current UTC date, e.g. "2026-10-15"
+fn today() -> String {
+    Utc::now().format("%Y-%m-%d").to_string()
+}
+
+// Current UTC month, e.g. "2026-10"
+fn this_month() -> String {
+    Utc::now().format("%Y-%m").to_string()
+}
+
+// Time from `now` until the next UTC midnight, or the first of next month for the monthly budget
+fn resets_in(period: &str, now: DateTime<Utc>) -> Duration {
+    let today = now.date_naive();
+    let reset = match period {
+        "monthly" => today
+            .with_day(1)
+            .and_then(|first| first.checked_add_months(Months::new(1))),
+        _ => today.succ_opt(),
+    };
+
+    reset
+        .and_then(|day| day.and_hms_opt(0, 0, 0))
+        .and_then(|midnight| (midnight.and_utc() - now).to_std().ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Path of a scratch file unique to the test
+    fn scratch_file(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("clara-costs-{}-{}.json", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    fn at(time: &str) -> DateTime<Utc> {
+        time.parse().unwrap()
+    }
+
+    #[test]
+    fn daily_budget_resets_at_the_next_midnight() {
+        let wait = resets_in("daily", at("2026-10-15T22:30:00Z"));
+
+        assert_eq!(wait, Duration::from_secs(90 * 60));
+    }
+
+    #[test]
+    fn monthly_budget_resets_on_the_first_of_next_month() {
+        assert_eq!(
+            resets_in("monthly", at("2026-10-31T23:00:00Z")),
+            Duration::from_secs(3600)
+        );
+        assert_eq!(
+            resets_in("monthly", at("2026-12-31T00:00:00Z")),
+            Duration::from_secs(24 * 3600)
+        );
+    }
+
+    #[test]
+    fn spend_survives_a_restart() {
+        let path = scratch_file("restart");
+        let tracker = CostTracker::load_from_file(&path).unwrap();
+        tracker.record("openai.image").unwrap();
+        tracker.record("openai.prompt").unwrap();
+
+        let reloaded = CostTracker::load_from_file(&path).unwrap();
+
+        assert!((reloaded.spent_today() - 0.14).abs() < 1e-9);
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupt_spend_file_is_an_error() {
+        let path = scratch_file("corrupt");
+        fs::write(&path, "{\"days\": {\"2026-10-15\": 1.2").unwrap();
+
+        let result = CostTracker::load_from_file(&path);
+
+        assert!(result.is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    // Tracker with spend per day, saved at a scratch file
+    fn tracker(name: &str, days: &[(String, f64)]) -> CostTracker {
+        CostTracker {
+            file_path: scratch_file(name),
+            days: Mutex::new(days.iter().cloned().collect()),
+            alerted: Mutex::new(None),
+            saving: Mutex::new(()),
+        }
+    }
+
+    #[test]
+    fn first_budget_reached_is_reported() {
+        let tracker = tracker("exceeded", &[(today(), 5.0), ("2000-01-01".to_string(), 100.0)]);
+        let budget = |daily, monthly| Budget { daily, monthly };
+
+        assert_eq!(tracker.exceeded(&Budget::default()), None);
+        assert_eq!(tracker.exceeded(&budget(Some(10.0), Some(20.0))), None);
+
+        let daily = tracker.exceeded(&budget(Some(5.0), Some(4.0))).unwrap();
+        assert_eq!((daily.period, daily.key), ("daily", today()));
+
+        // Spend from another month doesn't count against this one
+        let monthly = tracker.exceeded(&budget(Some(10.0), Some(4.0))).unwrap();
+        assert_eq!((monthly.period, monthly.key), ("monthly", this_month()));
+        assert!((monthly.spent - 5.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn operator_is_alerted_once_per_budget_period() {
+        let tracker = tracker("alert", &[(today(), 5.0)]);
+        let budget = Budget {
+            daily: Some(1.0),
+            monthly: Some(1.0),
+        };
+        let daily = tracker.exceeded(&budget).unwrap();
+        let monthly = BudgetExceeded {
+            period: "monthly",
+            key: this_month(),
+            ..daily.clone()
+        };
+
+        assert!(tracker.needs_alert(&daily).unwrap());
+        assert!(!tracker.needs_alert(&daily).unwrap());
+        assert!(tracker.needs_alert(&monthly).unwrap());
+
+        // The alert is remembered across restarts
+        let reloaded = CostTracker::load_from_file(&tracker.file_path).unwrap();
+        assert!(!reloaded.needs_alert(&monthly).unwrap());
+        fs::remove_file(&tracker.file_path).unwrap();
+    }
+}
@@ -0,0 +1,229 @@
+// Import file, formatting and networking handling
+use std::{fmt::Write, fs, net::SocketAddr};
+
+// Import date handling
+use chrono::DateTime;
+// Import error handling
+use anyhow::{anyhow, Result};
+
+// Import the audit log records, handler and metrics
+use crate::{
+    audit::{AuditEvent, AuditRecord},
+    config,
+    handler::Handler,
+    metrics::metrics,
+};
+
+// Address the dashboard is served on when DASHBOARD_ADDR isn't set
+const DEFAULT_DASHBOARD_ADDR: &str = "127.0.0.1:8081";
+// Mentions listed under recent generations
+const RECENT_MENTIONS: usize = 30;
+// Counter prefixes counted as provider errors, and restarts of the bot's own tasks
+const ERROR_COUNTERS: &[&str] = &[
+    "retry.failures.",
+    "vision.failures.",
+    "breaker.opened.",
+    "stage.timeouts.",
+    "supervisor.restarts.",
+];
+// Styling of the page
+const STYLE: &str = "body{font-family:sans-serif;margin:2em}table{border-collapse:collapse;margin-bottom:2em}\
+                     td,th{border:1px solid #ccc;padding:4px 8px;text-align:left;vertical-align:top}";
+
+// Read whether to serve the dashboard from DASHBOARD (off by default)
+pub fn dashboard_from_env() -> Result<bool> {
+    let dashboard = config::var("DASHBOARD").unwrap_or_default();
+
+    match dashboard.as_str() {
+        "" | "off" => Ok(false),
+        "on" => Ok(true),
+        other => Err(anyhow!("Unknown DASHBOARD {}", other)),
+    }
+}
+
+// Read the address to serve the dashboard on from DASHBOARD_ADDR (127.0.0.1:8081 by default)
+pub fn dashboard_addr_from_env() -> Result<SocketAddr> {
+    let addr = config::var("DASHBOARD_ADDR")
+        .ok()
+        .filter(|addr| !addr.is_empty())
+        .unwrap_or_else(|| DEFAULT_DASHBOARD_ADDR.to_string());
+
+    addr.parse()
+        .map_err(|err| anyhow!("DASHBOARD_ADDR must be an address like 127.0.0.1:8081: {}", err))
+}
+
+// Render the dashboard page from the handler's stores and the metrics collected so far
+pub fn render(handler: &Handler) -> Result<String> {
+    let snapshot = metrics().snapshot();
+    let counter = |name: &str| snapshot.counters.get(name).copied().unwrap_or_default();
+    let mut html = String::new();
+
+    write!(
+        html,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\"><title>Clara</title><style>{}</style></head><body>",
+        STYLE
+    )?;
+
+    // Spend against the budget
+    let budget = handler.budget();
+    let limit = |limit: Option<f64>| limit.map_or("none".to_string(), |limit| format!("${:.2}", limit));
+    write!(
+        html,
+        "<h2>Spend</h2><table><tr><th>Period</th><th>Spent</th><th>Budget</th></tr>\
+         <tr><td>Today</td><td>${:.2}</td><td>{}</td></tr>\
+         <tr><td>This month</td><td>${:.2}</td><td>{}</td></tr></table>",
+        handler.costs().spent_today(),
+        limit(budget.daily),
+        handler.costs().spent_this_month(),
+        limit(budget.monthly),
+    )?;
+
+    // Outcomes of mentions since the start
+    let completed = counter("mentions.completed");
+    let failed = counter("mentions.failed");
+    let error_rate = match completed + failed {
+        0 => 0.0,
+        attempts => failed as f64 * 100.0 / attempts as f64,
+    };
+    html.push_str("<h2>Mentions</h2><table>");
+    for (name, value) in [
+        ("Queued", handler.queue().len()?.to_string()),
+        ("Completed", completed.to_string()),
+        ("Failed attempts", failed.to_string()),
+        ("Error rate", format!("{:.1}%", error_rate)),
+        ("Given up on", counter("mentions.dead").to_string()),
+        ("Rate limited", counter("mentions.rate_limited").to_string()),
+        ("Deferred by the daily cap", counter("mentions.deferred").to_string()),
+        ("Over budget", counter("mentions.over_budget").to_string()),
+    ] {
+        write!(html, "<tr><th>{}</th><td>{}</td></tr>", name, value)?;
+    }
+    html.push_str("</table>");
+
+    // Provider errors
+    html.push_str("<h2>Errors</h2><table><tr><th>Counter</th><th>Count</th></tr>");
+    for (name, value) in &snapshot.counters {
+        if ERROR_COUNTERS.iter().any(|prefix| name.starts_with(prefix)) {
+            write!(html, "<tr><td>{}</td><td>{}</td></tr>", escape(name), value)?;
+        }
+    }
+    html.push_str("</table>");
+
+    // Latencies of the whole mention and each stage
+    html.push_str(
+        "<h2>Latencies</h2><table><tr><th>Stage</th><th>Count</th><th>p50</th><th>p95</th><th>p99</th>\
+         <th>Max</th></tr>",
+    );
+    for (name, timing) in &snapshot.timings {
+        if name == "mention.latency" || name.starts_with("stage.") {
+            write!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{}ms</td><td>{}ms</td><td>{}ms</td><td>{}ms</td></tr>",
+                escape(name),
+                timing.count,
+                timing.p50_ms,
+                timing.p95_ms,
+                timing.p99_ms,
+                timing.max_ms
+            )?;
+        }
+    }
+    html.push_str("</table>");
+
+    // Mentions waiting for an operator
+    html.push_str(
+        "<h2>Dead letters</h2><table><tr><th>Tweet</th><th>User</th><th>Attempts</th><th>Failed at</th>\
+         <th>Error</th></tr>",
+    );
+    for letter in handler.queue().dead_letters()? {
+        let error = letter.error.as_deref().unwrap_or_default();
+        write!(
+            html,
+            "<tr><td>{}</td><td>@{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&letter.tweet_id),
+            escape(letter.tweet.username.as_deref().unwrap_or_default()),
+            letter.attempts,
+            time(letter.failed_at),
+            escape(error.lines().next().unwrap_or_default()),
+        )?;
+    }
+    html.push_str("</table>");
+
+    // Recent generations from the audit log
+    html.push_str(
+        "<h2>Recent generations</h2><table><tr><th>Time</th><th>Tweet</th><th>User</th><th>Moderation</th>\
+         <th>Description</th><th>Image</th><th>Reply</th></tr>",
+    );
+    for records in handler.audit_log().recent(RECENT_MENTIONS)? {
+        html.push_str(&generation_row(&records));
+    }
+    html.push_str("</table></body></html>");
+
+    Ok(html)
+}
+
+// Read the image sent in reply to a mention, None if there was none
+pub fn image(handler: &Handler, tweet_id: &str) -> Result<Option<Vec<u8>>> {
+    let path = handler
+        .audit_log()
+        .by_tweet(tweet_id)?
+        .into_iter()
+        .rev()
+        .find_map(|record| match record.event {
+            AuditEvent::Image { path, .. } => Some(path),
+            _ => None,
+        });
+
+    match path {
+        Some(path) => Ok(Some(fs::read(path)?)),
+        None => Ok(None),
+    }
+}
+
+// Table row summarizing the records of one mention
+fn generation_row(records: &[AuditRecord]) -> String {
+    let latest = &records[records.len() - 1];
+    let tweet_id = latest.tweet_id.as_deref().unwrap_or_default();
+    let (mut moderation, mut description, mut reply) = (String::new(), String::new(), String::new());
+    let mut has_image = false;
+
+    for record in records {
+        match &record.event {
+            AuditEvent::Moderation { verdict, action, .. } => moderation = format!("{} ({})", verdict, action),
+            AuditEvent::Prompt { response, .. } => description = response.clone(),
+            AuditEvent::Image { .. } => has_image = true,
+            AuditEvent::Reply { text, .. } => reply = text.clone(),
+        }
+    }
+    let thumbnail = match has_image {
+        true => format!("<img src=\"/dashboard/images/{}\" width=\"160\">", escape(tweet_id)),
+        false => String::new(),
+    };
+
+    format!(
+        "<tr><td>{}</td><td>{}</td><td>@{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+        time(latest.timestamp),
+        escape(tweet_id),
+        escape(latest.user.as_deref().unwrap_or_default()),
+        escape(&moderation),
+        escape(&description),
+        thumbnail,
+        escape(&reply),
+    )
+}
+
+// Format a Unix timestamp for the page
+fn time(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default()
+}
+
+// Escape text for use in HTML
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
@@ -0,0 +1,247 @@
+// Import local modules for HTTP client, secrets and vision reports
+use crate::{http_client::HttpClient, secrets::secrets, vision::VisionReport};
+// Import error handling and logging
+use anyhow::{anyhow, Result};
+use log::error;
+// Import serialization traits
+use serde::{Deserialize, Serialize};
+// Import JSON handling utilities
+use serde_json::{self, Error};
+// Import JSON macro
+use ureq::json;
+// Import collections and file handling
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter},
+    process,
+};
+
+// OpenAI API endpoint for embeddings
+const OPENAI_EMBEDDINGS_URL: &str = "https://api.openai.com/v1/embeddings";
+// Embedding model applied to avatar descriptions
+const EMBEDDING_MODEL: &str = "text-embedding-3-small";
+
+// Structure to hold OpenAI API response for embeddings
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Embeddings {
+    // Vector of embeddings, one per input
+    pub data: Vec<EmbeddingData>,
+}
+
+// Structure to hold individual embedding from OpenAI
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EmbeddingData {
+    pub embedding: Vec<f32>,
+}
+
+// Main embedding client
+pub struct Embedder {
+    // OpenAI API key
+    key: String,
+    // HTTP client instance
+    http_client: HttpClient,
+}
+
+// Structure for the latest avatar embedding of a user
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct UserEmbedding {
+    // Embedding of the avatar description
+    pub vector: Vec<f32>,
+    // Avatar description the embedding was computed from
+    pub description: String,
+    // Image generated for this avatar
+    pub image_path: Option<String>,
+    // Hash of the prompt template and theme the image was drawn with, it is only reused while they match
+    #[serde(default)]
+    pub prompt_key: Option<String>,
+    // SHA-256 of the analyzed avatar, used to detect avatar changes
+    #[serde(default)]
+    pub avatar_hash: Option<String>,
+    // Analysis of the avatar after safety policies, reused while the avatar is unchanged
+    #[serde(default)]
+    pub report: Option<VisionReport>,
+    // Unix timestamp of the vision analysis the report comes from
+    #[serde(default)]
+    pub analyzed_at: Option<i64>,
+}
+
+// Structure for persistent storage of avatar embeddings per user
+#[derive(Serialize, Deserialize)]
+pub struct EmbeddingStore {
+    // Path to storage file
+    file_path: String,
+    // Latest embedding keyed by user ID
+    users: HashMap<String, UserEmbedding>,
+}
+
+impl Embedder {
+    // Initialize new embedding client
+    pub fn new() -> Result<Self> {
+        // Get OpenAI API key from the secrets provider
+        let key = secrets().get("OPENAI_API_KEY").unwrap_or_else(|err| {
+            error!("Missing OPENAI_API_KEY {}", err);
+            process::exit(1);
+        });
+
+        Ok(Self {
+            key,
+            http_client: HttpClient::new(),
+        })
+    }
+
+    // Create embedding for a text
+    pub fn embed(&self, text: &str) -> Result<Vec<f32>> {
+        let response = self.http_client.post_with_auth(
+            OPENAI_EMBEDDINGS_URL,
+            &self.key,
+            json!({
+              "model": EMBEDDING_MODEL,
+              "input": text,
+            }),
+        )?;
+
+        let embeddings: Embeddings = serde_json::from_str(&response)?;
+        embeddings
+            .data
+            .into_iter()
+            .next()
+            .map(|data| data.embedding)
+            .ok_or_else(|| anyhow!("Embeddings API returned no data"))
+    }
+}
+
+impl EmbeddingStore {
+    // Load storage from file, create new if file doesn't exist
+    pub fn load_from_file(file_path: &str) -> Result<Self, Error> {
+        // Open existing file or create new one
+        let file = File::open(file_path).unwrap_or_else(|_| File::create(file_path).unwrap());
+        let reader = BufReader::new(file);
+        // Try to deserialize existing data or create empty storage
+        serde_json::from_reader(reader).or_else(|_| {
+            Ok(EmbeddingStore {
+                file_path: file_path.to_string(),
+                users: HashMap::new(),
+            })
+        })
+    }
+
+    // Save current storage state to file
+    pub fn save_to_file(&self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.file_path)?;
+        let writer = BufWriter::new(file);
+        serde_json::to_writer(writer, &self).map_err(io::Error::other)
+    }
+
+    // Replace the user's latest embedding
+    pub fn insert(&mut self, user_id: String, embedding: UserEmbedding) {
+        self.users.insert(user_id, embedding);
+    }
+
+    // Get the user's latest embedding
+    pub fn get(&self, user_id: &str) -> Option<&UserEmbedding> {
+        self.users.get(user_id)
+    }
+
+    // Find other users whose avatars are most similar to the user's, best match first
+    pub fn similar_users(&self, user_id: &str, limit: usize) -> Vec<(String, f32)> {
+        let Some(target) = self.users.get(user_id) else {
+            return Vec::new();
+        };
+
+        let mut similar: Vec<(String, f32)> = self
+            .users
+            .iter()
+            .filter(|(id, _)| id.as_str() != user_id)
+            .map(|(id, embedding)| (id.clone(), cosine_similarity(&target.vector, &embedding.vector)))
+            .collect();
+        similar.sort_by(|a, b| b.1.total_cmp(&a.1));
+        similar.truncate(limit);
+
+        similar
+    }
+}
+
+// Cosine similarity of two vectors, 0 when either is empty or their lengths differ
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.is_empty() || a.len() != b.len() {
+        return 0.0;
+    }
+
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+
+    dot / (norm_a * norm_b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(users: &[(&str, Vec<f32>)]) -> EmbeddingStore {
+        let embedding = |vector: &Vec<f32>| UserEmbedding {
+            vector: vector.clone(),
+            description: String::new(),
+            image_path: None,
+            prompt_key: None,
+            avatar_hash: None,
+            report: None,
+            analyzed_at: None,
+        };
+
+        EmbeddingStore {
+            file_path: String::new(),
+            users: users
+                .iter()
+                .map(|(id, vector)| (id.to_string(), embedding(vector)))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn cosine_similarity_compares_directions() {
+        assert!((cosine_similarity(&[1.0, 2.0], &[2.0, 4.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 3.0]).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 0.0], &[-1.0, 0.0]) + 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn cosine_similarity_of_unusable_vectors_is_zero() {
+        assert_eq!(cosine_similarity(&[], &[]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0, 2.0], &[1.0, 2.0, 3.0]), 0.0);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 2.0]), 0.0);
+    }
+
+    #[test]
+    fn similar_users_are_ranked_best_first() {
+        let store = store(&[
+            ("alice", vec![1.0, 0.0]),
+            ("bob", vec![0.0, 1.0]),
+            ("carol", vec![0.9, 0.1]),
+            ("dave", vec![0.5, 0.5]),
+        ]);
+
+        let similar = store.similar_users("alice", 2);
+
+        let ids: Vec<&str> = similar.iter().map(|(id, _)| id.as_str()).collect();
+        assert_eq!(ids, ["carol", "dave"]);
+        assert!(similar[0].1 > similar[1].1);
+    }
+
+    #[test]
+    fn unknown_user_has_no_similar_users() {
+        let store = store(&[("alice", vec![1.0, 0.0])]);
+
+        assert!(store.similar_users("bob", 5).is_empty());
+        assert!(store.similar_users("alice", 5).is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+// Import synchronization handling
+use std::sync::OnceLock;
+
+// Import date handling
+use chrono::Utc;
+// Import WebSocket stream and sink helpers
+use futures_util::{SinkExt, StreamExt};
+// Import the HTTP server types used for the WebSocket upgrade
+use hyper::{
+    header::{HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, UPGRADE},
+    upgrade::Upgraded,
+    Body, Request, Response, StatusCode,
+};
+// Import serialization traits
+use serde::Serialize;
+// Import JSON building
+use serde_json::json;
+// Import the broadcast channel behind the bus
+use tokio::sync::broadcast::{self, error::RecvError};
+// Import the WebSocket protocol
+use tokio_tungstenite::{
+    tungstenite::{handshake::derive_accept_key, protocol::Role, Message},
+    WebSocketStream,
+};
+// Import logging
+use tracing::{info, warn};
+// Import query string parsing
+use url::form_urlencoded;
+
+// Import health check responses
+use crate::health::json_response;
+
+// Process-wide event bus
+static EVENTS: OnceLock<EventBus> = OnceLock::new();
+
+// Events buffered per subscriber, a subscriber falling further behind misses the oldest ones
+const EVENT_CAPACITY: usize = 256;
+
+// Progress of handling a mention
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum PipelineEvent {
+    // A mention is being handled
+    MentionReceived { user: Option<String> },
+    // The avatar was analyzed, or its earlier analysis reused
+    KeywordsExtracted { keywords: Vec<String> },
+    // The image to reply with is ready
+    ImageReady { path: String, reused: bool },
+    // A reply was posted
+    Posted { reply_id: Option<String> },
+    // An attempt failed, retried later unless the mention was given up on
+    Failed { error: String, retrying: bool },
+}
+
+// Event of a mention as sent to subscribers
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct EventRecord {
+    // Unix timestamp of the event
+    pub timestamp: i64,
+    // Tweet ID of the mention
+    pub request_id: String,
+    // The event itself
+    #[serde(flatten)]
+    pub event: PipelineEvent,
+}
+
+// Broadcast of pipeline events to whoever listens, e.g. WebSocket clients
+pub struct EventBus {
+    sender: broadcast::Sender<EventRecord>,
+}
+
+// Get the process-wide event bus
+pub fn events() -> &'static EventBus {
+    EVENTS.get_or_init(|| EventBus {
+        sender: broadcast::channel(EVENT_CAPACITY).0,
+    })
+}
+
+impl EventBus {
+    // Tell subscribers about an event of a mention, nothing happens when nobody listens
+    pub fn publish(&self, request_id: &str, event: PipelineEvent) {
+        let _ = self.sender.send(EventRecord {
+            timestamp: Utc::now().timestamp(),
+            request_id: request_id.to_string(),
+            event,
+        });
+    }
+
+    // Listen to the events published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<EventRecord> {
+        self.sender.subscribe()
+    }
+}
+
+// Upgrade a request to a WebSocket streaming events as JSON, only those of one mention with ?request_id=<tweet_id>
+pub fn websocket(request: Request<Body>) -> Response<Body> {
+    let is_websocket = request
+        .headers()
+        .get(UPGRADE)
+        .and_then(|upgrade| upgrade.to_str().ok())
+        .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"));
+    let key = match request.headers().get(SEC_WEBSOCKET_KEY) {
+        Some(key) if is_websocket => key,
+        _ => {
+            return json_response(
+                StatusCode::BAD_REQUEST,
+                json!({ "error": "Expected a WebSocket upgrade" }),
+            )
+        }
+    };
+    let accept = derive_accept_key(key.as_bytes());
+    let request_id = request.uri().query().and_then(|query| {
+        form_urlencoded::parse(query.as_bytes())
+            .find(|(name, _)| name == "request_id")
+            .map(|(_, value)| value.into_owned())
+    });
+
+    tokio::spawn(async move {
+        match hyper::upgrade::on(request).await {
+            Ok(upgraded) => {
+                let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                stream(socket, request_id).await;
+            }
+            Err(err) => warn!(error = ?err, "Failed to upgrade to a WebSocket"),
+        }
+    });
+
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let headers = response.headers_mut();
+    headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+    headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+    if let Ok(accept) = HeaderValue::from_str(&accept) {
+        headers.insert(SEC_WEBSOCKET_ACCEPT, accept);
+    }
+
+    response
+}
+
+// Send events to a WebSocket client until it goes away
+async fn stream(mut socket: WebSocketStream<Upgraded>, request_id: Option<String>) {
+    let mut events = events().subscribe();
+    info!(request_id = request_id.as_deref(), "Event stream opened");
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(record) if request_id.as_ref().is_none_or(|id| *id == record.request_id) => {
+                    let Ok(text) = serde_json::to_string(&record) else { continue };
+                    if socket.send(Message::Text(text)).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => warn!(missed, "Event stream fell behind"),
+                Err(RecvError::Closed) => break,
+            },
+            // Incoming messages only matter as a sign the client left, pings are answered by the socket
+            message = socket.next() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+
+    info!(request_id = request_id.as_deref(), "Event stream closed");
+}
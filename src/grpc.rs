@@ -0,0 +1,328 @@
+// Import networking and synchronization handling
+use std::{
+    net::{SocketAddr, TcpListener},
+    sync::Arc,
+};
+
+// Import base64 encoding of uploaded images
+use base64::{engine::general_purpose, Engine};
+// Import error handling
+use anyhow::{anyhow, Result};
+// Import the channel behind the progress stream
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+// Import the gRPC server
+use tonic::{
+    transport::{server::TcpIncoming, Server},
+    Request, Response, Status,
+};
+// Import logging
+use tracing::{error, info};
+
+// Import the breaker error, API token, handler, listeners and token checks, images, blocking calls and vision results
+use crate::{
+    api::api_token_from_env,
+    breaker::BreakerOpen,
+    config,
+    handler::{GenerationRefused, Handler},
+    health::{bind, tokens_match},
+    image::Image,
+    utils::blocking,
+    vision::{Category, VisionReport},
+};
+
+// Code generated from proto/clara.proto
+pub mod proto {
+    tonic::include_proto!("clara.v1");
+}
+
+// Import the generated service and messages
+use proto::{
+    generate_event::Event,
+    image_source::Source,
+    pipeline_server::{Pipeline, PipelineServer},
+    AnalyzeImageRequest, AnalyzeImageResponse, GenerateEvent, GenerateRequest, GenerateResult, ImageSource, Keyword,
+    Progress,
+};
+
+// Events buffered for a client reading the Generate stream slowly
+const EVENT_BUFFER: usize = 4;
+
+// Stream of Generate events sent to the client
+type EventSender = mpsc::Sender<Result<GenerateEvent, Status>>;
+
+// gRPC service running the generation pipeline, see proto/clara.proto
+pub struct PipelineService {
+    handler: Arc<Handler>,
+    // Bearer token every call needs, calls are refused without one
+    token: Option<String>,
+}
+
+// Read the address to serve gRPC on from GRPC_ADDR, e.g. 127.0.0.1:50051. None when unset
+pub fn grpc_addr_from_env() -> Result<Option<SocketAddr>> {
+    match config::var("GRPC_ADDR") {
+        Ok(value) if !value.is_empty() => value
+            .parse()
+            .map(Some)
+            .map_err(|err| anyhow!("GRPC_ADDR must be an address like 127.0.0.1:50051: {}", err)),
+        _ => Ok(None),
+    }
+}
+
+// Serve the pipeline over gRPC until the server fails. Uses the listener bound at startup, a restarted server binds
+// the address again
+pub async fn serve(addr: SocketAddr, listener: Option<TcpListener>, handler: Arc<Handler>) -> Result<()> {
+    let listener = match listener {
+        Some(listener) => listener,
+        None => bind(addr)?,
+    };
+    let incoming = TcpIncoming::from_listener(tokio::net::TcpListener::from_std(listener)?, true, None)
+        .map_err(|err| anyhow!("Cannot listen on {}: {}", addr, err))?;
+
+    let service = PipelineService {
+        handler,
+        token: api_token_from_env(),
+    };
+    info!(%addr, "Serving gRPC generation requests");
+    Server::builder()
+        .add_service(PipelineServer::new(service))
+        .serve_with_incoming(incoming)
+        .await?;
+
+    Ok(())
+}
+
+#[tonic::async_trait]
+impl Pipeline for PipelineService {
+    type GenerateStream = ReceiverStream<Result<GenerateEvent, Status>>;
+
+    async fn generate(&self, request: Request<GenerateRequest>) -> Result<Response<Self::GenerateStream>, Status> {
+        if let Some(refused) = unauthorized(self.token.as_deref(), &request) {
+            return Err(refused);
+        }
+        let client = client(&request);
+        self.handler.admit(&client).map_err(status)?;
+        let request = request.into_inner();
+        let source = request.image;
+        let image = blocking(move || image(source)).await.map_err(status)?;
+        let (sender, receiver) = mpsc::channel(EVENT_BUFFER);
+
+        let handler = self.handler.clone();
+        tokio::spawn(async move {
+            let (style, language) = (request.style.as_deref(), request.language.as_deref());
+            if let Err(err) = generate(&handler, &client, image, style, language, &sender).await {
+                let _ = sender.send(Err(status(err))).await;
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn analyze_image(
+        &self,
+        request: Request<AnalyzeImageRequest>,
+    ) -> Result<Response<AnalyzeImageResponse>, Status> {
+        if let Some(refused) = unauthorized(self.token.as_deref(), &request) {
+            return Err(refused);
+        }
+        self.handler.admit(&client(&request)).map_err(status)?;
+        let source = request.into_inner().image;
+        let image = blocking(move || image(source)).await.map_err(status)?;
+        let report = self.handler.analyze(image).await.map_err(status)?;
+
+        Ok(Response::new(analysis(report)))
+    }
+}
+
+// Run the pipeline for a Generate call, sending progress after each stage. Stops before the next stage once the
+// client is gone. The stages run their blocking calls on the blocking thread pool, progress is sent from here in
+// between
+async fn generate(
+    handler: &Handler,
+    client: &str,
+    image: Image,
+    style: Option<&str>,
+    language: Option<&str>,
+    events: &EventSender,
+) -> Result<()> {
+    let report = handler.analyze(image).await?;
+    if !send(events, Event::Progress(progress("vision"))).await {
+        return Ok(());
+    }
+
+    let description = handler.describe(client, &report, style, language).await?;
+    if !send(events, Event::Progress(progress("prompt"))).await {
+        return Ok(());
+    }
+
+    let (image, image_path) = handler.draw(client, &description).await?;
+    let result = GenerateResult {
+        description,
+        image: image.bytes(),
+        image_path,
+    };
+    send(events, Event::Result(result)).await;
+
+    Ok(())
+}
+
+// Send an event to the client, returning whether it is still listening
+async fn send(events: &EventSender, event: Event) -> bool {
+    events.send(Ok(GenerateEvent { event: Some(event) })).await.is_ok()
+}
+
+// Progress event for a finished stage
+fn progress(stage: &str) -> Progress {
+    Progress {
+        stage: stage.to_string(),
+    }
+}
+
+// Check that a call carries the API token as a bearer token in its authorization metadata, answering with the
+// problem if it can't go through. None when it can. Every call is refused while no token is set
+fn unauthorized<T>(token: Option<&str>, request: &Request<T>) -> Option<Status> {
+    let Some(token) = token else {
+        return Some(Status::permission_denied("This API is off, set API_TOKEN to use it"));
+    };
+
+    let given = request
+        .metadata()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if !tokens_match(given, token) {
+        return Some(Status::unauthenticated("Invalid token"));
+    }
+
+    None
+}
+
+// Client a call came from, its IP address, for rate limiting and the audit log
+fn client<T>(request: &Request<T>) -> String {
+    request
+        .remote_addr()
+        .map_or_else(|| "unknown".to_string(), |addr| addr.ip().to_string())
+}
+
+// Read the image of a request, downloading it from its URL. Blocks, so call it through `blocking`
+fn image(source: Option<ImageSource>) -> Result<Image> {
+    let image = match source.and_then(|image| image.source) {
+        Some(Source::Url(url)) => {
+            Image::from_url(&url).map_err(|err| GenerationRefused::InvalidImage(err.to_string()))?
+        }
+        Some(Source::Data(data)) => Image::from_base64(general_purpose::STANDARD.encode(data)),
+        None => return Err(GenerationRefused::InvalidImage("no url or data given".to_string()).into()),
+    };
+
+    Ok(image)
+}
+
+// Convert a vision report to its message
+fn analysis(report: VisionReport) -> AnalyzeImageResponse {
+    let keywords = report
+        .keywords
+        .into_iter()
+        .map(|keyword| Keyword {
+            label: keyword.label,
+            score: keyword.score,
+            category: match keyword.category {
+                Category::Subject => proto::Category::Subject,
+                Category::Style => proto::Category::Style,
+                Category::Color => proto::Category::Color,
+                Category::Mood => proto::Category::Mood,
+            } as i32,
+        })
+        .collect();
+
+    AnalyzeImageResponse {
+        keywords,
+        mood: report.mood.as_str().to_string(),
+        text: report.text,
+        provider: report.provider,
+    }
+}
+
+// Status for a failed call, with the same classes as the REST API
+fn status(err: anyhow::Error) -> Status {
+    let message = format!("{:#}", err);
+    match err.downcast_ref::<GenerationRefused>() {
+        Some(GenerationRefused::InvalidImage(_)) => Status::invalid_argument(message),
+        Some(GenerationRefused::Declined(_)) => Status::failed_precondition(message),
+        Some(GenerationRefused::OverBudget(_) | GenerationRefused::RateLimited(_)) => {
+            Status::resource_exhausted(message)
+        }
+        None if err.chain().any(|cause| cause.is::<BreakerOpen>()) => Status::unavailable(message),
+        None => {
+            error!(error = ?err, "Failed to generate");
+            Status::internal(message)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tonic::Code;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn missing_image_is_an_invalid_argument() {
+        let err = blocking(|| image(None)).await.unwrap_err();
+
+        assert_eq!(status(err).code(), Code::InvalidArgument);
+    }
+
+    #[tokio::test]
+    async fn uploaded_image_is_kept_as_base64() {
+        let source = ImageSource {
+            source: Some(Source::Data(b"png".to_vec())),
+        };
+
+        let image = blocking(move || image(Some(source))).await.unwrap();
+
+        assert_eq!(image.base64, general_purpose::STANDARD.encode(b"png"));
+    }
+
+    #[test]
+    fn refusals_and_open_breakers_map_to_their_status() {
+        let declined = GenerationRefused::Declined("shows a real face".to_string());
+        let breaker = BreakerOpen {
+            name: "vision".to_string(),
+            retry_in: Duration::from_secs(60),
+        };
+
+        assert_eq!(status(declined.into()).code(), Code::FailedPrecondition);
+        assert_eq!(
+            status(GenerationRefused::OverBudget("daily").into()).code(),
+            Code::ResourceExhausted
+        );
+        assert_eq!(status(breaker.into()).code(), Code::Unavailable);
+        assert_eq!(
+            status(GenerationRefused::RateLimited(Duration::from_secs(60)).into()).code(),
+            Code::ResourceExhausted
+        );
+    }
+
+    // Call carrying the given authorization metadata
+    fn call(authorization: Option<&str>) -> Request<()> {
+        let mut request = Request::new(());
+        if let Some(value) = authorization {
+            request.metadata_mut().insert("authorization", value.parse().unwrap());
+        }
+        request
+    }
+
+    #[test]
+    fn calls_need_the_api_token() {
+        let code = |token, authorization| unauthorized(token, &call(authorization)).unwrap().code();
+
+        assert_eq!(code(None, Some("Bearer anything")), Code::PermissionDenied);
+        assert_eq!(code(Some("secret"), None), Code::Unauthenticated);
+        assert_eq!(code(Some("secret"), Some("Bearer wrong")), Code::Unauthenticated);
+        assert_eq!(code(Some("secret"), Some("secret")), Code::Unauthenticated);
+        assert!(unauthorized(Some("secret"), &call(Some("Bearer secret"))).is_none());
+    }
+}
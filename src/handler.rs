@@ -1,165 +1,1530 @@
-use std::{env, process};
+use std::{
+    future::Future,
+    path::Path,
+    process,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
+// Import audit log of generated content
+use crate::audit::{AuditEvent, AuditLog};
+// Import settings
+use crate::config;
+// Import circuit breakers for external providers
+use crate::breaker::CircuitBreaker;
+// Import spend tracking and budgets
+use crate::cost::{Budget, BudgetExceeded, CostTracker};
+// Import pipeline progress events
+use crate::events::{events, PipelineEvent};
+// Import avatar embedding types
+use crate::embedding::{cosine_similarity, Embedder, EmbeddingStore, UserEmbedding};
+// Import HTTP client for budget alerts
+use crate::http_client::HttpClient;
+// Import metrics for stage instrumentation
+use crate::metrics::metrics;
+// Import the rate-limited OpenAI client
+use crate::openai_client::{estimate_tokens, prompt_tokens, OpenAiClient};
+// Import user preferences
+use crate::prefs::{PreferenceStore, UserPrefs};
 // Import required modules and types for image processing
 use crate::image::{Image, ImageGenerator, ImageRequest};
 use crate::image_gen::ImageGen;
-use crate::storage::Storage;
+use crate::ledger::{Ledger, MentionRecord};
+// Import the queue of accepted mentions
+use crate::queue::{MentionQueue, RequeuePolicy, Requeued};
+// Import seasonal theming
+use crate::theme::{Theme, ThemeCalendar};
 // Import Twitter related types
-use crate::twitter::{ExtractedTweet, Twitter};
-// Import utility function for custom image paths
-use crate::utils::custom_image_path;
-// Import Google Vision related types
-use crate::vision::{GoogleVision, GoogleVisionRequest};
+use crate::twitter::{is_default_avatar, ExtractedTweet, Twitter};
+// Import utility functions for custom image paths and rate limits
+use crate::utils::{
+    blocking,
+    custom_image_path,
+    rate_limit_from_env,
+    retry,
+    sanitize_keyword,
+    ttl_from_env,
+    FileRateStore,
+    RateDecision,
+    RateLimiter,
+    RateStrategy,
+    RetryPolicy,
+    StageTimeouts,
+    DEFAULT_MENTION_TTL_DAYS,
+    DEFAULT_USER_RATE_LIMIT,
+    DEFAULT_VISION_TTL_DAYS,
+};
+// Import vision related types
+use crate::vision::{
+    create_vision_service,
+    max_edge_from_env,
+    max_results_from_env,
+    Category,
+    ContextSource,
+    FacePolicy,
+    Keyword,
+    Mood,
+    SafetyVerdict,
+    TextPolicy,
+    VisionReport,
+    VisionRequest,
+    VisionService,
+};
+// Import Twitter profile type
+use agent_twitter_client::models::Profile;
+// Import date handling
+use chrono::{NaiveDate, NaiveTime, Utc};
 // Import error handling and other utilities
-use anyhow::Result;
-use log::error;
-use rig::completion::Prompt;
-use rig::providers::openai;
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+// Import serialization traits for checkpoints
+use serde::{Deserialize, Serialize};
+// Import hashing for the prompt an image was drawn from
+use sha2::{Digest, Sha256};
+// Import structured logging
+use tracing::{error, info, info_span, warn, Instrument, Span};
+
+// Confidence at which a label counts as a main subject of the avatar
+const MAIN_KEYWORD_SCORE: f64 = 0.85;
+// Reply sent with a generated image
+const IMAGE_REPLY: &str = "Check out this image!";
+// Reply sent with a mystery cat drawn for a default avatar
+const MYSTERY_CAT_REPLY: &str = "No avatar yet? Here's a mystery cat to keep you company!";
+// Labels used instead of vision labels for default avatars
+const MYSTERY_CAT_KEYWORDS: &[&str] = &["mysterious cat", "soft shadows", "question mark shaped tail", "curious glowing eyes"];
+// Entropy in bits below which an avatar is treated as a placeholder
+const DEFAULT_AVATAR_ENTROPY: f64 = 2.0;
+// Reply sent when an avatar is flagged as unsafe
+const UNSAFE_REPLY: &str = "I couldn't draw a cat from this avatar, but thanks for asking!";
+// Reply sent when an avatar showing a real person is declined
+const FACE_REJECT_REPLY: &str = "I only draw cats from avatars without real people in them. Sorry!";
+// Platform name used in user preference keys
+const PLATFORM: &str = "twitter";
+// Reply prefix when the user's avatar changed since their previous request
+const NEW_AVATAR_REPLY: &str = "Love the new avatar!";
+// Reply sent when a mention failed every attempt
+const FAILURE_REPLY: &str = "Sorry, I couldn't draw your cat this time. Please try again later!";
+// Similarity above which an avatar counts as unchanged since the user's previous request
+const DUPLICATE_AVATAR_SIMILARITY: f32 = 0.97;
+// Longest image description GPT-4 may answer with, in tokens
+const PROMPT_RESPONSE_TOKENS: u32 = 500;
+// Most search pages a backfill reads, in case the search keeps returning cursors
+const MAX_BACKFILL_PAGES: usize = 100;
+// Rate limit key of the daily reply cap
+const DAILY_REPLIES_KEY: &str = "replies";
+// Platform of generation API clients in rate limit keys
+const API_PLATFORM: &str = "api";
+
+// Persistent state the handler reads and updates
+pub struct Stores {
+    // Ledger of processed mentions
+    pub ledger: Ledger,
+    // Mentions accepted but not handled yet
+    pub queue: MentionQueue,
+    // Latest avatar embedding of each user
+    pub embeddings: EmbeddingStore,
+    // Preferences users set through mention commands
+    pub prefs: PreferenceStore,
+    // Per-user rate limit counts
+    pub rate_limits: FileRateStore,
+    // Estimated spend across providers
+    pub costs: CostTracker,
+    // Audit log of generated content
+    pub audit_log: AuditLog,
+}
+
+// Image drawn for a generation request outside of Twitter
+pub struct Generation {
+    // Image description GPT-4 wrote for DALL-E
+    pub description: String,
+    // Generated image
+    pub image: Image,
+    // Path the image was saved to
+    pub image_path: String,
+}
+
+// Reason a generation request was refused without drawing anything
+#[derive(Debug, thiserror::Error)]
+pub enum GenerationRefused {
+    // The image couldn't be read
+    #[error("Invalid image: {0}")]
+    InvalidImage(String),
+    // The image failed moderation
+    #[error("Image declined: {0}")]
+    Declined(String),
+    // A spending limit was reached
+    #[error("Over the {0} budget")]
+    OverBudget(&'static str),
+    // The client made too many requests, it may try again after this long
+    #[error("Over the rate limit, try again in {} seconds", .0.as_secs())]
+    RateLimited(Duration),
+}
+
+// Replay refused because the mention was already answered
+#[derive(Debug, thiserror::Error)]
+#[error("Tweet {0} was already answered, force the replay to answer it again")]
+pub struct AlreadyAnswered(pub String);
+
+// Paid-for work on a mention kept in the ledger, so an attempt after a crash or failure resumes from the last
+// finished stage. Only valid for the avatar it was made from
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Checkpoint {
+    // Hash of the normalized avatar
+    pub avatar_hash: String,
+    // Vision report of the avatar and when it was made
+    pub report: Option<VisionReport>,
+    pub analyzed_at: Option<i64>,
+    // Image description GPT-4 wrote for DALL-E
+    pub translated: Option<String>,
+    // Path of the generated image
+    pub image_path: Option<String>,
+}
+
+// Slot of the daily reply cap taken for a new mention. It is given back when dropped, so a mention that ends
+// without a reply, e.g. over the user's limit or without an avatar, doesn't count against the cap
+struct ReplySlot<'a> {
+    // Limiter the slot was taken from
+    limiter: &'a RateLimiter,
+    // Whether the slot stays counted
+    kept: bool,
+}
+
+// How a mention ended once handled
+#[derive(Debug, PartialEq)]
+enum Handled {
+    // Replied with the image generated for it, saved at the path
+    Drawn(String),
+    // Replied without drawing, e.g. to decline an unsafe avatar
+    Declined,
+    // Ended without a reply, e.g. when the avatar can't be found
+    Skipped,
+}
 
 // Main handler struct for processing tweets
 pub struct Handler {
     translate_prompt: String,
-    // Storage for persisting processed tweet IDs
-    storage: Storage,
-    // Twitter client instance
-    twitter: Twitter,
+    // Calendar of seasonal themes applied to the image prompt
+    themes: ThemeCalendar,
+    // Policy for avatars showing a real human face
+    face_policy: FacePolicy,
+    // Policy for text found in the avatar
+    text_policy: TextPolicy,
+    // Vision provider used to analyze avatars, shared with the blocking calls to it
+    vision: Arc<dyn VisionService>,
+    // Maximum number of labels requested from the vision provider
+    vision_max_results: u8,
+    // Longest image edge sent to the vision provider
+    vision_max_edge: u32,
+    // Extra images analyzed together with the avatar
+    context_sources: Vec<ContextSource>,
+    // Ledger of processed mentions
+    ledger: Ledger,
+    // Mentions accepted but not handled yet
+    queue: MentionQueue,
+    // When failed mentions are tried again
+    requeue_policy: RequeuePolicy,
+    // Storage for the latest avatar embedding of each user
+    embeddings: Mutex<EmbeddingStore>,
+    // Storage for the preferences users set through mention commands
+    prefs: Mutex<PreferenceStore>,
+    // OpenAI client shared by all mentions, keeping calls under the account limits
+    openai: OpenAiClient,
+    // Limit on mentions handled per user
+    user_limiter: RateLimiter,
+    // Limit on mentions handled per day by the whole bot
+    reply_limiter: RateLimiter,
+    // Retries of failed OpenAI, Twitter and download calls
+    retry_policy: RetryPolicy,
+    // Time limits of the stages of handling a mention
+    stage_timeouts: StageTimeouts,
+    // Breakers failing fast while a provider is down
+    openai_breaker: CircuitBreaker,
+    twitter_breaker: CircuitBreaker,
+    vision_breaker: CircuitBreaker,
+    // Estimated spend across providers
+    costs: CostTracker,
+    // Spending limits, new mentions wait while one is reached
+    budget: Budget,
+    // Append-only record of moderation verdicts, prompts, images and replies
+    audit_log: AuditLog,
+    // How long handled mentions and avatar analyses are kept, None for forever
+    mention_ttl: Option<Duration>,
+    vision_ttl: Option<Duration>,
+    // Twitter client instance, None for handlers only serving the generation APIs
+    twitter: Option<Twitter>,
     // Maximum number of tweets to process
     max_tweets: i32,
 }
 
 impl Handler {
     // Initialize a new Handler instance with storage
-    pub async fn new(storage: Storage) -> Result<Self> {
-        let translate_prompt = env::var("TRANSLATE_PROMPT").unwrap_or_else(|err| {
+    pub async fn new(stores: Stores) -> Result<Self> {
+        let vision = create_vision_service()?;
+        Self::with_vision(stores, vision).await
+    }
+
+    // Initialize a new Handler instance with a custom vision provider, e.g. a stub for tests
+    pub async fn with_vision(stores: Stores, vision: Box<dyn VisionService>) -> Result<Self> {
+        let twitter = Twitter::new().await?;
+        Self::build(stores, vision, Some(twitter))
+    }
+
+    // Initialize a Handler that only serves the generation APIs, without logging in to Twitter. Anything to do with
+    // mentions fails on it
+    pub fn without_twitter(stores: Stores) -> Result<Self> {
+        Self::build(stores, create_vision_service()?, None)
+    }
+
+    // Initialize a Handler from its stores, vision provider and Twitter client
+    fn build(stores: Stores, vision: Box<dyn VisionService>, twitter: Option<Twitter>) -> Result<Self> {
+        let translate_prompt = config::var("TRANSLATE_PROMPT").unwrap_or_else(|err| {
             error!("Missing TRANSLATE_PROMPT {}", err);
             process::exit(1);
         });
+        // Per-user and daily reply counts share the file of rate limits
+        let rate_limits = Arc::new(stores.rate_limits);
 
         Ok(Self {
             translate_prompt,
-            storage,
-            twitter: Twitter::new().await?,
+            themes: ThemeCalendar::load()?,
+            face_policy: FacePolicy::from_env()?,
+            text_policy: TextPolicy::from_env()?,
+            vision: Arc::from(vision),
+            vision_max_results: max_results_from_env()?,
+            vision_max_edge: max_edge_from_env()?,
+            context_sources: ContextSource::from_env()?,
+            ledger: stores.ledger,
+            queue: stores.queue,
+            requeue_policy: RequeuePolicy::from_env()?,
+            embeddings: Mutex::new(stores.embeddings),
+            prefs: Mutex::new(stores.prefs),
+            openai: OpenAiClient::from_env()?,
+            // Per-user counts are kept on disk so a restart doesn't reset them
+            user_limiter: RateLimiter::new(
+                RateStrategy::per_day(rate_limit_from_env("USER_RATE_LIMIT", DEFAULT_USER_RATE_LIMIT)?),
+                Box::new(rate_limits.clone()),
+            ),
+            reply_limiter: RateLimiter::new(
+                RateStrategy::per_day(rate_limit_from_env("DAILY_REPLY_CAP", 0)?),
+                Box::new(rate_limits),
+            ),
+            retry_policy: RetryPolicy::default(),
+            stage_timeouts: StageTimeouts::from_env()?,
+            openai_breaker: CircuitBreaker::new("openai"),
+            twitter_breaker: CircuitBreaker::new("twitter"),
+            vision_breaker: CircuitBreaker::new("vision"),
+            costs: stores.costs,
+            budget: Budget::from_env()?,
+            audit_log: stores.audit_log,
+            mention_ttl: ttl_from_env("MENTION_TTL_DAYS", DEFAULT_MENTION_TTL_DAYS)?,
+            vision_ttl: ttl_from_env("VISION_TTL_DAYS", DEFAULT_VISION_TTL_DAYS)?,
+            twitter,
             max_tweets: 20,
         })
     }
 
-    // Process new tweets mentioning the bot
-    pub async fn process_tweets(&mut self) -> Result<()> {
+    // Re-read changed settings. Invalid values keep the previous setting, and settings baked into the vision
+    // provider only take effect after a restart
+    pub fn reload(&mut self, changed: &[&str]) {
+        for name in changed {
+            let result = match *name {
+                "TRANSLATE_PROMPT" => config::var(name)
+                    .map(|prompt| self.translate_prompt = prompt)
+                    .map_err(|err| anyhow!(err)),
+                "THEME_CALENDAR" => ThemeCalendar::load().map(|themes| self.themes = themes),
+                "VISION_MAX_RESULTS" => max_results_from_env().map(|max| self.vision_max_results = max),
+                "VISION_MAX_EDGE" => max_edge_from_env().map(|max| self.vision_max_edge = max),
+                "VISION_CONTEXT" => ContextSource::from_env().map(|sources| self.context_sources = sources),
+                "VISION_OCR" => TextPolicy::from_env().map(|policy| self.text_policy = policy),
+                "FACE_POLICY" => FacePolicy::from_env().map(|policy| self.face_policy = policy),
+                "OPENAI_RATE_LIMIT" | "OPENAI_TOKEN_LIMIT" => self.openai.reload(),
+                "USER_RATE_LIMIT" => rate_limit_from_env(name, DEFAULT_USER_RATE_LIMIT)
+                    .map(|limit| self.user_limiter.set_strategy(RateStrategy::per_day(limit))),
+                "DAILY_REPLY_CAP" => rate_limit_from_env(name, 0)
+                    .map(|limit| self.reply_limiter.set_strategy(RateStrategy::per_day(limit))),
+                "DAILY_BUDGET_USD" | "MONTHLY_BUDGET_USD" => Budget::from_env().map(|budget| self.budget = budget),
+                "STAGE_TIMEOUTS" => StageTimeouts::from_env().map(|timeouts| self.stage_timeouts = timeouts),
+                "MENTION_MAX_ATTEMPTS" => RequeuePolicy::from_env().map(|policy| self.requeue_policy = policy),
+                "MENTION_TTL_DAYS" => ttl_from_env(name, DEFAULT_MENTION_TTL_DAYS).map(|ttl| self.mention_ttl = ttl),
+                "VISION_TTL_DAYS" => ttl_from_env(name, DEFAULT_VISION_TTL_DAYS).map(|ttl| self.vision_ttl = ttl),
+                _ => {
+                    warn!("{} changed, restart to apply it", name);
+                    continue;
+                }
+            };
+
+            match result {
+                Ok(()) => info!("Reloaded {}", name),
+                Err(err) => error!("Keeping the previous {}: {:?}", name, err),
+            }
+        }
+    }
+
+    // Queue new tweets mentioning the bot, returning how many were added
+    pub async fn poll_mentions(&self) -> Result<usize> {
         // Search for tweets mentioning the bot
-        let tweets = self
-            .twitter
-            .search_tweets(&format!("@{}", self.twitter.username), self.max_tweets, None, None)
-            .await?;
+        let twitter = self.twitter()?;
+        let query = format!("@{}", twitter.username);
+        let tweets = retry("twitter.search", &self.retry_policy, || {
+            self.twitter_breaker
+                .call(|| twitter.search_tweets(&query, self.max_tweets, None, None))
+        })
+        .await?;
+
+        self.queue_mentions(&tweets)
+    }
+
+    // Queue mentions of the bot since the start of a day (UTC), paging back through the search. They are queued
+    // oldest first so they are answered in order, returning how many were queued
+    pub async fn backfill_mentions(&self, since: NaiveDate) -> Result<usize> {
+        let since_timestamp = since.and_time(NaiveTime::MIN).and_utc().timestamp();
+        let twitter = self.twitter()?;
+        let query = format!("@{} since:{}", twitter.username, since.format("%Y-%m-%d"));
+
+        let mut mentions = Vec::new();
+        let mut cursor = None;
+        for _ in 0..MAX_BACKFILL_PAGES {
+            let search = || {
+                self.twitter_breaker
+                    .call(|| twitter.search_tweets_page(&query, self.max_tweets, None, cursor.clone()))
+            };
+            let (tweets, next) = retry("twitter.search", &self.retry_policy, search).await?;
+            if tweets.is_empty() {
+                break;
+            }
+            info!(found = tweets.len(), "Read a page of past mentions");
+            mentions.extend(
+                tweets
+                    .into_iter()
+                    .filter(|tweet| tweet.timestamp.is_some_and(|timestamp| timestamp >= since_timestamp)),
+            );
+            match next {
+                Some(next) if cursor.as_ref() != Some(&next) => cursor = Some(next),
+                _ => break,
+            }
+        }
+
+        mentions.sort_by_key(|tweet| tweet.timestamp);
+        self.queue_mentions(&mentions)
+    }
 
-        // Process each tweet
-        for tweet in &tweets {
+    // Queue each mention not handled yet, returning how many were queued
+    pub fn queue_mentions(&self, tweets: &[ExtractedTweet]) -> Result<usize> {
+        // Forget mentions handled long enough ago that sources no longer deliver them
+        if let Some(ttl) = self.mention_ttl {
+            let cutoff = Utc::now().timestamp() - ttl.as_secs() as i64;
+            let removed = self.ledger.prune_completed(cutoff)?;
+            if removed > 0 {
+                info!(removed, "Pruned handled mentions");
+            }
+        }
+
+        let mut queued = 0;
+        for tweet in tweets {
             // Extract tweet ID or skip if none
             let id = match &tweet.id {
-                Some(id) => id.clone(),
+                Some(id) => id,
                 None => continue,
             };
 
-            // Skip if tweet was already processed
-            if self.storage.contains(id.clone()) {
-                println!("Tweet {} already processed. Skipping", id.clone());
+            // Skip if tweet was already processed, failed ones are retried from the queue until given up on
+            if self.ledger.get(id)?.is_some() || self.queue.is_dead(id)? {
+                info!(request_id = %id, "Tweet already processed. Skipping");
                 continue;
             }
 
-            // Handle tweet and track processed status
-            if let Err(e) = self.handle_tweet(tweet).await {
-                println!("Error processing tweet {}: {:?}", id, e);
-            } else {
-                // Store processed tweet ID and save to file
-                self.storage.insert(id);
-                self.storage.save_to_file()?;
+            if self.queue.push(id, tweet)? {
+                queued += 1;
+            }
+        }
+        metrics().increment("queue.enqueued", queued as u64);
+
+        Ok(queued)
+    }
+
+    // Queue a mention again by tweet ID with fresh attempts, fetching it from Twitter. Mentions already answered are
+    // refused unless forced, since the user would get a second reply
+    pub async fn replay(&self, tweet_id: &str, force: bool) -> Result<ExtractedTweet> {
+        if !force && self.ledger.is_completed(tweet_id)? {
+            return Err(AlreadyAnswered(tweet_id.to_string()).into());
+        }
+
+        let twitter = self.twitter()?;
+        let tweet = retry("twitter.get_tweet", &self.retry_policy, || {
+            self.twitter_breaker.call(|| twitter.get_tweet(tweet_id))
+        })
+        .await?;
+        // Reopen the mention so it isn't dropped as handled, nor counted against the user's limit again
+        if self.ledger.get(tweet_id)?.is_some() {
+            self.ledger.mark_pending(tweet_id, tweet.username.as_deref())?;
+        }
+        self.queue.requeue(tweet_id, &tweet)?;
+        metrics().increment("mentions.replayed", 1);
+        info!(request_id = %tweet_id, force, "Queued mention for replay");
+
+        Ok(tweet)
+    }
+
+    // Ledger entry of a mention, None if it was never handled
+    pub fn mention_record(&self, tweet_id: &str) -> Result<Option<MentionRecord>> {
+        self.ledger.get(tweet_id)
+    }
+
+    // Queued mentions that are due, oldest first
+    pub fn due_mentions(&self) -> Result<Vec<ExtractedTweet>> {
+        self.queue.due()
+    }
+
+    // Handle one queued mention. It leaves the queue once handled or given up on
+    pub async fn process_mention(&self, tweet: &ExtractedTweet) -> Result<()> {
+        let id = match &tweet.id {
+            Some(id) => id.clone(),
+            None => return Ok(()),
+        };
+
+        // Drop mentions handled since they were queued
+        if self.ledger.is_completed(&id)? {
+            return self.queue.remove(&id);
+        }
+
+        // Put off mentions while over budget until the budget resets
+        if let Some(exceeded) = self.costs.exceeded(&self.budget) {
+            self.alert_budget(&exceeded)?;
+            let wait = exceeded.resets_in();
+            metrics().increment("mentions.over_budget", 1);
+            info!(request_id = %id, defer_s = wait.as_secs(), "Over budget. Deferring mention");
+            return self.queue.defer(&id, wait);
+        }
+
+        // Every record logged while handling the mention carries its tweet ID and user
+        let span = mention_span(tweet);
+
+        // Put off new mentions past the bot's daily cap until it resets, retries of failed ones were already counted
+        let mut reply_slot = None;
+        if self.ledger.get(&id)?.is_none() {
+            match self.reply_limiter.try_acquire(DAILY_REPLIES_KEY)? {
+                RateDecision::Allowed => reply_slot = Some(ReplySlot::new(&self.reply_limiter)),
+                RateDecision::Limited(wait) => {
+                    info!(parent: &span, defer_s = wait.as_secs(), "Over the daily reply cap. Deferring mention");
+                    metrics().increment("mentions.deferred", 1);
+                    return self.queue.defer(&id, wait);
+                }
+            }
+        }
+
+        // Count new mentions against the user's limit, retries of failed ones were already counted
+        if let Some(user_id) = &tweet.user_id {
+            if self.ledger.get(&id)?.is_none() {
+                let key = format!("{}:{}", PLATFORM, user_id);
+                if let RateDecision::Limited(wait) = self.user_limiter.try_acquire(&key)? {
+                    info!(parent: &span, retry_after_s = wait.as_secs(), "User over the rate limit. Skipping");
+                    metrics().increment("mentions.rate_limited", 1);
+                    self.ledger.mark_completed(&id, None)?;
+                    return self.queue.remove(&id);
+                }
+            }
+        }
+
+        // Handle tweet and track processed status, failed tweets are scheduled again
+        self.ledger.mark_pending(&id, tweet.username.as_deref())?;
+        self.publish(
+            tweet,
+            PipelineEvent::MentionReceived {
+                user: tweet.username.clone(),
+            },
+        );
+        let started = Instant::now();
+        let result = self.handle_tweet(tweet).instrument(span.clone()).await;
+        let duration = started.elapsed();
+        metrics().record_duration("mention.latency", duration);
+        let duration_ms = duration.as_millis() as u64;
+        if let Some(slot) = &mut reply_slot {
+            slot.settle(&result);
+        }
+        match result {
+            Ok(handled) => {
+                info!(parent: &span, duration_ms, "Tweet processed");
+                metrics().increment("mentions.completed", 1);
+                self.ledger.mark_completed(&id, handled.image_path())?;
+                self.queue.remove(&id)
+            }
+            Err(e) => {
+                error!(parent: &span, duration_ms, error = ?e, "Error processing tweet");
+                self.mention_failed(tweet, &format!("{:?}", e)).await
+            }
+        }
+    }
+
+    // Record a failed attempt at a mention, scheduling it again or giving up on it
+    pub async fn mention_failed(&self, tweet: &ExtractedTweet, error: &str) -> Result<()> {
+        let id = match &tweet.id {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        let span = mention_span(tweet);
+
+        metrics().increment("mentions.failed", 1);
+        self.ledger.mark_failed(id, error)?;
+        let requeued = self.queue.retry_later(id, error, &self.requeue_policy)?;
+        self.publish(
+            tweet,
+            PipelineEvent::Failed {
+                error: error.to_string(),
+                retrying: requeued != Requeued::Dead,
+            },
+        );
+        match requeued {
+            Requeued::Retrying { attempt, delay } => {
+                info!(parent: &span, attempt, retry_in_s = delay.as_secs(), "Retrying tweet later");
+            }
+            Requeued::Dead => {
+                warn!(parent: &span, "Giving up on tweet. Moved to the dead letters");
+                metrics().increment("mentions.dead", 1);
+                // Let the user know instead of leaving them waiting
+                if let Err(err) = self.send_reply(tweet, FAILURE_REPLY).instrument(span.clone()).await {
+                    warn!(parent: &span, error = ?err, "Failed to tell the user");
+                }
             }
         }
 
         Ok(())
     }
 
-    // Handle individual tweet processing
-    async fn handle_tweet(&self, tweet: &ExtractedTweet) -> Result<()> {
+    // Draw a cat from an image outside of Twitter, e.g. for the APIs. It goes through the same moderation and budget
+    // as mentions, counts against the client's USER_RATE_LIMIT and records its prompt and image in the audit log.
+    // There is no reply, so the daily reply cap doesn't apply
+    pub async fn generate(
+        &self,
+        client: &str,
+        image: Image,
+        style: Option<&str>,
+        language: Option<&str>,
+    ) -> Result<Generation> {
+        self.admit(client)?;
+        let report = self.analyze(image).await?;
+        let description = self.describe(client, &report, style, language).await?;
+        let (image, image_path) = self.draw(client, &description).await?;
+
+        Ok(Generation {
+            description,
+            image,
+            image_path,
+        })
+    }
+
+    // Count a request of an API client, e.g. its IP address, against USER_RATE_LIMIT like a user's mention
+    pub fn admit(&self, client: &str) -> Result<()> {
+        let key = format!("{}:{}", API_PLATFORM, client);
+        match self.user_limiter.try_acquire(&key)? {
+            RateDecision::Allowed => Ok(()),
+            RateDecision::Limited(wait) => {
+                metrics().increment("api.rate_limited", 1);
+                Err(GenerationRefused::RateLimited(wait).into())
+            }
+        }
+    }
+
+    // Analyze an image from outside of Twitter, refusing it like an avatar that fails moderation
+    pub async fn analyze(&self, image: Image) -> Result<VisionReport> {
+        self.check_budget()?;
+        let image = blocking(move || image.normalized())
+            .await
+            .map_err(|err| GenerationRefused::InvalidImage(err.to_string()))?;
+        let report = self.stage("vision", self.analyze_image(image, &[])).await?;
+        let report = match (report.safety(), self.face_policy) {
+            (SafetyVerdict::Unsafe(categories), _) => {
+                let reason = format!("flagged as unsafe ({})", categories.join(", "));
+                return Err(GenerationRefused::Declined(reason).into());
+            }
+            (SafetyVerdict::Faces(_), FacePolicy::Reject) => {
+                return Err(GenerationRefused::Declined("shows a real face".to_string()).into());
+            }
+            (SafetyVerdict::Faces(_), FacePolicy::Anonymize) => report.anonymized(),
+            _ => report,
+        };
+
+        Ok(match self.text_policy {
+            TextPolicy::Exclude => report.without_text(),
+            _ => report,
+        })
+    }
+
+    // Write the image description for DALL-E from an analyzed image, in the given art style and language, recording
+    // it for the API client in the audit log
+    pub async fn describe(
+        &self,
+        client: &str,
+        report: &VisionReport,
+        style: Option<&str>,
+        language: Option<&str>,
+    ) -> Result<String> {
+        self.check_budget()?;
+        // Styles are cleaned like the ones users set through mention commands
+        let prefs = UserPrefs {
+            style: style.and_then(sanitize_keyword),
+            ..UserPrefs::default()
+        };
+        let mut prompt = self.translation_prompt(report, "", &prefs)?;
+        // Languages are cleaned like styles, they end up in the prompt too
+        if let Some(language) = language.and_then(sanitize_keyword) {
+            prompt = format!("{} Write the description in {}.", prompt, language);
+        }
+        let translate = || {
+            let tokens = prompt_tokens(&prompt, PROMPT_RESPONSE_TOKENS);
+            self.call_openai("openai.prompt", tokens, || self.translate_description(&prompt))
+        };
+
+        let description = self
+            .stage("prompt", retry("openai.prompt", &self.retry_policy, translate))
+            .await?;
+        self.audit_client(
+            client,
+            AuditEvent::Prompt {
+                prompt,
+                response: description.clone(),
+            },
+        )?;
+
+        Ok(description)
+    }
+
+    // Draw a cat from an image description, returning it with the path it was saved to and recording it for the
+    // API client in the audit log
+    pub async fn draw(&self, client: &str, description: &str) -> Result<(Image, String)> {
+        self.check_budget()?;
+        let generate = || {
+            let description = description.to_string();
+            self.call_openai("openai.image", 0, || blocking(move || generate_image(&description)))
+        };
+
+        let (image, image_path) = self
+            .stage("image", retry("openai.image", &self.retry_policy, generate))
+            .await?;
+        self.audit_client(
+            client,
+            AuditEvent::Image {
+                path: image_path.clone(),
+                reused: false,
+            },
+        )?;
+
+        Ok((image, image_path))
+    }
+
+    // Refuse requests from outside of Twitter while a spending limit is reached
+    fn check_budget(&self) -> Result<()> {
+        match self.costs.exceeded(&self.budget) {
+            Some(exceeded) => Err(GenerationRefused::OverBudget(exceeded.period).into()),
+            None => Ok(()),
+        }
+    }
+
+    // Handle individual tweet processing, returning the path of the image sent if any
+    async fn handle_tweet(&self, tweet: &ExtractedTweet) -> Result<Handled> {
         // Get user profile information
+        let twitter = self.twitter()?;
+        let username = author(tweet)?;
+        let get_profile = || self.twitter_breaker.call(|| twitter.get_profile(username));
         let profile = self
-            .twitter
-            .get_profile(tweet.username.clone().unwrap().as_str())
+            .stage("profile", retry("twitter.profile", &self.retry_policy, get_profile))
             .await?;
 
         // Skip if tweet is from the bot itself
-        if profile.username == self.twitter.username {
-            println!("Username is self. Skipping");
-            return Ok(());
+        if profile.username == twitter.username {
+            info!("Username is self. Skipping");
+            return Ok(Handled::Skipped);
         }
 
+        // Update the user's preferences from commands such as "style: watercolor" in the mention
+        let text = tweet.text.clone().unwrap_or_default();
+        let mut prefs = UserPrefs::default();
+        let mut prefs_changed = false;
+        if let Some(user_id) = &tweet.user_id {
+            let mut store = self.prefs.lock().unwrap();
+            prefs = store.get(PLATFORM, user_id);
+            prefs_changed = prefs.apply_commands(&text);
+            if prefs_changed {
+                info!(style = ?prefs.style, gallery = prefs.gallery, "Updated preferences");
+                store.set(PLATFORM, user_id, prefs.clone());
+                store.save_to_file()?;
+            }
+        }
+
+        // Extra images fused with the avatar for a richer description
+        let context_urls = self.context_urls(tweet, &profile);
+
         // Get user's avatar URL
-        let avatar_url = match self.twitter.get_avatar(profile).await? {
+        let avatar_url = match twitter.get_avatar(profile).await? {
             Some(url) => url,
             None => {
-                println!("Avatar not found. Skipping");
-                return Ok(());
+                info!("Avatar not found. Skipping");
+                return Ok(Handled::Skipped);
             }
         };
 
         // Process image and generate response
-        let image = Image::from_url(&avatar_url)?;
-        let description = self.generate_description(image)?;
-        let translated_desc = self.translate_description(&description).await?;
-        let image = self.generate_image(&translated_desc)?;
+        let download = || {
+            let avatar_url = avatar_url.clone();
+            blocking(move || Image::from_url(&avatar_url))
+        };
+        // Decoding and re-encoding the avatar is CPU bound like the download
+        let image = self
+            .stage("avatar", async {
+                let image = retry("download.avatar", &self.retry_policy, download).await?;
+                blocking(move || image.normalized()).await
+            })
+            .await?;
+        let avatar_hash = image.sha256();
+
+        // Compare with the avatar of the user's previous request
+        let previous = tweet
+            .user_id
+            .as_deref()
+            .and_then(|id| self.embeddings.lock().unwrap().get(id).cloned());
+        let previous_hash = previous.as_ref().and_then(|previous| previous.avatar_hash.as_deref());
+        let avatar_changed = previous_hash.is_some_and(|hash| hash != avatar_hash);
+        let now = Utc::now().timestamp();
+        let cached = previous
+            .as_ref()
+            .filter(|_| previous_hash == Some(avatar_hash.as_str()) && context_urls.is_empty())
+            .filter(|previous| self.is_fresh(previous.analyzed_at, now))
+            .and_then(|previous| previous.report.clone().zip(previous.analyzed_at));
+        let mut analyzed_at = now;
+        // Resume from an earlier attempt at this mention unless the avatar changed since
+        let mut checkpoint = self
+            .checkpoint(tweet)?
+            .filter(|checkpoint| checkpoint.avatar_hash == avatar_hash)
+            .unwrap_or_else(|| Checkpoint {
+                avatar_hash: avatar_hash.clone(),
+                ..Checkpoint::default()
+            });
+
+        // Default avatars carry nothing to analyze, so draw a mystery cat instead
+        let default_avatar = is_default_avatar(&avatar_url) || {
+            let image = image.clone();
+            blocking(move || image.entropy()).await? < DEFAULT_AVATAR_ENTROPY
+        };
+        let (report, message) = if default_avatar {
+            info!("Default avatar detected. Drawing a mystery cat");
+            let keywords = MYSTERY_CAT_KEYWORDS
+                .iter()
+                .map(|label| Keyword::new(label.to_string(), 1.0))
+                .collect();
+            (VisionReport::from_keywords(keywords), MYSTERY_CAT_REPLY)
+        } else if let Some((report, cached_at)) = cached {
+            // The cached report already passed the safety and face policies
+            info!("Avatar unchanged since last request. Reusing its keywords");
+            analyzed_at = cached_at;
+            (report, IMAGE_REPLY)
+        } else if let Some((report, checkpoint_at)) = checkpoint.report.clone().zip(checkpoint.analyzed_at) {
+            info!("Resuming with the keywords of an earlier attempt");
+            analyzed_at = checkpoint_at;
+            (report, IMAGE_REPLY)
+        } else {
+            match self
+                .stage("vision", self.describe_avatar(tweet, image, &context_urls))
+                .await?
+            {
+                Some(report) => {
+                    checkpoint.report = Some(report.clone());
+                    checkpoint.analyzed_at = Some(analyzed_at);
+                    self.save_checkpoint(tweet, &checkpoint)?;
+                    (report, IMAGE_REPLY)
+                }
+                None => return Ok(Handled::Declined),
+            }
+        };
+
+        self.publish(
+            tweet,
+            PipelineEvent::KeywordsExtracted {
+                keywords: report.keywords.iter().map(|keyword| keyword.label.clone()).collect(),
+            },
+        );
+
+        let message = if avatar_changed {
+            format!("{} {}", NEW_AVATAR_REPLY, message)
+        } else {
+            message.to_string()
+        };
+
+        // Reuse the previous image when the avatar is nearly identical to the user's last request and it would be
+        // drawn from the same prompt template and theme, unless the user just changed their preferences
+        let description = self.generate_description(&report);
+        let prompt_key = prompt_key(&self.translate_prompt, self.themes.resolve_today(&text)?);
+        let vector = match previous.filter(|previous| previous.description == description) {
+            Some(previous) => previous.vector,
+            None => {
+                let embed = || {
+                    let description = description.clone();
+                    self.call_openai("openai.embedding", estimate_tokens(&description), || {
+                        blocking(move || Embedder::new()?.embed(&description))
+                    })
+                };
+                self.stage("embedding", retry("openai.embedding", &self.retry_policy, embed))
+                    .await?
+            }
+        };
+        let reusable = self
+            .previous_image(tweet, &vector, &prompt_key)
+            .filter(|_| !prefs_changed);
+        let reused = reusable.is_some();
+        let (image, image_path) = match reusable {
+            Some(path) => {
+                info!(path = %path, "Avatar nearly identical to last request. Reusing its image");
+                (Image::from_file(path.clone()), path)
+            }
+            None => {
+                let translated_desc = match checkpoint.translated.clone() {
+                    Some(translated) => {
+                        info!("Resuming with the image description of an earlier attempt");
+                        translated
+                    }
+                    None => {
+                        let prompt = self.translation_prompt(&report, &text, &prefs)?;
+                        let translate = || {
+                            let tokens = prompt_tokens(&prompt, PROMPT_RESPONSE_TOKENS);
+                            self.call_openai("openai.prompt", tokens, || self.translate_description(&prompt))
+                        };
+                        let translated_desc = self
+                            .stage("prompt", retry("openai.prompt", &self.retry_policy, translate))
+                            .await?;
+                        self.audit(
+                            tweet,
+                            AuditEvent::Prompt {
+                                prompt,
+                                response: translated_desc.clone(),
+                            },
+                        )?;
+                        checkpoint.translated = Some(translated_desc.clone());
+                        self.save_checkpoint(tweet, &checkpoint)?;
+                        translated_desc
+                    }
+                };
+
+                // The image file may be gone, e.g. after moving to another machine
+                match checkpoint.image_path.clone().filter(|path| Path::new(path).exists()) {
+                    Some(path) => {
+                        info!(path = %path, "Resuming with the image of an earlier attempt");
+                        (Image::from_file(path.clone()), path)
+                    }
+                    None => {
+                        let generate = || {
+                            let description = translated_desc.clone();
+                            self.call_openai("openai.image", 0, || blocking(move || generate_image(&description)))
+                        };
+                        let (image, image_path) = self
+                            .stage("image", retry("openai.image", &self.retry_policy, generate))
+                            .await?;
+                        checkpoint.image_path = Some(image_path.clone());
+                        self.save_checkpoint(tweet, &checkpoint)?;
+                        (image, image_path)
+                    }
+                }
+            }
+        };
+
+        self.audit(
+            tweet,
+            AuditEvent::Image {
+                path: image_path.clone(),
+                reused,
+            },
+        )?;
+        self.publish(
+            tweet,
+            PipelineEvent::ImageReady {
+                path: image_path.clone(),
+                reused,
+            },
+        );
 
         // Send response tweet with generated image
-        self.send_tweet_with_image(tweet, &image).await?;
+        self.stage("post", self.send_tweet_with_image(tweet, &image, &message))
+            .await?;
 
-        Ok(())
+        // Remember the avatar for similarity lookups, change detection and future dedup
+        if let Some(user_id) = &tweet.user_id {
+            let mut embeddings = self.embeddings.lock().unwrap();
+            embeddings.insert(
+                user_id.clone(),
+                UserEmbedding {
+                    vector,
+                    description,
+                    image_path: Some(image_path.clone()),
+                    prompt_key: Some(prompt_key),
+                    avatar_hash: Some(avatar_hash),
+                    report: Some(report),
+                    analyzed_at: Some(analyzed_at),
+                },
+            );
+            embeddings.save_to_file()?;
+        }
+
+        Ok(Handled::Drawn(image_path))
     }
 
-    // Generate description using Google Vision API
-    fn generate_description(&self, image: Image) -> Result<String> {
-        let vision = GoogleVision::new()?;
-        let descs = vision.create_desc(GoogleVisionRequest { image, max_results: 10 })?;
-        Ok(descs.join(","))
+    // Find the image generated for the user's previous avatar if it is nearly identical to the current one and
+    // was drawn from the same prompt template and theme
+    fn previous_image(&self, tweet: &ExtractedTweet, vector: &[f32], prompt_key: &str) -> Option<String> {
+        let embeddings = self.embeddings.lock().unwrap();
+        let previous = embeddings.get(tweet.user_id.as_deref()?)?;
+        let path = previous.image_path.clone()?;
+
+        (previous.prompt_key.as_deref() == Some(prompt_key)
+            && cosine_similarity(&previous.vector, vector) >= DUPLICATE_AVATAR_SIMILARITY
+            && Path::new(&path).exists())
+        .then_some(path)
     }
 
-    // Translate and optimize description using GPT-4
-    async fn translate_description(&self, desc_string: &str) -> Result<String> {
-        let client = openai::Client::from_env();
-        let gpt4 = client.agent("gpt-4").build();
-        let prompt_string = self.translate_prompt.replace("{}", &desc_string);
-        let response: String = gpt4.prompt(&prompt_string).await?;
+    // Check whether an avatar analysis from a Unix timestamp can still be reused, unknown times count as expired
+    fn is_fresh(&self, analyzed_at: Option<i64>, now: i64) -> bool {
+        match (analyzed_at, self.vision_ttl) {
+            (Some(_), None) => true,
+            (Some(analyzed_at), Some(ttl)) => now - analyzed_at < ttl.as_secs() as i64,
+            (None, _) => false,
+        }
+    }
+
+    // Analyze avatar and apply safety policies, returning None when the request was declined
+    async fn describe_avatar(
+        &self,
+        tweet: &ExtractedTweet,
+        image: Image,
+        context_urls: &[String],
+    ) -> Result<Option<VisionReport>> {
+        let report = self.analyze_image(image, context_urls).await?;
+
+        let safety = report.safety();
+        let report = match (&safety, self.face_policy) {
+            // Refuse politely before generating anything from an unsafe avatar
+            (SafetyVerdict::Unsafe(categories), _) => {
+                info!(categories = %categories.join(","), "Avatar flagged as unsafe. Declining");
+                self.audit(tweet, AuditEvent::moderation(&safety, "declined"))?;
+                self.send_reply(tweet, UNSAFE_REPLY).await?;
+                return Ok(None);
+            }
+            // Never derive a portrait from a real person's face
+            (SafetyVerdict::Faces(_), FacePolicy::Reject) => {
+                info!("Avatar shows a real face. Declining");
+                self.audit(tweet, AuditEvent::moderation(&safety, "declined"))?;
+                self.send_reply(tweet, FACE_REJECT_REPLY).await?;
+                return Ok(None);
+            }
+            (SafetyVerdict::Faces(_), FacePolicy::Anonymize) => {
+                self.audit(tweet, AuditEvent::moderation(&safety, "anonymized"))?;
+                report.anonymized()
+            }
+            _ => {
+                self.audit(tweet, AuditEvent::moderation(&safety, "allowed"))?;
+                report
+            }
+        };
+
+        let report = match self.text_policy {
+            TextPolicy::Exclude => report.without_text(),
+            _ => report,
+        };
 
-        Ok(response)
+        Ok(Some(report))
     }
 
-    // Generate new image using DALL-E
-    fn generate_image(&self, description: &str) -> Result<Image> {
-        let image_gen = ImageGen::new()?;
-        let image = image_gen.create_image(ImageRequest {
-            description: description.into(),
-            width: 1792,
-            height: 1024,
-        })?;
+    // Analyze avatar together with any context images using the configured vision provider
+    async fn analyze_image(&self, image: Image, context_urls: &[String]) -> Result<VisionReport> {
+        // Large avatars cost more to analyze without adding useful detail
+        let max_edge = self.vision_max_edge;
+        let image = blocking(move || image.downscaled(max_edge)).await?;
+        let started = Instant::now();
+        let request = VisionRequest {
+            image,
+            max_results: self.vision_max_results,
+            detect_text: self.text_policy != TextPolicy::Off,
+        };
+        let vision = self.vision.clone();
+        let report = self
+            .vision_breaker
+            .call(|| blocking(move || vision.create_report(request)))
+            .await
+            .inspect_err(|_| metrics().increment("vision.errors", 1))?;
+        let duration = started.elapsed();
+        info!(
+            provider = %report.provider,
+            labels = report.keywords.len(),
+            duration_ms = duration.as_millis() as u64,
+            "Avatar analyzed"
+        );
+
+        // Providers bill per analyzed image, labels show how much each call returned
+        metrics().record_duration(&format!("vision.latency.{}", report.provider), duration);
+        metrics().increment(&format!("vision.images.{}", report.provider), 1);
+        self.costs.record(&format!("vision.{}", report.provider))?;
+        metrics().increment(&format!("vision.labels.{}", report.provider), report.keywords.len() as u64);
 
-        // Save generated image to disk
-        let output_path = custom_image_path();
-        image.save(&output_path)?;
-        println!("Saved image to {:?}", output_path);
+        if context_urls.is_empty() {
+            return Ok(report);
+        }
 
-        Ok(image)
+        // Fuse in the context images, the avatar leads colors and mood
+        let started = Instant::now();
+        let mut reports = vec![report];
+        let (vision, urls) = (self.vision.clone(), context_urls.to_vec());
+        let (max_results, max_edge) = (self.vision_max_results, self.vision_max_edge);
+        for result in blocking(move || Ok(vision.analyze_batch(&urls, max_results, max_edge))).await? {
+            match result {
+                Ok(report) => {
+                    metrics().increment(&format!("vision.images.{}", report.provider), 1);
+                    self.costs.record(&format!("vision.{}", report.provider))?;
+                    reports.push(report);
+                }
+                Err(err) => {
+                    metrics().increment("vision.context_errors", 1);
+                    warn!(error = ?err, "Skipping context image");
+                }
+            }
+        }
+        metrics().record_duration("vision.context_latency", started.elapsed());
+        info!(images = reports.len(), "Fused images");
+
+        VisionReport::fuse(reports).ok_or_else(|| anyhow!("No images analyzed"))
     }
 
-    // Send tweet with generated image as reply
-    async fn send_tweet_with_image(&self, tweet: &ExtractedTweet, image: &Image) -> anyhow::Result<()> {
-        let media_data = vec![(image.bytes(), "image/jpeg".to_string())];
-        let tweet_with_media = self
-            .twitter
-            .send_tweet(
-                &format!("Check out this image! @{}", tweet.username.clone().unwrap()),
-                None,
-                Some(media_data),
-            )
+    // Collect the URLs of the context images enabled in VISION_CONTEXT
+    fn context_urls(&self, tweet: &ExtractedTweet, profile: &Profile) -> Vec<String> {
+        let mut urls = Vec::new();
+        for source in &self.context_sources {
+            match source {
+                ContextSource::Banner => urls.extend(profile.profile_banner_url.clone()),
+                ContextSource::Photos => urls.extend(tweet.photos.iter().cloned()),
+            }
+        }
+
+        urls
+    }
+
+    // Generate description from the analysis report
+    fn generate_description(&self, report: &VisionReport) -> String {
+        // Labels come from a user-controlled image, so never trust them as-is
+        let keywords = report.sanitized_keywords();
+
+        // Lead with confident labels so they dominate the prompt, the top label always counts as main
+        let (main, details): (Vec<_>, Vec<_>) = keywords
+            .iter()
+            .enumerate()
+            .partition(|(index, keyword)| *index == 0 || keyword.score >= MAIN_KEYWORD_SCORE);
+        let join = |keywords: Vec<(usize, &Keyword)>| {
+            keywords
+                .into_iter()
+                .map(|(_, keyword)| keyword.label.as_str())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+
+        let mut description = join(main);
+        if !details.is_empty() {
+            description = format!("{} (minor details: {})", description, join(details));
+        }
+
+        // Echo text from the avatar only when the operator opted in
+        let text = report.sanitized_text();
+        if self.text_policy == TextPolicy::Include && !text.is_empty() {
+            description = format!("{},text on the avatar: {}", description, text.join(" "));
+        }
+
+        // Add the avatar's palette so the generated art matches its colors
+        match report.palette() {
+            Some(palette) => format!("{},{}", description, palette),
+            None => description,
+        }
+    }
+
+    // Fill the prompt template: {} takes the full description, {subject}, {style}, {color} and {mood} take
+    // the labels of that category
+    fn build_prompt(&self, report: &VisionReport) -> String {
+        let keywords = report.sanitized_keywords();
+        let slot = |category: Category| {
+            keywords
+                .iter()
+                .filter(|keyword| keyword.category == category)
+                .map(|keyword| keyword.label.as_str())
+                .collect::<Vec<_>>()
+        };
+
+        let mut colors = slot(Category::Color);
+        let palette = report.palette();
+        if let Some(palette) = &palette {
+            colors.push(palette);
+        }
+
+        let mut moods = slot(Category::Mood);
+        if report.mood != Mood::Unknown {
+            moods.push(report.mood.as_str());
+        }
+
+        self.translate_prompt
+            .replace("{}", &self.generate_description(report))
+            .replace("{subject}", &slot(Category::Subject).join(","))
+            .replace("{style}", &slot(Category::Style).join(","))
+            .replace("{color}", &colors.join(","))
+            .replace("{mood}", &moods.join(","))
+    }
+
+    // Build the prompt asking GPT-4 for an image description, with the theme and style of the mention
+    fn translation_prompt(&self, report: &VisionReport, text: &str, prefs: &UserPrefs) -> Result<String> {
+        let mut prompt_string = self.build_prompt(report);
+
+        // Add the seasonal theme, unless the mention opted out
+        if let Some(theme) = self.themes.resolve_today(text)? {
+            info!(theme = %theme.name, "Applying theme");
+            prompt_string = format!("{} {}", prompt_string, theme.prompt);
+        }
+
+        // Add the art style the user asked for
+        if let Some(style) = &prefs.style {
+            prompt_string = format!("{} Draw it in a {} style.", prompt_string, style);
+        }
+
+        Ok(prompt_string)
+    }
+
+    // Translate and optimize description using GPT-4
+    async fn translate_description(&self, prompt: &str) -> Result<String> {
+        self.openai.prompt("gpt-4", prompt, PROMPT_RESPONSE_TOKENS).await
+    }
+
+    // Call OpenAI once the account limits allow about `estimated_tokens` more tokens, failing fast while its
+    // breaker is open, and add the estimated price of the operation to the spend
+    async fn call_openai<T, Fut>(&self, operation: &str, estimated_tokens: u32, op: impl FnOnce() -> Fut) -> Result<T>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        let output = self
+            .openai_breaker
+            .call(|| self.openai.call(estimated_tokens, op))
             .await?;
+        self.costs.record(operation)?;
+
+        Ok(output)
+    }
+
+    // Run one stage of handling a mention in its own log span, recording its latency as e.g. "stage.vision". A stage
+    // running past its time limit is cancelled and fails the attempt
+    async fn stage<T>(&self, name: &'static str, future: impl Future<Output = Result<T>>) -> Result<T> {
+        let started = Instant::now();
+        let future = future.instrument(info_span!("stage", stage = name));
+        let output = self.stage_timeouts.run(name, future).await;
+        metrics().record_duration(&format!("stage.{}", name), started.elapsed());
+
+        output
+    }
+
+    // Log of generated content, shown on the dashboard
+    pub fn audit_log(&self) -> &AuditLog {
+        &self.audit_log
+    }
+
+    // Estimated spend, shown on the dashboard
+    pub fn costs(&self) -> &CostTracker {
+        &self.costs
+    }
+
+    // Configured spending limits
+    pub fn budget(&self) -> Budget {
+        self.budget
+    }
+
+    // Queue of accepted mentions and its dead letters
+    pub fn queue(&self) -> &MentionQueue {
+        &self.queue
+    }
+
+    // Providers whose circuit breaker currently rejects calls
+    pub fn unavailable_providers(&self) -> Vec<&str> {
+        [&self.openai_breaker, &self.twitter_breaker, &self.vision_breaker]
+            .into_iter()
+            .filter(|breaker| breaker.is_open())
+            .map(CircuitBreaker::name)
+            .collect()
+    }
+
+    // Tell the operator once per budget period that a budget was reached, in the log and at BUDGET_ALERT_WEBHOOK
+    fn alert_budget(&self, exceeded: &BudgetExceeded) -> Result<()> {
+        if !self.costs.needs_alert(exceeded)? {
+            return Ok(());
+        }
+
+        error!(
+            period = exceeded.period,
+            spent = exceeded.spent,
+            limit = exceeded.limit,
+            "Budget reached"
+        );
+        let message = format!(
+            "Clara reached its {} budget for {}: ${:.2} of ${:.2} spent. New mentions wait until it resets",
+            exceeded.period, exceeded.key, exceeded.spent, exceeded.limit
+        );
+
+        // Slack-style webhook, a failed alert shouldn't stop the bot
+        if let Some(url) = config::var("BUDGET_ALERT_WEBHOOK").ok().filter(|url| !url.is_empty()) {
+            let body = serde_json::json!({ "text": message }).to_string();
+            let headers = [("Content-Type", "application/json")];
+            if let Err(err) = HttpClient::new().post_with_headers(&url, &headers, &body) {
+                warn!(error = ?err, "Cannot send budget alert");
+            }
+        }
 
-        println!("tweet_with_media {:#?}", tweet_with_media);
         Ok(())
     }
+
+    // Send tweet with generated image as reply
+    async fn send_tweet_with_image(&self, tweet: &ExtractedTweet, image: &Image, text: &str) -> anyhow::Result<()> {
+        let twitter = self.twitter()?;
+        let text = format!("{} @{}", text, author(tweet)?);
+        let tweet_with_media = retry("twitter.post", &self.retry_policy, || {
+            let media_data = vec![(image.bytes(), "image/jpeg".to_string())];
+            self.twitter_breaker
+                .call(|| twitter.send_tweet(&text, None, Some(media_data)))
+        })
+        .await?;
+
+        info!(response = ?tweet_with_media, "Sent tweet with image");
+        let reply_id = reply_id(&tweet_with_media);
+        self.publish(
+            tweet,
+            PipelineEvent::Posted {
+                reply_id: reply_id.clone(),
+            },
+        );
+        self.audit(tweet, AuditEvent::Reply { reply_id, text })
+    }
+
+    // Twitter client, failing for handlers made without one
+    fn twitter(&self) -> Result<&Twitter> {
+        self.twitter
+            .as_ref()
+            .ok_or_else(|| anyhow!("Not logged in to Twitter, this handler only serves the generation APIs"))
+    }
+
+    // Append an event of a mention to the audit log
+    fn audit(&self, tweet: &ExtractedTweet, event: AuditEvent) -> Result<()> {
+        self.audit_log
+            .record(tweet.id.as_deref(), tweet.username.as_deref(), event)
+    }
+
+    // Append an event of an API request to the audit log, under the client it came from
+    fn audit_client(&self, client: &str, event: AuditEvent) -> Result<()> {
+        self.audit_log
+            .record(None, Some(&format!("{}:{}", API_PLATFORM, client)), event)
+    }
+
+    // Read the checkpoint of a mention, None if there is none or it can't be read anymore
+    fn checkpoint(&self, tweet: &ExtractedTweet) -> Result<Option<Checkpoint>> {
+        let data = match &tweet.id {
+            Some(id) => self.ledger.checkpoint(id)?,
+            None => None,
+        };
+
+        Ok(data.and_then(|data| serde_json::from_str(&data).ok()))
+    }
+
+    // Save the checkpoint of a mention
+    fn save_checkpoint(&self, tweet: &ExtractedTweet, checkpoint: &Checkpoint) -> Result<()> {
+        match &tweet.id {
+            Some(id) => self.ledger.save_checkpoint(id, &serde_json::to_string(checkpoint)?),
+            None => Ok(()),
+        }
+    }
+
+    // Tell event stream subscribers about progress on a mention
+    fn publish(&self, tweet: &ExtractedTweet, event: PipelineEvent) {
+        if let Some(id) = &tweet.id {
+            events().publish(id, event);
+        }
+    }
+
+    // Send text-only reply to the tweet's author
+    async fn send_reply(&self, tweet: &ExtractedTweet, text: &str) -> Result<()> {
+        let twitter = self.twitter()?;
+        let text = format!("{} @{}", text, author(tweet)?);
+        let reply = retry("twitter.post", &self.retry_policy, || {
+            self.twitter_breaker.call(|| twitter.send_tweet(&text, None, None))
+        })
+        .await?;
+
+        info!(response = ?reply, "Sent reply");
+        let reply_id = reply_id(&reply);
+        self.publish(
+            tweet,
+            PipelineEvent::Posted {
+                reply_id: reply_id.clone(),
+            },
+        );
+        self.audit(tweet, AuditEvent::Reply { reply_id, text })
+    }
+}
+
+impl<'a> ReplySlot<'a> {
+    // Wrap a slot just taken from the limiter
+    fn new(limiter: &'a RateLimiter) -> Self {
+        Self { limiter, kept: false }
+    }
+
+    // Leave the slot counted
+    fn keep(&mut self) {
+        self.kept = true;
+    }
+
+    // Keep the slot once a reply was posted, declines included, or when the mention will be retried as the slot
+    // is only taken once
+    fn settle(&mut self, result: &Result<Handled>) {
+        if !matches!(result, Ok(Handled::Skipped)) {
+            self.keep();
+        }
+    }
+}
+
+impl Handled {
+    // Path of the generated image, if one was drawn
+    fn image_path(&self) -> Option<&str> {
+        match self {
+            Handled::Drawn(path) => Some(path),
+            Handled::Declined | Handled::Skipped => None,
+        }
+    }
+}
+
+impl Drop for ReplySlot<'_> {
+    fn drop(&mut self) {
+        if self.kept {
+            return;
+        }
+
+        if let Err(err) = self.limiter.release(DAILY_REPLIES_KEY) {
+            warn!(error = ?err, "Failed to give back a daily reply cap slot");
+        }
+    }
+}
+
+// Hash of the prompt template and the theme resolved for a mention, which change the image drawn for the same avatar
+fn prompt_key(template: &str, theme: Option<&Theme>) -> String {
+    let theme = theme.map_or("", |theme| theme.prompt.as_str());
+    let digest = Sha256::digest(format!("{}\n{}", template, theme));
+
+    format!("{:x}", digest)
+}
+
+// Generate new image using DALL-E, returning it with the path it was saved to. Blocks on the request
+fn generate_image(description: &str) -> Result<(Image, String)> {
+    let image_gen = ImageGen::new()?;
+    let image = image_gen.create_image(ImageRequest {
+        description: description.into(),
+        width: 1792,
+        height: 1024,
+    })?;
+
+    // Save generated image to disk
+    let output_path = custom_image_path();
+    image.save(&output_path)?;
+    info!(path = %output_path.display(), "Saved image");
+
+    Ok((image, output_path.to_string_lossy().into_owned()))
+}
+
+// Handle of the author of a mention, which answering it needs
+fn author(tweet: &ExtractedTweet) -> Result<&str> {
+    tweet
+        .username
+        .as_deref()
+        .ok_or_else(|| anyhow!("Mention {} has no username", tweet.id.as_deref().unwrap_or_default()))
+}
+
+// Get the ID of a posted tweet from the create tweet response
+fn reply_id(response: &Value) -> Option<String> {
+    response
+        .pointer("/data/create_tweet/tweet_results/result/rest_id")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+}
+
+// Span carrying the tweet ID and user of a mention
+fn mention_span(tweet: &ExtractedTweet) -> Span {
+    info_span!(
+        "mention",
+        request_id = tweet.id.as_deref().unwrap_or_default(),
+        user = tweet.username.as_deref().unwrap_or_default()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::MockClock;
+
+    fn daily_cap(limit: u32, clock: &Arc<MockClock>) -> RateLimiter {
+        RateLimiter::in_memory(RateStrategy::per_day(limit)).with_clock(clock.clone())
+    }
+
+    fn take(limiter: &RateLimiter) -> Option<ReplySlot<'_>> {
+        match limiter.try_acquire(DAILY_REPLIES_KEY).unwrap() {
+            RateDecision::Allowed => Some(ReplySlot::new(limiter)),
+            RateDecision::Limited(_) => None,
+        }
+    }
+
+    #[test]
+    fn prompt_key_changes_with_the_template_and_theme() {
+        let calendar = ThemeCalendar::default();
+        let theme = calendar.themes.first();
+        let key = prompt_key("Draw a cat: {}", None);
+
+        assert_eq!(prompt_key("Draw a cat: {}", None), key);
+        assert_ne!(prompt_key("Draw a kitten: {}", None), key);
+        assert_ne!(prompt_key("Draw a cat: {}", theme), key);
+    }
+
+    #[test]
+    fn dropped_mention_gives_its_slot_back() {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let limiter = daily_cap(2, &clock);
+
+        // Mentions over the user's limit or without a reply drop the slot without keeping it
+        for _ in 0..5 {
+            drop(take(&limiter).unwrap());
+        }
+
+        let mut first = take(&limiter).unwrap();
+        first.keep();
+        assert!(take(&limiter).is_some());
+    }
+
+    #[test]
+    fn declined_mentions_keep_their_slot() {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let limiter = daily_cap(2, &clock);
+
+        // Declining an unsafe avatar still posts a reply, a skipped mention doesn't
+        take(&limiter).unwrap().settle(&Ok(Handled::Skipped));
+        take(&limiter).unwrap().settle(&Ok(Handled::Declined));
+        let drawn = Ok(Handled::Drawn("cat.png".to_string()));
+        take(&limiter).unwrap().settle(&drawn);
+
+        assert!(take(&limiter).is_none());
+    }
+
+    #[test]
+    fn answered_mentions_count_until_the_day_is_over() {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let limiter = daily_cap(2, &clock);
+
+        for _ in 0..2 {
+            take(&limiter).unwrap().keep();
+        }
+        assert!(take(&limiter).is_none());
+
+        clock.advance(Duration::from_secs(23 * 60 * 60));
+        assert!(take(&limiter).is_none());
+
+        clock.advance(Duration::from_secs(60 * 60));
+        take(&limiter).unwrap().keep();
+        assert!(take(&limiter).is_some());
+    }
 }
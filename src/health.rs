@@ -0,0 +1,435 @@
+// Import networking, synchronization, future and time handling
+use std::{
+    convert::Infallible,
+    future::Future,
+    net::{SocketAddr, TcpListener},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+// Import error handling
+use anyhow::{anyhow, Result};
+// Import the HTTP server
+use hyper::{
+    header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE, WWW_AUTHENTICATE},
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+// Import JSON building
+use serde_json::{json, Value};
+// Import hashing for comparing admin tokens
+use sha2::{Digest, Sha256};
+// Import the handler lock
+use tokio::sync::RwLock;
+// Import logging
+use tracing::{error, info};
+// Import query string parsing
+use url::form_urlencoded;
+
+// Import settings, the dashboard, event stream, handler and secrets
+use crate::{
+    config, dashboard, events,
+    handler::{AlreadyAnswered, Handler},
+    secrets::secrets,
+};
+
+// Longest time without a finished poll before the bot counts as stuck, polls wait while every worker is busy
+const MAX_POLL_AGE: Duration = Duration::from_secs(15 * 60);
+
+// State behind the readiness check and dashboard, updated by the bot as it starts and polls
+#[derive(Default)]
+pub struct Health {
+    // Bearer token the admin API requires, which is off without one
+    admin_token: Option<String>,
+    // Handler, set once it logged in with the configured credentials
+    handler: OnceLock<Arc<RwLock<Handler>>>,
+    // When the last poll for mentions finished
+    last_poll: Mutex<Option<Instant>>,
+}
+
+impl Health {
+    // Create state for a bot that hasn't started yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Take admin API requests carrying this bearer token, None leaves the admin API off
+    pub fn with_admin_token(mut self, admin_token: Option<String>) -> Self {
+        self.admin_token = admin_token;
+        self
+    }
+
+    // Mark the bot as started with its handler
+    pub fn set_handler(&self, handler: Arc<RwLock<Handler>>) {
+        let _ = self.handler.set(handler);
+    }
+
+    // Record a finished poll for mentions
+    pub fn record_poll(&self) {
+        *self.last_poll.lock().unwrap() = Some(Instant::now());
+    }
+
+    // Reasons the bot isn't ready, empty when it is
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        match self.handler.get().map(|handler| handler.try_read()) {
+            None => problems.push("Not logged in yet".to_string()),
+            // Settings are being reloaded, which waits for the mentions in progress
+            Some(Err(_)) => problems.push("Reloading settings".to_string()),
+            Some(Ok(handler)) => {
+                for provider in handler.unavailable_providers() {
+                    problems.push(format!("{} is unavailable", provider));
+                }
+            }
+        }
+
+        match *self.last_poll.lock().unwrap() {
+            None => problems.push("No poll for mentions finished yet".to_string()),
+            Some(last_poll) if last_poll.elapsed() > MAX_POLL_AGE => problems.push(format!(
+                "No poll for mentions finished in {} seconds",
+                last_poll.elapsed().as_secs()
+            )),
+            Some(_) => {}
+        }
+
+        problems
+    }
+}
+
+// Read the address to serve health checks on from HEALTH_ADDR, e.g. 0.0.0.0:8080. None when unset
+pub fn health_addr_from_env() -> Result<Option<SocketAddr>> {
+    match config::var("HEALTH_ADDR") {
+        Ok(value) if !value.is_empty() => value
+            .parse()
+            .map(Some)
+            .map_err(|err| anyhow!("HEALTH_ADDR must be an address like 0.0.0.0:8080: {}", err)),
+        _ => Ok(None),
+    }
+}
+
+// Read the bearer token of the admin API from ADMIN_TOKEN through the secrets provider. None when unset, which
+// leaves the admin API off
+pub fn admin_token_from_env() -> Option<String> {
+    secrets().get("ADMIN_TOKEN").ok().filter(|token| !token.is_empty())
+}
+
+// Bind the address of a server before supervising it, so an address that is taken or not allowed fails startup
+// instead of being retried
+pub fn bind(addr: SocketAddr) -> Result<TcpListener> {
+    let listener = TcpListener::bind(addr).map_err(|err| anyhow!("Cannot listen on {}: {}", addr, err))?;
+    listener.set_nonblocking(true)?;
+
+    Ok(listener)
+}
+
+// Serve /healthz, /readyz and the admin API until the server fails. Uses the listener bound
+// at startup, a restarted server binds the address again
+pub async fn serve(addr: SocketAddr, listener: Option<TcpListener>, health: Arc<Health>) -> Result<()> {
+    info!(%addr, "Serving health checks");
+    serve_with(addr, listener, health, respond).await
+}
+
+// Serve the dashboard and event stream until the server fails. They aren't authenticated, so they get an address of
+// their own instead of sharing the one probes reach
+pub async fn serve_dashboard(addr: SocketAddr, listener: Option<TcpListener>, health: Arc<Health>) -> Result<()> {
+    info!(%addr, "Serving the dashboard");
+    serve_with(addr, listener, health, respond_dashboard).await
+}
+
+// Serve requests with `respond` on the listener bound at startup, or bind the address again after a restart
+async fn serve_with<F, Fut>(
+    addr: SocketAddr,
+    listener: Option<TcpListener>,
+    health: Arc<Health>,
+    respond: F,
+) -> Result<()>
+where
+    F: Fn(Arc<Health>, Request<Body>) -> Fut + Copy + Send + Sync + 'static,
+    Fut: Future<Output = Result<Response<Body>, Infallible>> + Send + 'static,
+{
+    let listener = match listener {
+        Some(listener) => listener,
+        None => bind(addr)?,
+    };
+    let make_service = make_service_fn(move |_| {
+        let health = health.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |request| respond(health.clone(), request))) }
+    });
+
+    Ok(Server::from_tcp(listener)?.serve(make_service).await?)
+}
+
+// Answer a health check request
+async fn respond(health: Arc<Health>, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let path = request.uri().path().to_string();
+    let (status, body) = match path.as_str() {
+        // The process is up and answering
+        "/healthz" => (StatusCode::OK, json!({ "status": "ok" })),
+        "/readyz" => match health.problems() {
+            problems if problems.is_empty() => (StatusCode::OK, json!({ "status": "ready" })),
+            problems => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!({ "status": "not ready", "problems": problems }),
+            ),
+        },
+        // POST /admin/replay/<tweet_id>, with ?force=true to answer a mention again. It posts tweets and spends
+        // budget, so it needs the admin token and is off without one
+        path if path.starts_with("/admin/replay/") => {
+            if let Some(response) = unauthorized(health.admin_token.as_deref(), "ADMIN_TOKEN", &request) {
+                return Ok(response);
+            }
+            if request.method() != Method::POST {
+                return Ok(json_response(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                    json!({ "error": "Use POST" }),
+                ));
+            }
+            let force = request.uri().query().is_some_and(|query| {
+                form_urlencoded::parse(query.as_bytes()).any(|(name, value)| name == "force" && value == "true")
+            });
+            return Ok(replay(&health, &path["/admin/replay/".len()..], force).await);
+        }
+        _ => (StatusCode::NOT_FOUND, json!({ "error": "Not found" })),
+    };
+
+    Ok(json_response(status, body))
+}
+
+// Answer a dashboard request
+async fn respond_dashboard(health: Arc<Health>, request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let path = request.uri().path().to_string();
+    Ok(match path.as_str() {
+        "/dashboard" => with_handler(&health, dashboard_page),
+        "/events" => events::websocket(request),
+        path if path.starts_with("/dashboard/images/") => {
+            let tweet_id = &path["/dashboard/images/".len()..];
+            with_handler(&health, |handler| dashboard_image(handler, tweet_id))
+        }
+        _ => json_response(StatusCode::NOT_FOUND, json!({ "error": "Not found" })),
+    })
+}
+
+// Check the bearer token of a request against the token an API requires, answering with the problem if it can't
+// go through. None when it can. The API is off while `setting` holds no token
+pub fn unauthorized(expected: Option<&str>, setting: &str, request: &Request<Body>) -> Option<Response<Body>> {
+    let Some(expected) = expected else {
+        return Some(json_response(
+            StatusCode::FORBIDDEN,
+            json!({ "error": format!("This API is off, set {} to use it", setting) }),
+        ));
+    };
+
+    let token = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if !tokens_match(token, expected) {
+        let mut response = json_response(StatusCode::UNAUTHORIZED, json!({ "error": "Invalid token" }));
+        response
+            .headers_mut()
+            .insert(WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"));
+        return Some(response);
+    }
+
+    None
+}
+
+// Compare tokens in constant time. Comparing their hashes keeps the length of the expected token from leaking too
+pub fn tokens_match(given: &str, expected: &str) -> bool {
+    let (given, expected) = (Sha256::digest(given.as_bytes()), Sha256::digest(expected.as_bytes()));
+
+    given
+        .iter()
+        .zip(expected.iter())
+        .fold(0, |difference, (a, b)| difference | (a ^ b))
+        == 0
+}
+
+// Answer a dashboard request with the handler, which is unavailable while starting or reloading settings
+fn with_handler(health: &Health, respond: impl FnOnce(&Handler) -> Response<Body>) -> Response<Body> {
+    match health.handler.get().map(|handler| handler.try_read()) {
+        Some(Ok(handler)) => respond(&handler),
+        _ => json_response(
+            StatusCode::SERVICE_UNAVAILABLE,
+            json!({ "error": "Starting or reloading" }),
+        ),
+    }
+}
+
+// Render the dashboard
+fn dashboard_page(handler: &Handler) -> Response<Body> {
+    match dashboard::render(handler) {
+        Ok(page) => response(StatusCode::OK, "text/html; charset=utf-8", page),
+        Err(err) => {
+            error!(error = ?err, "Failed to render the dashboard");
+            json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({ "error": err.to_string() }))
+        }
+    }
+}
+
+// Send the image generated for a mention
+fn dashboard_image(handler: &Handler, tweet_id: &str) -> Response<Body> {
+    match dashboard::image(handler, tweet_id) {
+        Ok(Some(image)) => response(StatusCode::OK, "image/png", image),
+        Ok(None) => json_response(StatusCode::NOT_FOUND, json!({ "error": "Not found" })),
+        Err(err) => json_response(StatusCode::INTERNAL_SERVER_ERROR, json!({ "error": err.to_string() })),
+    }
+}
+
+// Queue a mention again for the workers
+async fn replay(health: &Health, tweet_id: &str, force: bool) -> Response<Body> {
+    let Some(handler) = health.handler.get() else {
+        return json_response(StatusCode::SERVICE_UNAVAILABLE, json!({ "error": "Starting" }));
+    };
+
+    match handler.read().await.replay(tweet_id, force).await {
+        Ok(_) => json_response(StatusCode::ACCEPTED, json!({ "queued": tweet_id })),
+        Err(err) if err.is::<AlreadyAnswered>() => {
+            json_response(StatusCode::CONFLICT, json!({ "error": err.to_string() }))
+        }
+        Err(err) => {
+            error!(request_id = %tweet_id, error = ?err, "Failed to replay a mention");
+            json_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({ "error": format!("{:#}", err) }),
+            )
+        }
+    }
+}
+
+// Build a JSON response
+pub fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    response(status, "application/json", body.to_string())
+}
+
+// Build a response with a body of the given type
+fn response(status: StatusCode, content_type: &'static str, body: impl Into<Body>) -> Response<Body> {
+    let mut response = Response::new(body.into());
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    // POST /admin/replay/1 with an optional bearer token
+    fn replay_request(token: Option<&str>) -> Request<Body> {
+        let mut request = Request::builder().method(Method::POST).uri("/admin/replay/1");
+        if let Some(token) = token {
+            request = request.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        request.body(Body::empty()).unwrap()
+    }
+
+    // Health state serving the admin API with the given token
+    fn health(admin_token: Option<&str>) -> Arc<Health> {
+        Arc::new(Health::new().with_admin_token(admin_token.map(str::to_string)))
+    }
+
+    #[tokio::test]
+    async fn admin_api_is_off_without_a_token() {
+        let response = respond(health(None), replay_request(Some("anything"))).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn admin_api_refuses_missing_and_wrong_tokens() {
+        for token in [None, Some("wrong"), Some("secret-but-longer")] {
+            let response = respond(health(Some("secret")), replay_request(token)).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(response.headers()[WWW_AUTHENTICATE], "Bearer");
+        }
+    }
+
+    #[tokio::test]
+    async fn admin_api_takes_the_right_token() {
+        // Without a started handler the replay itself can't run yet
+        let response = respond(health(Some("secret")), replay_request(Some("secret")))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn admin_api_checks_the_token_before_the_method() {
+        let request = Request::builder().uri("/admin/replay/1").body(Body::empty()).unwrap();
+
+        let response = respond(health(Some("secret")), request).await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    // GET request of a path
+    fn get(path: &str) -> Request<Body> {
+        Request::builder().uri(path).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn probe_address_never_serves_the_dashboard() {
+        for path in ["/dashboard", "/events", "/dashboard/images/1"] {
+            let response = respond(health(Some("secret")), get(path)).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn dashboard_address_serves_only_the_dashboard() {
+        // Without a started handler the dashboard can't render yet
+        let response = respond_dashboard(health(None), get("/dashboard")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        for path in ["/healthz", "/admin/replay/1"] {
+            let response = respond_dashboard(health(None), get(path)).await.unwrap();
+
+            assert_eq!(response.status(), StatusCode::NOT_FOUND, "{}", path);
+        }
+    }
+
+    #[test]
+    fn taken_address_fails_to_bind() {
+        let taken = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+
+        let err = bind(taken.local_addr().unwrap()).unwrap_err();
+
+        assert!(err.to_string().starts_with("Cannot listen on 127.0.0.1:"));
+    }
+
+    #[tokio::test]
+    async fn server_answers_on_the_listener_bound_at_startup() {
+        let listener = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(addr, Some(listener), health(None)));
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+    }
+
+    #[test]
+    fn tokens_match_only_when_equal() {
+        assert!(tokens_match("secret", "secret"));
+        assert!(!tokens_match("secret", "Secret"));
+        assert!(!tokens_match("", "secret"));
+    }
+}
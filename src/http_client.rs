@@ -1,29 +1,70 @@
+// Import time handling for request timeouts
+use std::time::Duration;
+
 // Import error handling
 use anyhow::Result;
-// Import HTTP POST request function
-use ureq::post;
+// Import HTTP agent
+use ureq::{Agent, AgentBuilder};
+
+// Timeout for a whole request, long enough for HD image generation
+const REQUEST_TIMEOUT_SECS: u64 = 120;
 
 // HTTP client structure for making requests
 #[derive(Debug)]
-pub struct HttpClient;
+pub struct HttpClient {
+    // Agent with request timeouts applied
+    agent: Agent,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl HttpClient {
     // Create new HTTP client instance
     pub fn new() -> Self {
-        HttpClient
+        HttpClient {
+            agent: AgentBuilder::new()
+                .timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS))
+                .build(),
+        }
     }
 
     // Make POST request with JSON body
     pub fn post(&self, url: &str, body: serde_json::Value) -> Result<serde_json::Value> {
-        let response = post(url).send_json(body)?;
+        let response = self.agent.post(url).send_json(body)?;
         Ok(response.into_json()?)
     }
 
     // Make authenticated POST request with JSON body
     pub fn post_with_auth(&self, url: &str, access_token: &str, body: serde_json::Value) -> Result<String> {
-        let response = post(url)
+        let response = self
+            .agent
+            .post(url)
             .set("Authorization", &format!("Bearer {}", access_token))
             .send_json(body)?;
         Ok(response.into_string()?)
     }
+
+    // Make GET request with custom headers
+    pub fn get_with_headers(&self, url: &str, headers: &[(&str, &str)]) -> Result<String> {
+        let mut request = self.agent.get(url);
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        let response = request.call()?;
+        Ok(response.into_string()?)
+    }
+
+    // Make POST request with a raw body and custom headers
+    pub fn post_with_headers(&self, url: &str, headers: &[(&str, &str)], body: &str) -> Result<String> {
+        let mut request = self.agent.post(url);
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        let response = request.send_string(body)?;
+        Ok(response.into_string()?)
+    }
 }
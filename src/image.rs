@@ -3,15 +3,22 @@ use base64::{engine::general_purpose, Engine};
 // Import serialization traits
 use serde::{Deserialize, Serialize};
 // Import file handling modules
-use std::io::Read;
 use std::{
     fs::{self, File},
     io::Write,
     path::PathBuf,
 };
-// Import HTTP GET request function and error handling
-use ureq::get;
-use anyhow::Result;
+// Import hardened downloader and error handling
+use crate::downloader::Downloader;
+use anyhow::{anyhow, Result};
+// Import image decoding, resizing and SVG rasterization
+use image::{imageops::FilterType, ImageFormat};
+use resvg::{tiny_skia, usvg};
+// Import hashing for change detection
+use sha2::{Digest, Sha256};
+
+// Largest SVG canvas rendered in pixels, 64 MiB as RGBA
+const MAX_SVG_PIXELS: u64 = 4096 * 4096;
 
 // Structure representing an image with base64 encoding
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -34,10 +41,9 @@ impl Image {
         Self { base64 }
     }
 
-    // Create Image from URL
+    // Create Image from an untrusted URL
     pub fn from_url(url: &str) -> Result<Self> {
-        let response = get(url).call()?;
-        let image_bytes = response.into_reader().bytes().collect::<Result<Vec<u8>, _>>()?;
+        let image_bytes = Downloader::new().download(url)?;
         let base64 = general_purpose::STANDARD.encode(&image_bytes);
 
         Ok(Self { base64 })
@@ -59,6 +65,113 @@ impl Image {
             .decode(&self.base64)
             .expect("Failed to decode base64 string")
     }
+
+    // Hex encoded SHA-256 of the image bytes
+    pub fn sha256(&self) -> String {
+        format!("{:x}", Sha256::digest(self.bytes()))
+    }
+
+    // Convert animated, SVG or other formats to a still PNG; PNG and JPEG are returned unchanged
+    pub fn normalized(&self) -> Result<Self> {
+        let bytes = self.bytes();
+
+        if is_svg(&bytes) {
+            return Ok(Self::from_png_bytes(rasterize_svg(&bytes)?));
+        }
+
+        match image::guess_format(&bytes)? {
+            ImageFormat::Png | ImageFormat::Jpeg => Ok(self.clone()),
+            format => {
+                // Animated GIF and WebP decode to their first frame
+                let decoded = image::load_from_memory_with_format(&bytes, format)?;
+                let mut png = Vec::new();
+                decoded.write_to(&mut std::io::Cursor::new(&mut png), ImageFormat::Png)?;
+
+                Ok(Self::from_png_bytes(png))
+            }
+        }
+    }
+
+    // Shrink the image so its longest edge is at most max_edge pixels, 0 disables downscaling. JPEG stays
+    // JPEG, anything else becomes PNG
+    pub fn downscaled(&self, max_edge: u32) -> Result<Self> {
+        let bytes = self.bytes();
+        let format = match image::guess_format(&bytes)? {
+            ImageFormat::Jpeg => ImageFormat::Jpeg,
+            _ => ImageFormat::Png,
+        };
+        let decoded = image::load_from_memory(&bytes)?;
+
+        if max_edge == 0 || decoded.width().max(decoded.height()) <= max_edge {
+            return Ok(self.clone());
+        }
+
+        let resized = decoded.resize(max_edge, max_edge, FilterType::Triangle);
+        let mut output = Vec::new();
+        resized.write_to(&mut std::io::Cursor::new(&mut output), format)?;
+
+        Ok(Self::from_base64(general_purpose::STANDARD.encode(output)))
+    }
+
+    // Shannon entropy of the grayscale histogram in bits, low for flat placeholder images
+    pub fn entropy(&self) -> Result<f64> {
+        let gray = image::load_from_memory(&self.bytes())?.to_luma8();
+        let mut histogram = [0u64; 256];
+        for pixel in gray.pixels() {
+            histogram[pixel.0[0] as usize] += 1;
+        }
+
+        let total = gray.pixels().len() as f64;
+        if total == 0.0 {
+            return Ok(0.0);
+        }
+
+        Ok(histogram
+            .iter()
+            .filter(|count| **count > 0)
+            .map(|count| {
+                let p = *count as f64 / total;
+                -p * p.log2()
+            })
+            .sum())
+    }
+
+    // Create Image from PNG bytes
+    fn from_png_bytes(bytes: Vec<u8>) -> Self {
+        Self::from_base64(general_purpose::STANDARD.encode(bytes))
+    }
+}
+
+// Check whether the bytes look like an SVG document
+fn is_svg(bytes: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(512)]);
+    let head = head.trim_start_matches('\u{feff}').trim_start();
+
+    head.starts_with("<svg") || (head.starts_with("<?xml") && head.contains("<svg"))
+}
+
+// Render an SVG document to PNG bytes at its intrinsic size
+fn rasterize_svg(bytes: &[u8]) -> Result<Vec<u8>> {
+    // Documents come from users, so never load the images they reference, e.g. local files
+    let options = usvg::Options {
+        image_href_resolver: usvg::ImageHrefResolver {
+            resolve_data: Box::new(|_, _, _| None),
+            resolve_string: Box::new(|_, _| None),
+        },
+        ..usvg::Options::default()
+    };
+    let tree = usvg::Tree::from_data(bytes, &options)?;
+    let size = tree.size().to_int_size();
+
+    // The declared size is up to the document, check it before allocating the canvas
+    if size.width() as u64 * size.height() as u64 > MAX_SVG_PIXELS {
+        return Err(anyhow!("SVG canvas of {}x{} is too large", size.width(), size.height()));
+    }
+    let mut pixmap =
+        tiny_skia::Pixmap::new(size.width(), size.height()).ok_or_else(|| anyhow!("SVG has an empty canvas"))?;
+
+    resvg::render(&tree, tiny_skia::Transform::default(), &mut pixmap.as_mut());
+    Ok(pixmap.encode_png()?)
 }
 
 // Structure for image generation request
@@ -76,3 +189,56 @@ pub trait ImageGenerator {
     // Create image from request parameters
     fn create_image(&self, request: ImageRequest) -> Result<Image>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // SVG document of the given size and content
+    fn svg(width: u32, height: u32, content: &str) -> String {
+        format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}">{}</svg>"#,
+            width, height, content
+        )
+    }
+
+    // Color of the center pixel of a rasterized SVG
+    fn center_pixel(document: &str) -> image::Rgba<u8> {
+        let png = rasterize_svg(document.as_bytes()).unwrap();
+        let decoded = image::load_from_memory(&png).unwrap().to_rgba8();
+        *decoded.get_pixel(decoded.width() / 2, decoded.height() / 2)
+    }
+
+    #[test]
+    fn svg_renders_at_its_declared_size() {
+        let svg = svg(40, 20, r#"<rect width="40" height="20" fill="red"/>"#);
+
+        let png = rasterize_svg(svg.as_bytes()).unwrap();
+        let decoded = image::load_from_memory(&png).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (40, 20));
+        assert_eq!(center_pixel(&svg), image::Rgba([255, 0, 0, 255]));
+    }
+
+    #[test]
+    fn svg_never_loads_referenced_files() {
+        let path = std::env::temp_dir().join(format!("clara-svg-href-{}.png", std::process::id()));
+        image::RgbaImage::from_pixel(8, 8, image::Rgba([0, 0, 255, 255]))
+            .save(&path)
+            .unwrap();
+
+        for href in [format!("file://{}", path.display()), path.display().to_string()] {
+            let svg = svg(8, 8, &format!(r#"<image href="{}" width="8" height="8"/>"#, href));
+            assert_eq!(center_pixel(&svg), image::Rgba([0, 0, 0, 0]), "{}", href);
+        }
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn oversized_svg_canvas_is_refused() {
+        let svg = svg(100_000, 100_000, "");
+
+        let err = rasterize_svg(svg.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("too large"));
+    }
+}
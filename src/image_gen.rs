@@ -1,19 +1,20 @@
-// Import local modules for HTTP client and image handling
+// Import local modules for HTTP client, image handling and secrets
 use crate::{
     http_client::HttpClient,
     image::{Image, ImageGenerator, ImageRequest},
+    secrets::secrets,
 };
 // Import error handling and logging
 use anyhow::Result;
 use log::error;
 // Import serialization traits
 use serde::{Deserialize, Serialize};
-// Import JSON macro and environment handling
+// Import JSON macro and process handling
 use ureq::json;
-use std::{env, process};
+use std::process;
 
 // OpenAI API endpoint for image generation
-const OPENAI_IMAGE_GEN_URL: &'static str = "https://api.openai.com/v1/images/generations";
+const OPENAI_IMAGE_GEN_URL: &str = "https://api.openai.com/v1/images/generations";
 
 // Structure to hold OpenAI API response for image generation
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -42,8 +43,8 @@ pub struct ImageGen {
 impl ImageGen {
     // Initialize new image generation client
     pub fn new() -> Result<Self> {
-        // Get OpenAI API key from environment variables
-        let key = env::var("OPENAI_API_KEY").unwrap_or_else(|err| {
+        // Get OpenAI API key from the secrets provider
+        let key = secrets().get("OPENAI_API_KEY").unwrap_or_else(|err| {
             error!("Missing OPENAI_API_KEY {}", err);
             process::exit(1);
         });
@@ -0,0 +1,306 @@
+// Import synchronization handling
+use std::sync::{Mutex, MutexGuard};
+
+// Import date handling
+use chrono::Utc;
+// Import error handling
+use anyhow::{anyhow, Result};
+// Import SQLite bindings
+use rusqlite::{params, Connection, OptionalExtension, Row};
+// Import serialization traits
+use serde::{Deserialize, Serialize};
+
+// Import storage for migrating processed tweet IDs
+use crate::storage::Storage;
+
+// Processing state of a mention
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum MentionStatus {
+    // Picked up, not finished yet (or interrupted)
+    Pending,
+    // Handled, including polite refusals and skips
+    Completed,
+    // Handling failed, retried on the next iteration
+    Failed,
+}
+
+// Structure for a ledger entry of one mention
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MentionRecord {
+    // Tweet ID of the mention
+    pub tweet_id: String,
+    // Handle of the user who mentioned the bot
+    pub user: Option<String>,
+    // Processing state
+    pub status: MentionStatus,
+    // Unix timestamp of when the mention was first seen
+    pub created_at: i64,
+    // Unix timestamp of the last status change
+    pub updated_at: i64,
+    // Reference to the result, e.g. the generated image path
+    pub result: Option<String>,
+    // Error of the last failed attempt
+    pub error: Option<String>,
+}
+
+// Persistent ledger of processed mentions backed by SQLite
+pub struct Ledger {
+    // Open database connection, locked per statement so workers can share it
+    conn: Mutex<Connection>,
+}
+
+impl MentionStatus {
+    // Name stored in the database
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MentionStatus::Pending => "pending",
+            MentionStatus::Completed => "completed",
+            MentionStatus::Failed => "failed",
+        }
+    }
+
+    // Parse a name stored in the database
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "pending" => Ok(MentionStatus::Pending),
+            "completed" => Ok(MentionStatus::Completed),
+            "failed" => Ok(MentionStatus::Failed),
+            other => Err(anyhow!("Unknown mention status {}", other)),
+        }
+    }
+}
+
+impl Ledger {
+    // Open the ledger database, creating it if it doesn't exist
+    pub fn open(path: &str) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS mentions (
+                tweet_id TEXT PRIMARY KEY,
+                user TEXT,
+                status TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                updated_at INTEGER NOT NULL,
+                result TEXT,
+                error TEXT
+            );
+            CREATE INDEX IF NOT EXISTS mentions_status ON mentions (status);
+            CREATE TABLE IF NOT EXISTS checkpoints (
+                tweet_id TEXT PRIMARY KEY,
+                data TEXT NOT NULL,
+                updated_at INTEGER NOT NULL
+            );",
+        )?;
+
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    // Record tweet IDs from JSON storage as completed, keeping entries already in the ledger
+    pub fn import_storage(&self, storage: &Storage) -> Result<usize> {
+        let now = Utc::now().timestamp();
+        let mut imported = 0;
+        for tweet_id in storage.items() {
+            imported += self.conn().execute(
+                "INSERT OR IGNORE INTO mentions (tweet_id, status, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?3)",
+                params![tweet_id, MentionStatus::Completed.as_str(), now],
+            )?;
+        }
+
+        Ok(imported)
+    }
+
+    // Get the entry of a mention
+    pub fn get(&self, tweet_id: &str) -> Result<Option<MentionRecord>> {
+        self.conn()
+            .query_row(
+                "SELECT tweet_id, user, status, created_at, updated_at, result, error
+                 FROM mentions WHERE tweet_id = ?1",
+                params![tweet_id],
+                |row| Ok(Self::record(row)),
+            )
+            .optional()?
+            .transpose()
+    }
+
+    // Check whether a mention was already handled
+    pub fn is_completed(&self, tweet_id: &str) -> Result<bool> {
+        Ok(self
+            .get(tweet_id)?
+            .is_some_and(|record| record.status == MentionStatus::Completed))
+    }
+
+    // Mark a mention as being handled
+    pub fn mark_pending(&self, tweet_id: &str, user: Option<&str>) -> Result<()> {
+        let now = Utc::now().timestamp();
+        self.conn().execute(
+            "INSERT INTO mentions (tweet_id, user, status, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?4)
+             ON CONFLICT (tweet_id) DO UPDATE SET status = ?3, updated_at = ?4",
+            params![tweet_id, user, MentionStatus::Pending.as_str(), now],
+        )?;
+
+        Ok(())
+    }
+
+    // Mark a mention as handled, with an optional reference to its result. Its checkpoint is no longer needed
+    pub fn mark_completed(&self, tweet_id: &str, result: Option<&str>) -> Result<()> {
+        self.update(tweet_id, MentionStatus::Completed, result, None)?;
+        self.conn()
+            .execute("DELETE FROM checkpoints WHERE tweet_id = ?1", params![tweet_id])?;
+
+        Ok(())
+    }
+
+    // Work done on an unfinished mention so far, as saved by the handler
+    pub fn checkpoint(&self, tweet_id: &str) -> Result<Option<String>> {
+        Ok(self
+            .conn()
+            .query_row(
+                "SELECT data FROM checkpoints WHERE tweet_id = ?1",
+                params![tweet_id],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    // Save the work done on a mention so far, replacing the previous checkpoint
+    pub fn save_checkpoint(&self, tweet_id: &str, data: &str) -> Result<()> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO checkpoints (tweet_id, data, updated_at) VALUES (?1, ?2, ?3)",
+            params![tweet_id, data, Utc::now().timestamp()],
+        )?;
+
+        Ok(())
+    }
+
+    // Mark a mention as failed with the error that stopped it
+    pub fn mark_failed(&self, tweet_id: &str, error: &str) -> Result<()> {
+        self.update(tweet_id, MentionStatus::Failed, None, Some(error))
+    }
+
+    // List mentions in a given state, oldest first
+    pub fn by_status(&self, status: MentionStatus) -> Result<Vec<MentionRecord>> {
+        let conn = self.conn();
+        let mut statement = conn.prepare(
+            "SELECT tweet_id, user, status, created_at, updated_at, result, error
+             FROM mentions WHERE status = ?1 ORDER BY created_at",
+        )?;
+        let records = statement
+            .query_map(params![status.as_str()], |row| Ok(Self::record(row)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        records.into_iter().collect()
+    }
+
+    // Forget handled mentions last updated before a Unix timestamp, returning how many were removed. Failed and
+    // pending mentions are kept so they are still retried, only their checkpoints that old are dropped
+    pub fn prune_completed(&self, before: i64) -> Result<usize> {
+        let removed = self.conn().execute(
+            "DELETE FROM mentions WHERE status = ?1 AND updated_at < ?2",
+            params![MentionStatus::Completed.as_str(), before],
+        )?;
+        self.conn()
+            .execute("DELETE FROM checkpoints WHERE updated_at < ?1", params![before])?;
+
+        Ok(removed)
+    }
+
+    // Change the state of a mention, creating its entry if needed
+    fn update(&self, tweet_id: &str, status: MentionStatus, result: Option<&str>, error: Option<&str>) -> Result<()> {
+        let now = Utc::now().timestamp();
+        self.conn().execute(
+            "INSERT INTO mentions (tweet_id, status, created_at, updated_at, result, error)
+             VALUES (?1, ?2, ?3, ?3, ?4, ?5)
+             ON CONFLICT (tweet_id) DO UPDATE SET status = ?2, updated_at = ?3, result = ?4, error = ?5",
+            params![tweet_id, status.as_str(), now, result, error],
+        )?;
+
+        Ok(())
+    }
+
+    // Lock the database connection
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    // Read a mention entry from a result row
+    fn record(row: &Row) -> Result<MentionRecord> {
+        let status: String = row.get(2)?;
+
+        Ok(MentionRecord {
+            tweet_id: row.get(0)?,
+            user: row.get(1)?,
+            status: MentionStatus::parse(&status)?,
+            created_at: row.get(3)?,
+            updated_at: row.get(4)?,
+            result: row.get(5)?,
+            error: row.get(6)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ledger() -> Ledger {
+        Ledger::open(":memory:").unwrap()
+    }
+
+    fn status(ledger: &Ledger, tweet_id: &str) -> Option<MentionStatus> {
+        ledger.get(tweet_id).unwrap().map(|record| record.status)
+    }
+
+    #[test]
+    fn mention_moves_through_its_states() {
+        let ledger = ledger();
+        assert_eq!(status(&ledger, "1"), None);
+
+        ledger.mark_pending("1", Some("alice")).unwrap();
+        assert_eq!(status(&ledger, "1"), Some(MentionStatus::Pending));
+
+        ledger.mark_failed("1", "timeout").unwrap();
+        let record = ledger.get("1").unwrap().unwrap();
+        assert_eq!(record.status, MentionStatus::Failed);
+        assert_eq!(record.error.as_deref(), Some("timeout"));
+
+        ledger.mark_completed("1", Some("cat.png")).unwrap();
+        let record = ledger.get("1").unwrap().unwrap();
+        assert!(ledger.is_completed("1").unwrap());
+        assert_eq!(record.user.as_deref(), Some("alice"));
+        assert_eq!(record.result.as_deref(), Some("cat.png"));
+        assert_eq!(record.error, None);
+    }
+
+    #[test]
+    fn checkpoint_is_replaced_and_dropped_on_completion() {
+        let ledger = ledger();
+        assert_eq!(ledger.checkpoint("1").unwrap(), None);
+
+        ledger.save_checkpoint("1", r#"{"step":1}"#).unwrap();
+        ledger.save_checkpoint("1", r#"{"step":2}"#).unwrap();
+        assert_eq!(ledger.checkpoint("1").unwrap().as_deref(), Some(r#"{"step":2}"#));
+
+        ledger.mark_completed("1", None).unwrap();
+        assert_eq!(ledger.checkpoint("1").unwrap(), None);
+    }
+
+    #[test]
+    fn pruning_keeps_unfinished_mentions() {
+        let ledger = ledger();
+        ledger.mark_completed("done", None).unwrap();
+        ledger.mark_failed("failed", "down").unwrap();
+        ledger.mark_pending("pending", None).unwrap();
+        ledger.save_checkpoint("failed", "{}").unwrap();
+
+        let removed = ledger.prune_completed(Utc::now().timestamp() + 1).unwrap();
+
+        assert_eq!(removed, 1);
+        assert_eq!(status(&ledger, "done"), None);
+        assert_eq!(status(&ledger, "failed"), Some(MentionStatus::Failed));
+        assert_eq!(status(&ledger, "pending"), Some(MentionStatus::Pending));
+        assert_eq!(ledger.checkpoint("failed").unwrap(), None);
+        assert_eq!(ledger.by_status(MentionStatus::Failed).unwrap().len(), 1);
+    }
+}
@@ -5,4 +5,31 @@ pub mod utils;
 pub mod vision;
 pub mod twitter;
 pub mod handler;
-pub mod storage;
\ No newline at end of file
+pub mod storage;
+pub mod aws;
+pub mod rekognition;
+pub mod theme;
+pub mod embedding;
+pub mod downloader;
+pub mod metrics;
+pub mod ledger;
+pub mod queue;
+pub mod admin;
+pub mod workers;
+pub mod config;
+pub mod secrets;
+pub mod prefs;
+pub mod logging;
+pub mod breaker;
+pub mod clock;
+pub mod cost;
+pub mod audit;
+pub mod openai_client;
+pub mod bot;
+pub mod health;
+pub mod dashboard;
+pub mod api;
+pub mod grpc;
+pub mod events;
+pub mod source;
+pub mod supervisor;
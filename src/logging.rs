@@ -0,0 +1,52 @@
+// Import error handling
+use anyhow::{anyhow, Result};
+// Import log formatting and filtering
+use tracing_subscriber::{fmt::format::FmtSpan, EnvFilter};
+
+// Import settings
+use crate::config;
+
+// Filter used when RUST_LOG is not set
+const DEFAULT_LOG_FILTER: &str = "info";
+
+// How log records are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    // One JSON object per record, with the fields of every enclosing span
+    Json,
+    // Human-readable lines for local runs
+    Text,
+}
+
+impl LogFormat {
+    // Read the format from LOG_FORMAT (json by default)
+    pub fn from_env() -> Result<Self> {
+        let format = config::var("LOG_FORMAT").unwrap_or_default();
+
+        match format.as_str() {
+            "" | "json" => Ok(LogFormat::Json),
+            "text" => Ok(LogFormat::Text),
+            other => Err(anyhow!("Unknown LOG_FORMAT {}", other)),
+        }
+    }
+}
+
+// Install the process-wide logger. Records from the log crate are forwarded too, and closing a span, e.g. a
+// mention or one of its stages, writes a record with how long it took
+pub fn init_logging() -> Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_LOG_FILTER));
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_span_events(FmtSpan::CLOSE);
+
+    match LogFormat::from_env()? {
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .flatten_event(true)
+            .try_init(),
+        LogFormat::Text => builder.try_init(),
+    }
+    .map_err(|err| anyhow!("Cannot initialize logging: {}", err))
+}
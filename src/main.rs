@@ -1,35 +1,52 @@
-// Import Duration from the standard time module
-use std::time::Duration;
+// Import environment from the standard modules
+use std::env;
 
-// Import the Handler struct and Storage from clara module
-use clara::{handler::Handler, storage::Storage};
-// Import sleep function from tokio's time module
-use tokio::time::sleep;
-
-// File path for persistent storage
-const STORAGE_FILE: &'static str = "storage.json";
+// Import the admin commands, bot, config, logging and secrets from clara module
+use clara::{
+    admin,
+    bot::{self, QUEUE_FILE},
+    config::{AppConfig, ConfigWatcher},
+    logging::init_logging,
+    secrets::{create_secrets_provider, set_secrets_provider},
+};
 
 // Main async function using tokio runtime
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     // Load environment variables from .env file
     dotenv::dotenv().ok();
-    // Initialize the environment logger
-    env_logger::init();
-
-    // Load processed tweets from storage file
-    let storage = Storage::load_from_file(STORAGE_FILE)?;
-
-    // Create a new instance of Handler with storage
-    let mut handler = Handler::new(storage).await?;
-
-    // Infinite loop to continuously process tweets
-    loop {
-        // Print status message for each iteration
-        println!("Starting a new iteration...");
-        // Process tweets using the handler
-        handler.process_tweets().await?;
-        // Sleep for 2 minutes before next iteration
-        sleep(Duration::from_secs(2 * 60)).await;
+    // Fill in settings from the config file that the environment doesn't set
+    AppConfig::load()?.apply();
+    // Initialize structured logging
+    init_logging()?;
+    // Run an admin command instead of the bot when one is given
+    let args: Vec<String> = env::args().skip(1).collect();
+    let serve = args == ["serve"];
+    let command = args.first().map(String::as_str);
+    let replay = command == Some("replay");
+    let backfill = command == Some("backfill");
+    if !args.is_empty() && !serve && !replay && !backfill {
+        return admin::run(&args, QUEUE_FILE);
     }
+    // Look up credentials with the configured secrets provider
+    set_secrets_provider(create_secrets_provider()?)?;
+    // Report every configuration problem before starting, serve mode doesn't need Twitter
+    AppConfig::validate(!serve)?;
+    // Serve generation requests instead of answering mentions in serve mode
+    if serve {
+        return bot::serve(bot::open_stores()?).await;
+    }
+    // Handle a single mention again and exit
+    if replay {
+        return admin::replay(&args[1..], bot::open_stores()?).await;
+    }
+    // Answer past mentions and exit
+    if backfill {
+        return admin::backfill(&args[1..], bot::open_stores()?).await;
+    }
+    // Watch the config file to apply changes without a restart
+    let watcher = ConfigWatcher::from_env()?;
+
+    // Open the stores and handle mentions, restarting stopped tasks
+    bot::run(bot::open_stores()?, watcher).await
 }
@@ -0,0 +1,153 @@
+// Import collections, synchronization and time handling
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Mutex, OnceLock},
+    time::Duration,
+};
+
+// Import latency histograms
+use hdrhistogram::Histogram;
+// Import serialization traits
+use serde::{Deserialize, Serialize};
+
+// Process-wide metrics instance
+static METRICS: OnceLock<AppMetrics> = OnceLock::new();
+
+// Longest duration told apart in histograms, longer ones count as this, one hour in milliseconds
+const MAX_TRACKED_MS: u64 = 60 * 60 * 1000;
+// Significant decimal digits kept by histograms, 3 keeps values within 0.1%
+const HISTOGRAM_PRECISION: u8 = 3;
+
+// Summary of one timed operation
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TimingSummary {
+    pub count: u64,
+    pub p50_ms: u64,
+    pub p95_ms: u64,
+    pub p99_ms: u64,
+    pub max_ms: u64,
+}
+
+// Point-in-time copy of all metrics, sorted by name
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct MetricsSnapshot {
+    pub counters: BTreeMap<String, u64>,
+    pub timings: BTreeMap<String, TimingSummary>,
+}
+
+// Counters and timings collected while processing mentions
+#[derive(Debug, Default)]
+pub struct AppMetrics {
+    counters: Mutex<HashMap<String, u64>>,
+    timings: Mutex<HashMap<String, Histogram<u64>>>,
+}
+
+// Get the process-wide metrics
+pub fn metrics() -> &'static AppMetrics {
+    METRICS.get_or_init(AppMetrics::default)
+}
+
+impl AppMetrics {
+    // Add to a counter, e.g. "vision.images.google"
+    pub fn increment(&self, name: &str, by: u64) {
+        *self.counters.lock().unwrap().entry(name.to_string()).or_default() += by;
+    }
+
+    // Record how long an operation took, e.g. "vision.latency.google" or "stage.post"
+    pub fn record_duration(&self, name: &str, duration: Duration) {
+        let ms = duration.as_millis() as u64;
+        let mut timings = self.timings.lock().unwrap();
+        let histogram = timings.entry(name.to_string()).or_insert_with(|| {
+            Histogram::new_with_bounds(1, MAX_TRACKED_MS, HISTOGRAM_PRECISION).expect("valid histogram bounds")
+        });
+        histogram.saturating_record(ms);
+    }
+
+    // Copy the current values
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let counters = self
+            .counters
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, value)| (name.clone(), *value))
+            .collect();
+        let timings = self
+            .timings
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, histogram)| {
+                let summary = TimingSummary {
+                    count: histogram.len(),
+                    p50_ms: histogram.value_at_quantile(0.5),
+                    p95_ms: histogram.value_at_quantile(0.95),
+                    p99_ms: histogram.value_at_quantile(0.99),
+                    max_ms: histogram.max(),
+                };
+                (name.clone(), summary)
+            })
+            .collect();
+
+        MetricsSnapshot { counters, timings }
+    }
+
+    // One-line summary for logs, e.g. "vision.images.google=3 vision.latency.google=p50 420ms p95 850ms p99 900ms
+    // max 900ms"
+    pub fn summary(&self) -> String {
+        let snapshot = self.snapshot();
+        let counters = snapshot
+            .counters
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value));
+        let timings = snapshot.timings.iter().map(|(name, timing)| {
+            format!(
+                "{}=p50 {}ms p95 {}ms p99 {}ms max {}ms",
+                name, timing.p50_ms, timing.p95_ms, timing.p99_ms, timing.max_ms
+            )
+        });
+
+        counters.chain(timings).collect::<Vec<_>>().join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_follow_the_recorded_latencies() {
+        let metrics = AppMetrics::default();
+        for ms in 1..=1000 {
+            metrics.record_duration("stage.post", Duration::from_millis(ms));
+        }
+
+        let timing = metrics.snapshot().timings["stage.post"].clone();
+
+        let expected = TimingSummary {
+            count: 1000,
+            p50_ms: 500,
+            p95_ms: 950,
+            p99_ms: 990,
+            max_ms: 1000,
+        };
+        assert_eq!(timing, expected);
+        assert_eq!(metrics.summary(), "stage.post=p50 500ms p95 950ms p99 990ms max 1000ms");
+    }
+
+    #[test]
+    fn durations_past_the_histogram_count_as_its_maximum() {
+        let metrics = AppMetrics::default();
+        metrics.record_duration("stage.image", Duration::from_millis(200));
+        metrics.record_duration("stage.image", Duration::from_secs(3 * 60 * 60));
+        metrics.increment("mentions.completed", 2);
+
+        let snapshot = metrics.snapshot();
+
+        let timing = &snapshot.timings["stage.image"];
+        assert_eq!((timing.count, timing.p50_ms), (2, 200));
+        // Histograms keep 3 significant digits, so the maximum is within 0.1%
+        assert!(timing.max_ms.abs_diff(MAX_TRACKED_MS) <= MAX_TRACKED_MS / 1000);
+        assert_eq!(snapshot.counters["mentions.completed"], 2);
+    }
+}
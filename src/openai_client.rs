@@ -0,0 +1,239 @@
+// Import future, synchronization and time handling
+use std::{
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// Import error handling
+use anyhow::{anyhow, Result};
+// Import serialization traits
+use serde::Deserialize;
+// Import async sleeping
+use tokio::time::sleep;
+// Import JSON macro
+use ureq::json;
+// Import logging
+use tracing::warn;
+
+// Import clock, HTTP client, metrics, secrets and rate limiting
+use crate::{
+    clock::{system_clock, Clock},
+    http_client::HttpClient,
+    metrics::metrics,
+    secrets::secrets,
+    utils::{blocking, rate_limit_from_env, retry_after, RateLimiter, RateStrategy, DEFAULT_OPENAI_RATE_LIMIT},
+};
+
+// Default OpenAI tokens per minute across all calls
+pub const DEFAULT_OPENAI_TOKEN_LIMIT: u32 = 10_000;
+// Rate limiter key of the shared account limits
+const ACCOUNT_KEY: &str = "openai";
+// OpenAI API endpoint for chat completions
+const OPENAI_CHAT_URL: &str = "https://api.openai.com/v1/chat/completions";
+
+// Structure to hold OpenAI API response for chat completions
+#[derive(Debug, Deserialize)]
+struct ChatCompletion {
+    // Generated answers, one unless more were asked for
+    choices: Vec<ChatChoice>,
+}
+
+// Structure to hold one generated answer
+#[derive(Debug, Deserialize)]
+struct ChatChoice {
+    // Message of the answer
+    message: ChatMessage,
+}
+
+// Structure to hold the message of an answer
+#[derive(Debug, Deserialize)]
+struct ChatMessage {
+    // Text of the message, missing when the model refused or called a tool
+    content: Option<String>,
+}
+
+// OpenAI client shared by every OpenAI call, keeping requests and tokens per minute under the account tier
+// limits and pausing all calls when OpenAI answers with a Retry-After
+pub struct OpenAiClient {
+    // Requests per minute, from OPENAI_RATE_LIMIT
+    requests: RateLimiter,
+    // Tokens per minute, from OPENAI_TOKEN_LIMIT
+    tokens: RateLimiter,
+    // Time until which OpenAI asked not to be called
+    paused_until: Mutex<Option<Instant>>,
+    // Time source for the limits and pauses
+    clock: Arc<dyn Clock>,
+}
+
+impl OpenAiClient {
+    // Create client with the limits from OPENAI_RATE_LIMIT and OPENAI_TOKEN_LIMIT
+    pub fn from_env() -> Result<Self> {
+        let (requests, tokens) = limits_from_env()?;
+
+        Ok(Self {
+            requests: RateLimiter::in_memory(requests),
+            tokens: RateLimiter::in_memory(tokens),
+            paused_until: Mutex::new(None),
+            clock: system_clock(),
+        })
+    }
+
+    // Use another time source for the limits and pauses, e.g. a mock clock in tests
+    pub fn with_clock(self, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            requests: self.requests.with_clock(clock.clone()),
+            tokens: self.tokens.with_clock(clock.clone()),
+            paused_until: self.paused_until,
+            clock,
+        }
+    }
+
+    // Re-read both limits, keeping the previous ones if either is invalid
+    pub fn reload(&mut self) -> Result<()> {
+        let (requests, tokens) = limits_from_env()?;
+        self.requests.set_strategy(requests);
+        self.tokens.set_strategy(tokens);
+
+        Ok(())
+    }
+
+    // Run a call once the limits allow one more request of about `estimated_tokens` tokens. A rate limit
+    // answer with a Retry-After pauses every call until then
+    pub async fn call<T, Fut>(&self, estimated_tokens: u32, op: impl FnOnce() -> Fut) -> Result<T>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        let paused_until = *self.paused_until.lock().unwrap();
+        if let Some(wait) = paused_until.map(|until| until.saturating_duration_since(self.clock.instant())) {
+            sleep(wait).await;
+        }
+        self.requests.acquire(ACCOUNT_KEY).await?;
+        self.tokens.acquire_many(ACCOUNT_KEY, estimated_tokens as f64).await?;
+
+        let result = op().await;
+        if let Some(wait) = result.as_ref().err().and_then(retry_after) {
+            warn!(retry_after_ms = wait.as_millis() as u64, "OpenAI rate limit reached");
+            metrics().increment("openai.throttled", 1);
+            self.pause(wait);
+        }
+
+        result
+    }
+
+    // Send a prompt to a chat model, e.g. "gpt-4", and get its answer. Run it through `call` with
+    // `prompt_tokens` to count it against the limits. Error responses keep their status and headers, so rate
+    // limits and server errors are retried and Retry-After pauses the client
+    pub async fn prompt(&self, model: &str, prompt: &str, max_response_tokens: u32) -> Result<String> {
+        let key = secrets().get("OPENAI_API_KEY")?;
+        let body = json!({
+            "model": model,
+            "messages": [{ "role": "user", "content": prompt }],
+            // OpenAI counts the response allowance against the token limit too
+            "max_tokens": max_response_tokens,
+        });
+
+        let response = blocking(move || HttpClient::new().post_with_auth(OPENAI_CHAT_URL, &key, body)).await?;
+        chat_answer(&response)
+    }
+
+    // Hold every call until `wait` from now, unless already held longer
+    fn pause(&self, wait: Duration) {
+        let until = self.clock.instant() + wait;
+        let mut paused_until = self.paused_until.lock().unwrap();
+        if paused_until.is_none_or(|current| current < until) {
+            *paused_until = Some(until);
+        }
+    }
+}
+
+// Rough token count of a text, about four characters per token for English
+pub fn estimate_tokens(text: &str) -> u32 {
+    (text.chars().count() / 4) as u32 + 1
+}
+
+// Tokens a prompt counts against the limit, including the allowance for the response
+pub fn prompt_tokens(prompt: &str, max_response_tokens: u32) -> u32 {
+    estimate_tokens(prompt) + max_response_tokens
+}
+
+// Text of the first answer of a chat completion response
+fn chat_answer(response: &str) -> Result<String> {
+    let completion: ChatCompletion = serde_json::from_str(response)?;
+
+    completion
+        .choices
+        .into_iter()
+        .next()
+        .and_then(|choice| choice.message.content)
+        .ok_or_else(|| anyhow!("OpenAI answered without a message"))
+}
+
+// Requests and tokens per minute strategies from the environment
+fn limits_from_env() -> Result<(RateStrategy, RateStrategy)> {
+    Ok((
+        RateStrategy::per_minute(rate_limit_from_env("OPENAI_RATE_LIMIT", DEFAULT_OPENAI_RATE_LIMIT)?),
+        RateStrategy::per_minute(rate_limit_from_env("OPENAI_TOKEN_LIMIT", DEFAULT_OPENAI_TOKEN_LIMIT)?),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+    use crate::clock::MockClock;
+
+    // Failed call answered with a rate limit asking to wait the given number of seconds
+    async fn rate_limited(secs: u32) -> Result<()> {
+        let response: ureq::Response = format!("HTTP/1.1 429 Too Many Requests\r\nRetry-After: {}\r\n\r\n", secs)
+            .parse()
+            .unwrap();
+        Err(ureq::Error::Status(429, response).into())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn rate_limit_pauses_every_call_until_retry_after() {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let client = OpenAiClient::from_env().unwrap().with_clock(clock.clone());
+
+        assert!(client.call(1, || rate_limited(30)).await.is_err());
+
+        // The next call waits out the pause
+        let started = tokio::time::Instant::now();
+        client.call(1, || async { Ok(()) }).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(30));
+
+        // Once the clock is past the pause, calls go through right away
+        clock.advance(Duration::from_secs(30));
+        let started = tokio::time::Instant::now();
+        client.call(1, || async { Ok(()) }).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn shorter_retry_after_keeps_the_longer_pause() {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        let client = OpenAiClient::from_env().unwrap().with_clock(clock);
+
+        assert!(client.call(1, || rate_limited(60)).await.is_err());
+        assert!(client.call(1, || rate_limited(5)).await.is_err());
+
+        let started = tokio::time::Instant::now();
+        client.call(1, || async { Ok(()) }).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn chat_answer_is_the_first_message() {
+        let response = r#"{"choices":[{"message":{"role":"assistant","content":"A cat in a hat"}}]}"#;
+
+        assert_eq!(chat_answer(response).unwrap(), "A cat in a hat");
+    }
+
+    #[test]
+    fn chat_answer_without_a_message_fails() {
+        assert!(chat_answer(r#"{"choices":[]}"#).is_err());
+        assert!(chat_answer(r#"{"choices":[{"message":{"role":"assistant","content":null}}]}"#).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+// Import serialization traits
+use serde::{Deserialize, Serialize};
+// Import JSON handling utilities
+use serde_json::{self, Error};
+// Import collections and file handling
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, BufWriter},
+};
+
+// Import keyword sanitizing for user-provided styles
+use crate::utils::sanitize_keyword;
+
+// Values that reset a preference to its default
+const RESET_VALUES: &[&str] = &["none", "default", "off", "reset"];
+
+// Structure for the settings a user chose through mention commands
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct UserPrefs {
+    // Art style added to the image prompt, e.g. "watercolor"
+    #[serde(default)]
+    pub style: Option<String>,
+    // Whether the user agreed to have their images shown in a gallery
+    #[serde(default)]
+    pub gallery: bool,
+}
+
+// Structure for persistent storage of user preferences
+#[derive(Serialize, Deserialize)]
+pub struct PreferenceStore {
+    // Path to storage file
+    file_path: String,
+    // Preferences keyed by platform and user ID, e.g. "twitter:12345"
+    users: HashMap<String, UserPrefs>,
+}
+
+impl PreferenceStore {
+    // Load storage from file, create new if file doesn't exist
+    pub fn load_from_file(file_path: &str) -> Result<Self, Error> {
+        // Open existing file or create new one
+        let file = File::open(file_path).unwrap_or_else(|_| File::create(file_path).unwrap());
+        let reader = BufReader::new(file);
+        // Try to deserialize existing data or create empty storage
+        serde_json::from_reader(reader).or_else(|_| {
+            Ok(PreferenceStore {
+                file_path: file_path.to_string(),
+                users: HashMap::new(),
+            })
+        })
+    }
+
+    // Save current storage state to file
+    pub fn save_to_file(&self) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.file_path)?;
+        let writer = BufWriter::new(file);
+        serde_json::to_writer(writer, &self).map_err(io::Error::other)
+    }
+
+    // Get a user's preferences, defaults when they never set any
+    pub fn get(&self, platform: &str, user_id: &str) -> UserPrefs {
+        self.users
+            .get(&format!("{}:{}", platform, user_id))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // Replace a user's preferences
+    pub fn set(&mut self, platform: &str, user_id: &str, prefs: UserPrefs) {
+        self.users.insert(format!("{}:{}", platform, user_id), prefs);
+    }
+}
+
+impl UserPrefs {
+    // Apply commands such as "style: watercolor" or "gallery: yes" found in a mention, returning whether
+    // anything changed
+    pub fn apply_commands(&mut self, text: &str) -> bool {
+        let before = self.clone();
+
+        for segment in text.split(['\n', ',', ';']) {
+            let segment = segment.to_lowercase();
+
+            if let Some(value) = command_value(&segment, "style:") {
+                // Styles end up in the image prompt, so they get the same treatment as vision labels
+                self.style = match value {
+                    value if RESET_VALUES.contains(&value) => None,
+                    value => sanitize_keyword(value).or(self.style.take()),
+                };
+            }
+
+            if let Some(value) = command_value(&segment, "gallery:") {
+                match value {
+                    "yes" | "on" | "true" => self.gallery = true,
+                    "no" | "off" | "false" => self.gallery = false,
+                    _ => {}
+                }
+            }
+        }
+
+        *self != before
+    }
+}
+
+// Get the trimmed text following a command name in a segment
+fn command_value<'a>(segment: &'a str, command: &str) -> Option<&'a str> {
+    segment
+        .find(command)
+        .map(|start| segment[start + command.len()..].trim())
+        .filter(|value| !value.is_empty())
+}
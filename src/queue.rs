@@ -0,0 +1,449 @@
+// Import synchronization and time handling
+use std::{
+    sync::{Mutex, MutexGuard},
+    time::Duration,
+};
+
+// Import date handling
+use chrono::Utc;
+// Import error handling
+use anyhow::{anyhow, Result};
+// Import SQLite bindings
+use rusqlite::{params, Connection, Row};
+
+// Import settings and the mention type kept in the queue
+use crate::{config, twitter::ExtractedTweet};
+
+// Default attempts at a mention before it is moved to the dead letters
+pub const DEFAULT_MENTION_MAX_ATTEMPTS: u32 = 4;
+
+// When failed mentions are tried again and when they are given up on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequeuePolicy {
+    // Attempts in total, including the first one
+    pub max_attempts: u32,
+    // Wait before the second attempt, doubled for every further one
+    pub base_delay: Duration,
+    // Longest wait between attempts
+    pub max_delay: Duration,
+}
+
+// What happened to a failed mention
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Requeued {
+    // Scheduled again after a delay
+    Retrying { attempt: u32, delay: Duration },
+    // Out of attempts and moved to the dead letters
+    Dead,
+}
+
+// Mention that failed every attempt
+#[derive(Debug)]
+pub struct DeadLetter {
+    // Tweet ID of the mention
+    pub tweet_id: String,
+    // The mention itself
+    pub tweet: ExtractedTweet,
+    // Attempts made
+    pub attempts: u32,
+    // Error of the last attempt
+    pub error: Option<String>,
+    // Unix timestamp of when it was given up on
+    pub failed_at: i64,
+}
+
+// Durable queue of accepted mentions backed by SQLite. Mentions stay queued until handled, so a crash or deploy
+// mid-burst picks them up again on the next start
+pub struct MentionQueue {
+    // Open database connection, locked per statement so workers can share it
+    conn: Mutex<Connection>,
+}
+
+impl Default for RequeuePolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: DEFAULT_MENTION_MAX_ATTEMPTS,
+            base_delay: Duration::from_secs(5 * 60),
+            max_delay: Duration::from_secs(2 * 60 * 60),
+        }
+    }
+}
+
+impl RequeuePolicy {
+    // Read the attempts from MENTION_MAX_ATTEMPTS, keeping the default schedule
+    pub fn from_env() -> Result<Self> {
+        let max_attempts = match config::var("MENTION_MAX_ATTEMPTS") {
+            Ok(value) if !value.is_empty() => value
+                .parse()
+                .map_err(|err| anyhow!("MENTION_MAX_ATTEMPTS must be a whole number: {}", err))?,
+            _ => DEFAULT_MENTION_MAX_ATTEMPTS,
+        };
+        if max_attempts == 0 {
+            return Err(anyhow!("MENTION_MAX_ATTEMPTS must be at least 1"));
+        }
+
+        Ok(Self {
+            max_attempts,
+            ..Self::default()
+        })
+    }
+
+    // Wait after a failed attempt, counted from 1
+    fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+impl MentionQueue {
+    // Open the queue database, creating it if it doesn't exist
+    pub fn open(path: &str) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS queue (
+                tweet_id TEXT PRIMARY KEY,
+                tweet TEXT NOT NULL,
+                enqueued_at INTEGER NOT NULL,
+                attempts INTEGER NOT NULL DEFAULT 0,
+                next_attempt_at INTEGER NOT NULL DEFAULT 0,
+                error TEXT
+            );
+            CREATE TABLE IF NOT EXISTS dead_letters (
+                tweet_id TEXT PRIMARY KEY,
+                tweet TEXT NOT NULL,
+                attempts INTEGER NOT NULL,
+                error TEXT,
+                failed_at INTEGER NOT NULL
+            );",
+        )?;
+
+        // Queues created before retries were scheduled lack the retry columns
+        let columns = conn
+            .prepare("SELECT name FROM pragma_table_info('queue')")?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        if !columns.iter().any(|column| column == "attempts") {
+            conn.execute_batch(
+                "ALTER TABLE queue ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+                ALTER TABLE queue ADD COLUMN next_attempt_at INTEGER NOT NULL DEFAULT 0;
+                ALTER TABLE queue ADD COLUMN error TEXT;",
+            )?;
+        }
+
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    // Add a mention unless it is already queued, returning whether it was added
+    pub fn push(&self, tweet_id: &str, tweet: &ExtractedTweet) -> Result<bool> {
+        let added = self.conn().execute(
+            "INSERT OR IGNORE INTO queue (tweet_id, tweet, enqueued_at) VALUES (?1, ?2, ?3)",
+            params![tweet_id, serde_json::to_string(tweet)?, Utc::now().timestamp()],
+        )?;
+
+        Ok(added > 0)
+    }
+
+    // List queued mentions that are due, oldest first
+    pub fn due(&self) -> Result<Vec<ExtractedTweet>> {
+        let conn = self.conn();
+        let mut statement =
+            conn.prepare("SELECT tweet FROM queue WHERE next_attempt_at <= ?1 ORDER BY enqueued_at, rowid")?;
+        let tweets = statement
+            .query_map(params![Utc::now().timestamp()], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        tweets.iter().map(|tweet| Ok(serde_json::from_str(tweet)?)).collect()
+    }
+
+    // Take a mention off the queue once it is handled
+    pub fn remove(&self, tweet_id: &str) -> Result<()> {
+        self.conn()
+            .execute("DELETE FROM queue WHERE tweet_id = ?1", params![tweet_id])?;
+
+        Ok(())
+    }
+
+    // Put off a queued mention for a while without counting an attempt, e.g. while a cap is reached
+    pub fn defer(&self, tweet_id: &str, delay: Duration) -> Result<()> {
+        let next_attempt_at = Utc::now().timestamp() + delay.as_secs() as i64;
+        self.conn().execute(
+            "UPDATE queue SET next_attempt_at = ?2 WHERE tweet_id = ?1",
+            params![tweet_id, next_attempt_at],
+        )?;
+
+        Ok(())
+    }
+
+    // Schedule a failed mention again, or move it to the dead letters once it is out of attempts
+    pub fn retry_later(&self, tweet_id: &str, error: &str, policy: &RequeuePolicy) -> Result<Requeued> {
+        let attempts: u32 = self.conn().query_row(
+            "SELECT attempts FROM queue WHERE tweet_id = ?1",
+            params![tweet_id],
+            |row| row.get(0),
+        )?;
+        let attempt = attempts + 1;
+        let now = Utc::now().timestamp();
+
+        if attempt >= policy.max_attempts {
+            let mut conn = self.conn();
+            let tx = conn.transaction()?;
+            tx.execute(
+                "INSERT OR REPLACE INTO dead_letters (tweet_id, tweet, attempts, error, failed_at)
+                 SELECT tweet_id, tweet, ?2, ?3, ?4 FROM queue WHERE tweet_id = ?1",
+                params![tweet_id, attempt, error, now],
+            )?;
+            tx.execute("DELETE FROM queue WHERE tweet_id = ?1", params![tweet_id])?;
+            tx.commit()?;
+
+            return Ok(Requeued::Dead);
+        }
+
+        let delay = policy.delay(attempt);
+        self.conn().execute(
+            "UPDATE queue SET attempts = ?2, next_attempt_at = ?3, error = ?4 WHERE tweet_id = ?1",
+            params![tweet_id, attempt, now + delay.as_secs() as i64, error],
+        )?;
+
+        Ok(Requeued::Retrying { attempt, delay })
+    }
+
+    // List the dead letters, most recent first
+    pub fn dead_letters(&self) -> Result<Vec<DeadLetter>> {
+        let conn = self.conn();
+        let mut statement = conn
+            .prepare("SELECT tweet_id, tweet, attempts, error, failed_at FROM dead_letters ORDER BY failed_at DESC")?;
+        let letters = statement
+            .query_map([], |row| Ok(Self::dead_letter(row)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        letters.into_iter().collect()
+    }
+
+    // Check whether a mention was given up on
+    pub fn is_dead(&self, tweet_id: &str) -> Result<bool> {
+        let count: i64 = self.conn().query_row(
+            "SELECT COUNT(*) FROM dead_letters WHERE tweet_id = ?1",
+            params![tweet_id],
+            |row| row.get(0),
+        )?;
+
+        Ok(count > 0)
+    }
+
+    // Move a dead letter back into the queue with fresh attempts, returning whether it existed
+    pub fn revive(&self, tweet_id: &str) -> Result<bool> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO queue (tweet_id, tweet, enqueued_at)
+             SELECT tweet_id, tweet, ?2 FROM dead_letters WHERE tweet_id = ?1",
+            params![tweet_id, Utc::now().timestamp()],
+        )?;
+        let revived = tx.execute("DELETE FROM dead_letters WHERE tweet_id = ?1", params![tweet_id])?;
+        tx.commit()?;
+
+        Ok(revived > 0)
+    }
+
+    // Queue a mention with fresh attempts, taking it out of the dead letters or replacing it if already queued
+    pub fn requeue(&self, tweet_id: &str, tweet: &ExtractedTweet) -> Result<()> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM dead_letters WHERE tweet_id = ?1", params![tweet_id])?;
+        tx.execute(
+            "INSERT OR REPLACE INTO queue (tweet_id, tweet, enqueued_at) VALUES (?1, ?2, ?3)",
+            params![tweet_id, serde_json::to_string(tweet)?, Utc::now().timestamp()],
+        )?;
+        tx.commit()?;
+
+        Ok(())
+    }
+
+    // Drop a dead letter for good, returning whether it existed
+    pub fn discard(&self, tweet_id: &str) -> Result<bool> {
+        let discarded = self
+            .conn()
+            .execute("DELETE FROM dead_letters WHERE tweet_id = ?1", params![tweet_id])?;
+
+        Ok(discarded > 0)
+    }
+
+    // Number of queued mentions
+    pub fn len(&self) -> Result<usize> {
+        let count: i64 = self
+            .conn()
+            .query_row("SELECT COUNT(*) FROM queue", [], |row| row.get(0))?;
+
+        Ok(count as usize)
+    }
+
+    // Check whether nothing is queued
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    // Lock the database connection
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap()
+    }
+
+    // Read a dead letter from a result row
+    fn dead_letter(row: &Row) -> Result<DeadLetter> {
+        let tweet: String = row.get(1)?;
+
+        Ok(DeadLetter {
+            tweet_id: row.get(0)?,
+            tweet: serde_json::from_str(&tweet)?,
+            attempts: row.get(2)?,
+            error: row.get(3)?,
+            failed_at: row.get(4)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mention(id: &str) -> ExtractedTweet {
+        ExtractedTweet {
+            name: None,
+            username: Some("alice".to_string()),
+            user_id: Some("42".to_string()),
+            text: Some("@clara draw me".to_string()),
+            timestamp: None,
+            permanent_url: None,
+            id: Some(id.to_string()),
+            photos: Vec::new(),
+        }
+    }
+
+    fn queue_with(ids: &[&str]) -> MentionQueue {
+        let queue = MentionQueue::open(":memory:").unwrap();
+        for id in ids {
+            queue.push(id, &mention(id)).unwrap();
+        }
+        queue
+    }
+
+    fn due_ids(queue: &MentionQueue) -> Vec<String> {
+        queue.due().unwrap().into_iter().filter_map(|tweet| tweet.id).collect()
+    }
+
+    fn policy(max_attempts: u32) -> RequeuePolicy {
+        RequeuePolicy {
+            max_attempts,
+            ..RequeuePolicy::default()
+        }
+    }
+
+    #[test]
+    fn mentions_are_queued_once() {
+        let queue = queue_with(&["1"]);
+
+        assert!(!queue.push("1", &mention("1")).unwrap());
+        assert!(queue.push("2", &mention("2")).unwrap());
+        assert_eq!(queue.len().unwrap(), 2);
+    }
+
+    #[test]
+    fn due_mentions_come_oldest_first() {
+        let queue = queue_with(&["3", "1", "2"]);
+
+        assert_eq!(due_ids(&queue), ["3", "1", "2"]);
+
+        queue.remove("1").unwrap();
+        assert_eq!(due_ids(&queue), ["3", "2"]);
+    }
+
+    #[test]
+    fn deferred_mention_is_not_due() {
+        let queue = queue_with(&["1", "2"]);
+
+        queue.defer("1", Duration::from_secs(60 * 60)).unwrap();
+
+        assert_eq!(due_ids(&queue), ["2"]);
+        assert_eq!(queue.len().unwrap(), 2);
+    }
+
+    #[test]
+    fn failed_mention_is_retried_then_given_up_on() {
+        let queue = queue_with(&["1"]);
+        let policy = policy(3);
+
+        let first = queue.retry_later("1", "timeout", &policy).unwrap();
+        assert_eq!(
+            first,
+            Requeued::Retrying {
+                attempt: 1,
+                delay: policy.base_delay
+            }
+        );
+        assert!(due_ids(&queue).is_empty());
+
+        let second = queue.retry_later("1", "timeout", &policy).unwrap();
+        assert_eq!(
+            second,
+            Requeued::Retrying {
+                attempt: 2,
+                delay: policy.base_delay * 2
+            }
+        );
+
+        assert_eq!(queue.retry_later("1", "rate limited", &policy).unwrap(), Requeued::Dead);
+        assert!(queue.is_empty().unwrap());
+        assert!(queue.is_dead("1").unwrap());
+        let letters = queue.dead_letters().unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].attempts, 3);
+        assert_eq!(letters[0].error.as_deref(), Some("rate limited"));
+        assert_eq!(letters[0].tweet.id.as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn dead_letter_can_be_revived_or_discarded() {
+        let queue = queue_with(&["1", "2"]);
+        for id in ["1", "2"] {
+            queue.retry_later(id, "down", &policy(1)).unwrap();
+        }
+
+        assert!(queue.revive("1").unwrap());
+        assert!(!queue.revive("1").unwrap());
+        assert_eq!(due_ids(&queue), ["1"]);
+        assert!(!queue.is_dead("1").unwrap());
+
+        assert!(queue.discard("2").unwrap());
+        assert!(!queue.discard("2").unwrap());
+        assert!(queue.dead_letters().unwrap().is_empty());
+    }
+
+    #[test]
+    fn queue_without_retry_columns_is_migrated() {
+        let path = std::env::temp_dir().join(format!("clara-queue-migration-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_string_lossy().into_owned();
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE queue (tweet_id TEXT PRIMARY KEY, tweet TEXT NOT NULL, enqueued_at INTEGER NOT NULL);",
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO queue (tweet_id, tweet, enqueued_at) VALUES ('1', ?1, 0)",
+            params![serde_json::to_string(&mention("1")).unwrap()],
+        )
+        .unwrap();
+        drop(conn);
+
+        let queue = MentionQueue::open(&path).unwrap();
+
+        assert_eq!(due_ids(&queue), ["1"]);
+        assert!(matches!(
+            queue.retry_later("1", "down", &policy(2)).unwrap(),
+            Requeued::Retrying { attempt: 1, .. }
+        ));
+        drop(queue);
+        // Opening again finds the columns and leaves them alone
+        assert!(MentionQueue::open(&path).unwrap().due().unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
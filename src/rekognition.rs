@@ -0,0 +1,243 @@
+// Import required dependencies
+use anyhow::Result;
+// Import serialization traits
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+// Import JSON macro
+use ureq::json;
+
+// Import local modules
+use crate::{
+    aws::AwsClient,
+    image::Image,
+    vision::{DominantColor, Keyword, VisionReport, VisionRequest, VisionService},
+};
+
+// Service name used in the signing scope
+const REKOGNITION_SERVICE: &str = "rekognition";
+// Content type of the Rekognition JSON protocol
+const REKOGNITION_CONTENT_TYPE: &str = "application/x-amz-json-1.1";
+// Labels signalling a real human face, and the confidence required
+const FACE_LABELS: &[&str] = &["Face", "Person", "Human", "Portrait", "Selfie"];
+const FACE_MIN_CONFIDENCE: f64 = 80.0;
+// Confidence at which a moderation label flags the avatar
+const UNSAFE_MIN_CONFIDENCE: f64 = 70.0;
+
+// Response structure for DetectLabels
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DetectLabelsResponse {
+    #[serde(rename = "Labels", default)]
+    pub labels: Vec<RekognitionLabel>,
+    // Present when the IMAGE_PROPERTIES feature was requested
+    #[serde(rename = "ImageProperties", default)]
+    pub image_properties: Option<ImageProperties>,
+}
+
+// Structure for image properties of DetectLabels
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImageProperties {
+    #[serde(rename = "DominantColors", default)]
+    pub dominant_colors: Vec<RekognitionColor>,
+}
+
+// Structure for individual dominant color
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RekognitionColor {
+    #[serde(rename = "Red", default)]
+    pub red: f64,
+    #[serde(rename = "Green", default)]
+    pub green: f64,
+    #[serde(rename = "Blue", default)]
+    pub blue: f64,
+    // Share of the image covered by the color in percent
+    #[serde(rename = "PixelPercent", default)]
+    pub pixel_percent: f64,
+}
+
+// Structure for individual Rekognition label
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RekognitionLabel {
+    // Human-readable label name
+    #[serde(rename = "Name")]
+    pub name: String,
+    // Confidence score in percent
+    #[serde(rename = "Confidence")]
+    pub confidence: f64,
+}
+
+// Response structure for DetectModerationLabels
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DetectModerationLabelsResponse {
+    #[serde(rename = "ModerationLabels", default)]
+    pub moderation_labels: Vec<ModerationLabel>,
+}
+
+// Structure for individual moderation label
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ModerationLabel {
+    // Moderation category name
+    #[serde(rename = "Name")]
+    pub name: String,
+    // Confidence score in percent
+    #[serde(rename = "Confidence")]
+    pub confidence: f64,
+    // Top-level category, empty for top-level labels
+    #[serde(rename = "ParentName", default)]
+    pub parent_name: String,
+}
+
+// Response structure for DetectText
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DetectTextResponse {
+    #[serde(rename = "TextDetections", default)]
+    pub text_detections: Vec<TextDetection>,
+}
+
+// Structure for individual detected line or word
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TextDetection {
+    // Detected text
+    #[serde(rename = "DetectedText")]
+    pub detected_text: String,
+    // LINE or WORD
+    #[serde(rename = "Type")]
+    pub kind: String,
+}
+
+// Main AWS Rekognition client
+#[derive(Debug)]
+pub struct Rekognition {
+    // Signed AWS client
+    aws: AwsClient,
+}
+
+impl Rekognition {
+    // Initialize new Rekognition client
+    pub fn new() -> Result<Self> {
+        Ok(Self { aws: AwsClient::new()? })
+    }
+
+    // Detect unsafe content labels in the image
+    pub fn detect_moderation_labels(&self, image: &Image) -> Result<Vec<ModerationLabel>> {
+        let response = self.call(
+            "RekognitionService.DetectModerationLabels",
+            json!({
+                "Image": { "Bytes": image.base64 },
+                "MinConfidence": 50
+            }),
+        )?;
+
+        let response: DetectModerationLabelsResponse = serde_json::from_str(&response)?;
+        Ok(response.moderation_labels)
+    }
+
+    // Detect words of text in the image
+    pub fn detect_text(&self, image: &Image) -> Result<Vec<String>> {
+        let response = self.call(
+            "RekognitionService.DetectText",
+            json!({
+                "Image": { "Bytes": image.base64 }
+            }),
+        )?;
+
+        let response: DetectTextResponse = serde_json::from_str(&response)?;
+        Ok(response
+            .text_detections
+            .into_iter()
+            .filter(|detection| detection.kind == "WORD")
+            .map(|detection| detection.detected_text)
+            .collect())
+    }
+
+    // Detect labels, optionally with image properties
+    fn detect_labels(&self, request: &VisionRequest, features: &[&str]) -> Result<DetectLabelsResponse> {
+        let mut body = json!({
+            "Image": { "Bytes": request.image.base64 },
+            "MaxLabels": request.max_results
+        });
+        if !features.is_empty() {
+            body["Features"] = json!(features);
+        }
+
+        let response = self.call("RekognitionService.DetectLabels", body)?;
+        Ok(serde_json::from_str(&response)?)
+    }
+
+    // Make a signed call to a Rekognition action
+    fn call(&self, target: &str, body: Value) -> Result<String> {
+        self.aws
+            .call(REKOGNITION_SERVICE, REKOGNITION_CONTENT_TYPE, target, body)
+    }
+}
+
+// Implementation of VisionService trait for Rekognition
+impl VisionService for Rekognition {
+    fn name(&self) -> &str {
+        "rekognition"
+    }
+
+    // Generate image descriptions using DetectLabels
+    fn create_desc(&self, request: VisionRequest) -> Result<Vec<String>> {
+        let response = self.detect_labels(&request, &[])?;
+        Ok(sorted_keywords(&response.labels)
+            .into_iter()
+            .map(|keyword| keyword.label)
+            .collect())
+    }
+
+    // Generate image descriptions and dominant colors in a single DetectLabels call
+    fn create_report(&self, request: VisionRequest) -> Result<VisionReport> {
+        let response = self.detect_labels(&request, &["GENERAL_LABELS", "IMAGE_PROPERTIES"])?;
+
+        let colors = response
+            .image_properties
+            .iter()
+            .flat_map(|properties| &properties.dominant_colors)
+            .map(|color| DominantColor::new(color.red, color.green, color.blue, color.pixel_percent / 100.0))
+            .collect();
+
+        // DetectLabels does not count faces, so treat a confident person label as one
+        let has_face = response
+            .labels
+            .iter()
+            .any(|label| FACE_LABELS.contains(&label.name.as_str()) && label.confidence >= FACE_MIN_CONFIDENCE);
+
+        let mut report = VisionReport::from_colors(sorted_keywords(&response.labels), colors);
+        report.faces = has_face as u32;
+        report.provider = self.name().to_string();
+
+        // Moderation labels come from a separate action; report top-level categories only
+        let mut categories: Vec<String> = Vec::new();
+        for label in self.detect_moderation_labels(&request.image)? {
+            if label.confidence < UNSAFE_MIN_CONFIDENCE {
+                continue;
+            }
+            let category = if label.parent_name.is_empty() {
+                label.name
+            } else {
+                label.parent_name
+            };
+            if !categories.contains(&category) {
+                categories.push(category);
+            }
+        }
+        report.unsafe_categories = categories;
+
+        if request.detect_text {
+            report.text = self.detect_text(&request.image)?;
+        }
+
+        Ok(report)
+    }
+}
+
+// Get label names with scores between 0 and 1, sorted by confidence
+fn sorted_keywords(labels: &[RekognitionLabel]) -> Vec<Keyword> {
+    let mut keywords: Vec<Keyword> = labels
+        .iter()
+        .map(|label| Keyword::new(label.name.clone(), label.confidence / 100.0))
+        .collect();
+    keywords.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    keywords
+}
@@ -0,0 +1,271 @@
+// Import collections, file, synchronization and time handling
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+// Import error handling
+use anyhow::{anyhow, Result};
+// Import logging
+use log::warn;
+// Import JSON handling
+use serde_json::Value;
+
+// Import local modules for settings and HTTP client
+use crate::{config, http_client::HttpClient};
+
+// Directory holding one file per secret when SECRETS_DIR is not set
+const DEFAULT_SECRETS_DIR: &str = "/run/secrets";
+// How long secrets fetched from Vault are reused before asking again
+const VAULT_CACHE_SECS: u64 = 300;
+
+// Key/value pairs of a Vault secret, shared by the lookups made until the next fetch
+type VaultData = Arc<HashMap<String, String>>;
+
+// Process-wide secrets provider
+static SECRETS: OnceLock<Box<dyn SecretsProvider>> = OnceLock::new();
+
+// Trait for looking up credentials such as API keys
+pub trait SecretsProvider: Send + Sync {
+    // Short provider name used in logs and errors
+    fn name(&self) -> &str;
+
+    // Get the current value of a secret, failing when it is not set
+    fn get(&self, key: &str) -> Result<String>;
+}
+
+// Secrets read from environment variables
+pub struct EnvSecrets;
+
+// Secrets read from one file per secret, e.g. Docker or Kubernetes mounted secrets. Files are read on every
+// lookup so rotated credentials apply without a restart
+pub struct FileSecrets {
+    // Directory holding the secret files
+    dir: PathBuf,
+}
+
+// Secrets read from a HashiCorp Vault KV version 2 secret
+pub struct VaultSecrets {
+    // Vault server address, e.g. https://vault.example.com:8200
+    address: String,
+    // Vault token
+    token: String,
+    // Secret path including the mount, e.g. secret/data/clara
+    path: String,
+    // HTTP client instance
+    http_client: HttpClient,
+    // Last fetched secret data and when it was fetched
+    cache: Mutex<Option<(Instant, VaultData)>>,
+}
+
+impl SecretsProvider for EnvSecrets {
+    fn name(&self) -> &str {
+        "env"
+    }
+
+    fn get(&self, key: &str) -> Result<String> {
+        config::var(key)
+            .ok()
+            .filter(|value| !value.is_empty())
+            .ok_or_else(|| anyhow!("{} is not set in the environment", key))
+    }
+}
+
+impl FileSecrets {
+    // Initialize provider for the directory in SECRETS_DIR (/run/secrets by default)
+    pub fn new() -> Self {
+        let dir = config::var("SECRETS_DIR")
+            .ok()
+            .filter(|dir| !dir.is_empty())
+            .unwrap_or_else(|| DEFAULT_SECRETS_DIR.to_string());
+
+        Self { dir: dir.into() }
+    }
+}
+
+impl Default for FileSecrets {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SecretsProvider for FileSecrets {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn get(&self, key: &str) -> Result<String> {
+        let path = self.dir.join(key);
+        let value = fs::read_to_string(&path).map_err(|err| anyhow!("Cannot read {}: {}", path.display(), err))?;
+
+        // Files usually end with a newline that isn't part of the secret
+        Some(value.trim().to_string())
+            .filter(|value| !value.is_empty())
+            .ok_or_else(|| anyhow!("{} is empty", path.display()))
+    }
+}
+
+impl VaultSecrets {
+    // Initialize provider from VAULT_ADDR, VAULT_TOKEN and VAULT_SECRET_PATH
+    pub fn new() -> Result<Self> {
+        let var = |name: &str| {
+            config::var(name)
+                .ok()
+                .filter(|value| !value.is_empty())
+                .ok_or_else(|| anyhow!("Missing {} for the vault secrets provider", name))
+        };
+
+        Ok(Self {
+            address: var("VAULT_ADDR")?.trim_end_matches('/').to_string(),
+            token: var("VAULT_TOKEN")?,
+            path: var("VAULT_SECRET_PATH")?.trim_matches('/').to_string(),
+            http_client: HttpClient::new(),
+            cache: Mutex::new(None),
+        })
+    }
+
+    // Fetch all key/value pairs of the secret
+    fn fetch(&self) -> Result<HashMap<String, String>> {
+        let response = self.http_client.get_with_headers(
+            &format!("{}/v1/{}", self.address, self.path),
+            &[("X-Vault-Token", &self.token)],
+        )?;
+        let response: Value = serde_json::from_str(&response)?;
+
+        let data = response["data"]["data"]
+            .as_object()
+            .ok_or_else(|| anyhow!("Vault secret {} has no data", self.path))?;
+
+        Ok(data
+            .iter()
+            .filter_map(|(key, value)| value.as_str().map(|value| (key.clone(), value.to_string())))
+            .collect())
+    }
+}
+
+impl SecretsProvider for VaultSecrets {
+    fn name(&self) -> &str {
+        "vault"
+    }
+
+    fn get(&self, key: &str) -> Result<String> {
+        // The lock is only held to copy the cache, never while Vault is asked
+        let cached = self.cache.lock().unwrap().clone();
+
+        // Ask Vault again once the cached copy is old, so rotated credentials are picked up
+        let data = match cached {
+            Some((fetched, data)) if fetched.elapsed() <= Duration::from_secs(VAULT_CACHE_SECS) => data,
+            cached => match self.fetch() {
+                Ok(data) => {
+                    let data = Arc::new(data);
+                    *self.cache.lock().unwrap() = Some((Instant::now(), data.clone()));
+                    data
+                }
+                // Keep using the old copy while Vault can't be reached, and wait a while before asking again
+                Err(err) => {
+                    let Some((_, data)) = cached else {
+                        return Err(err);
+                    };
+                    warn!("Cannot refresh secrets from vault, using the cached copy: {:?}", err);
+                    *self.cache.lock().unwrap() = Some((Instant::now(), data.clone()));
+                    data
+                }
+            },
+        };
+
+        data.get(key)
+            .cloned()
+            .ok_or_else(|| anyhow!("{} is not set in vault secret {}", key, self.path))
+    }
+}
+
+// Create the provider named in SECRETS_PROVIDER: env (default), file or vault
+pub fn create_secrets_provider() -> Result<Box<dyn SecretsProvider>> {
+    let provider = config::var("SECRETS_PROVIDER").unwrap_or_default();
+
+    match provider.as_str() {
+        "" | "env" => Ok(Box::new(EnvSecrets)),
+        "file" => Ok(Box::new(FileSecrets::new())),
+        "vault" => Ok(Box::new(VaultSecrets::new()?)),
+        other => Err(anyhow!("Unknown SECRETS_PROVIDER {}", other)),
+    }
+}
+
+// Set the process-wide secrets provider, once and before the first lookup
+pub fn set_secrets_provider(provider: Box<dyn SecretsProvider>) -> Result<()> {
+    SECRETS
+        .set(provider)
+        .map_err(|_| anyhow!("Secrets provider is already set"))
+}
+
+// Get the process-wide secrets provider, environment variables unless another one was set
+pub fn secrets() -> &'static dyn SecretsProvider {
+    SECRETS.get_or_init(|| Box::new(EnvSecrets)).as_ref()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Secrets directory unique to the test
+    fn secrets_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("clara-secrets-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Vault provider nothing listens for, holding a copy fetched `age` ago
+    fn unreachable_vault(age: Duration) -> VaultSecrets {
+        let data = HashMap::from([("OPENAI_API_KEY".to_string(), "sk-cached".to_string())]);
+        VaultSecrets {
+            address: "http://127.0.0.1:1".to_string(),
+            token: "token".to_string(),
+            path: "secret/data/clara".to_string(),
+            http_client: HttpClient::new(),
+            cache: Mutex::new(Some((Instant::now().checked_sub(age).unwrap(), Arc::new(data)))),
+        }
+    }
+
+    #[test]
+    fn secret_files_are_trimmed() {
+        let dir = secrets_dir("trimmed");
+        fs::write(dir.join("OPENAI_API_KEY"), "  sk-test\n").unwrap();
+
+        let value = FileSecrets { dir: dir.clone() }.get("OPENAI_API_KEY").unwrap();
+
+        assert_eq!(value, "sk-test");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn empty_or_missing_secret_files_are_errors() {
+        let dir = secrets_dir("empty");
+        fs::write(dir.join("OPENAI_API_KEY"), "\n").unwrap();
+        let secrets = FileSecrets { dir: dir.clone() };
+
+        let empty = secrets.get("OPENAI_API_KEY").unwrap_err();
+
+        assert!(empty.to_string().ends_with("is empty"));
+        assert!(secrets.get("TWITTER_PASSWORD").is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn stale_vault_copy_is_used_while_vault_is_down() {
+        let vault = unreachable_vault(Duration::from_secs(VAULT_CACHE_SECS + 60));
+
+        assert_eq!(vault.get("OPENAI_API_KEY").unwrap(), "sk-cached");
+        assert!(vault.get("TWITTER_PASSWORD").is_err());
+    }
+
+    #[test]
+    fn vault_without_a_copy_reports_the_fetch_error() {
+        let vault = unreachable_vault(Duration::ZERO);
+        *vault.cache.lock().unwrap() = None;
+
+        assert!(vault.get("OPENAI_API_KEY").is_err());
+    }
+}
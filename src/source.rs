@@ -0,0 +1,399 @@
+// Import time handling
+use std::time::Duration;
+
+// Import error handling
+use anyhow::{anyhow, Result};
+// Import the async trait support for sources
+use async_trait::async_trait;
+// Import stream helpers for reading subscriptions
+use futures_util::{FutureExt, StreamExt};
+// Import the Kafka consumer
+use rdkafka::{
+    config::ClientConfig,
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    Message,
+};
+// Import serialization traits
+use serde::Deserialize;
+use serde_json::json;
+// Import the handler lock and timeouts
+use tokio::{sync::RwLock, task, time::timeout};
+// Import logging
+use tracing::{info, warn};
+
+// Import the AWS client, handler, metrics and tweets
+use crate::{aws::AwsClient, config, handler::Handler, metrics::metrics, twitter::ExtractedTweet};
+
+// Time between searches for new mentions on Twitter
+const POLL_INTERVAL: Duration = Duration::from_secs(2 * 60);
+// Longest wait for the first message of a batch from a message queue
+const RECEIVE_WAIT: Duration = Duration::from_secs(20);
+// Most messages taken from NATS or Kafka at once
+const MAX_BATCH: usize = 100;
+// Subject or topic read when MENTION_TOPIC isn't set
+const DEFAULT_MENTION_TOPIC: &str = "clara.mentions";
+// Queue group or consumer group joined when MENTION_GROUP isn't set
+const DEFAULT_MENTION_GROUP: &str = "clara";
+// Service name and content type of the SQS JSON protocol
+const SQS_SERVICE: &str = "sqs";
+const SQS_CONTENT_TYPE: &str = "application/x-amz-json-1.0";
+// Most messages SQS returns at once
+const SQS_MAX_MESSAGES: usize = 10;
+
+// Where new mentions come from. Message queue sources expect each message to be a mention as JSON, in the form
+// mentions are stored in the queue, so platform listeners can publish them for any number of generation workers
+#[async_trait]
+pub trait MentionSource: Send {
+    // Name used in logs and metrics
+    fn name(&self) -> &str;
+    // Queue new mentions with the handler, returning how many were added
+    async fn receive(&mut self, handler: &RwLock<Handler>) -> Result<usize>;
+    // Time to wait after a receive before the next one
+    fn interval(&self) -> Duration;
+}
+
+// Kind of mention source
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SourceKind {
+    // Search Twitter for mentions of the bot
+    Twitter,
+    // Subscribe to a NATS subject in a queue group
+    Nats,
+    // Consume a Kafka topic in a consumer group
+    Kafka,
+    // Receive from an SQS queue
+    Sqs,
+}
+
+// Mention source settings
+#[derive(Debug, Clone)]
+pub struct SourceConfig {
+    pub kind: SourceKind,
+    // NATS server URL, Kafka bootstrap brokers or SQS queue URL
+    pub url: String,
+    // NATS subject or Kafka topic
+    pub topic: String,
+    // NATS queue group or Kafka consumer group
+    pub group: String,
+}
+
+impl SourceConfig {
+    // Read the source from MENTION_SOURCE (twitter by default), MENTION_QUEUE_URL, MENTION_TOPIC and MENTION_GROUP
+    pub fn from_env() -> Result<Self> {
+        let kind = match config::var("MENTION_SOURCE").unwrap_or_default().as_str() {
+            "" | "twitter" => SourceKind::Twitter,
+            "nats" => SourceKind::Nats,
+            "kafka" => SourceKind::Kafka,
+            "sqs" => SourceKind::Sqs,
+            other => return Err(anyhow!("Unknown MENTION_SOURCE {}", other)),
+        };
+        let var = |name: &str| config::var(name).ok().filter(|value| !value.is_empty());
+
+        let url = var("MENTION_QUEUE_URL").unwrap_or_default();
+        if kind != SourceKind::Twitter && url.is_empty() {
+            return Err(anyhow!(
+                "MENTION_QUEUE_URL must be set unless MENTION_SOURCE is twitter"
+            ));
+        }
+
+        Ok(Self {
+            kind,
+            url,
+            topic: var("MENTION_TOPIC").unwrap_or_else(|| DEFAULT_MENTION_TOPIC.to_string()),
+            group: var("MENTION_GROUP").unwrap_or_else(|| DEFAULT_MENTION_GROUP.to_string()),
+        })
+    }
+}
+
+// Connect to the mention source configured in the environment
+pub async fn create_mention_source() -> Result<Box<dyn MentionSource>> {
+    let config = SourceConfig::from_env()?;
+
+    let source: Box<dyn MentionSource> = match config.kind {
+        SourceKind::Twitter => Box::new(TwitterSearch),
+        SourceKind::Nats => Box::new(NatsSource::connect(&config).await?),
+        SourceKind::Kafka => Box::new(KafkaSource::connect(&config)?),
+        SourceKind::Sqs => Box::new(SqsSource::new(&config)?),
+    };
+    info!(source = source.name(), "Receiving mentions");
+
+    Ok(source)
+}
+
+// Mentions found by searching Twitter
+pub struct TwitterSearch;
+
+#[async_trait]
+impl MentionSource for TwitterSearch {
+    fn name(&self) -> &str {
+        "twitter"
+    }
+
+    async fn receive(&mut self, handler: &RwLock<Handler>) -> Result<usize> {
+        handler.read().await.poll_mentions().await
+    }
+
+    fn interval(&self) -> Duration {
+        POLL_INTERVAL
+    }
+}
+
+// Mentions published to a NATS subject. Core NATS keeps no messages, those published while no worker is
+// subscribed are lost
+pub struct NatsSource {
+    subscriber: async_nats::Subscriber,
+}
+
+impl NatsSource {
+    // Subscribe to the subject, sharing its messages with other workers in the queue group
+    pub async fn connect(config: &SourceConfig) -> Result<Self> {
+        let client = async_nats::connect(&config.url).await?;
+        let subscriber = client
+            .queue_subscribe(config.topic.clone(), config.group.clone())
+            .await?;
+
+        Ok(Self { subscriber })
+    }
+}
+
+#[async_trait]
+impl MentionSource for NatsSource {
+    fn name(&self) -> &str {
+        "nats"
+    }
+
+    async fn receive(&mut self, handler: &RwLock<Handler>) -> Result<usize> {
+        // Wait for a first message, then take those already delivered
+        let mut payloads = match timeout(RECEIVE_WAIT, self.subscriber.next()).await {
+            Ok(Some(message)) => vec![message.payload],
+            Ok(None) => return Err(anyhow!("NATS subscription closed")),
+            Err(_) => return Ok(0),
+        };
+        while payloads.len() < MAX_BATCH {
+            match self.subscriber.next().now_or_never() {
+                Some(Some(message)) => payloads.push(message.payload),
+                _ => break,
+            }
+        }
+
+        let tweets = parse_mentions(self.name(), payloads.iter().map(|payload| payload.as_ref()));
+        handler.read().await.queue_mentions(&tweets)
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+// Mentions published to a Kafka topic. Offsets are committed once the mentions are queued
+pub struct KafkaSource {
+    consumer: StreamConsumer,
+}
+
+impl KafkaSource {
+    // Join the consumer group and subscribe to the topic
+    pub fn connect(config: &SourceConfig) -> Result<Self> {
+        let consumer: StreamConsumer = ClientConfig::new()
+            .set("bootstrap.servers", &config.url)
+            .set("group.id", &config.group)
+            .set("enable.auto.commit", "false")
+            .set("auto.offset.reset", "earliest")
+            .create()?;
+        consumer.subscribe(&[&config.topic])?;
+
+        Ok(Self { consumer })
+    }
+}
+
+#[async_trait]
+impl MentionSource for KafkaSource {
+    fn name(&self) -> &str {
+        "kafka"
+    }
+
+    async fn receive(&mut self, handler: &RwLock<Handler>) -> Result<usize> {
+        let payload = |message: &rdkafka::message::BorrowedMessage| message.payload().unwrap_or_default().to_vec();
+
+        // Wait for a first message, then take those already fetched
+        let mut payloads = match timeout(RECEIVE_WAIT, self.consumer.recv()).await {
+            Ok(message) => vec![payload(&message?)],
+            Err(_) => return Ok(0),
+        };
+        while payloads.len() < MAX_BATCH {
+            match self.consumer.recv().now_or_never() {
+                Some(message) => payloads.push(payload(&message?)),
+                None => break,
+            }
+        }
+
+        let tweets = parse_mentions(self.name(), payloads.iter().map(Vec::as_slice));
+        let queued = handler.read().await.queue_mentions(&tweets)?;
+        // Mentions are deduplicated when queued, so a lost commit only redelivers them
+        self.consumer.commit_consumer_state(CommitMode::Async)?;
+
+        Ok(queued)
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+// Response of ReceiveMessage
+#[derive(Debug, Deserialize)]
+struct ReceiveMessageResponse {
+    #[serde(rename = "Messages", default)]
+    messages: Vec<SqsMessage>,
+}
+
+// Message received from SQS
+#[derive(Debug, Deserialize)]
+struct SqsMessage {
+    // Handle to delete the message with
+    #[serde(rename = "ReceiptHandle")]
+    receipt_handle: String,
+    #[serde(rename = "Body")]
+    body: String,
+}
+
+// Mentions sent to an SQS queue. Messages are deleted once the mentions are queued
+pub struct SqsSource {
+    // Signed AWS client, in AWS_REGION
+    aws: AwsClient,
+    // URL of the queue
+    queue_url: String,
+}
+
+impl SqsSource {
+    // Create a source for the queue at the configured URL
+    pub fn new(config: &SourceConfig) -> Result<Self> {
+        Ok(Self {
+            aws: AwsClient::new()?,
+            queue_url: config.url.clone(),
+        })
+    }
+
+    // Make a call to an SQS action, blocking only this worker thread during long polls
+    fn call(&self, action: &str, body: serde_json::Value) -> Result<String> {
+        let target = format!("AmazonSQS.{}", action);
+        task::block_in_place(|| self.aws.call(SQS_SERVICE, SQS_CONTENT_TYPE, &target, body))
+    }
+}
+
+#[async_trait]
+impl MentionSource for SqsSource {
+    fn name(&self) -> &str {
+        "sqs"
+    }
+
+    async fn receive(&mut self, handler: &RwLock<Handler>) -> Result<usize> {
+        let response = self.call(
+            "ReceiveMessage",
+            json!({
+                "QueueUrl": self.queue_url,
+                "MaxNumberOfMessages": SQS_MAX_MESSAGES,
+                "WaitTimeSeconds": RECEIVE_WAIT.as_secs()
+            }),
+        )?;
+        let response: ReceiveMessageResponse = serde_json::from_str(&response)?;
+        if response.messages.is_empty() {
+            return Ok(0);
+        }
+
+        let tweets = parse_mentions(
+            self.name(),
+            response.messages.iter().map(|message| message.body.as_bytes()),
+        );
+        let queued = handler.read().await.queue_mentions(&tweets)?;
+
+        // Messages that aren't deleted come back after the visibility timeout, and are deduplicated then
+        let entries = response
+            .messages
+            .iter()
+            .enumerate()
+            .map(|(index, message)| json!({ "Id": index.to_string(), "ReceiptHandle": message.receipt_handle }))
+            .collect::<Vec<_>>();
+        self.call(
+            "DeleteMessageBatch",
+            json!({
+                "QueueUrl": self.queue_url,
+                "Entries": entries
+            }),
+        )?;
+
+        Ok(queued)
+    }
+
+    fn interval(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+// Parse messages into mentions, skipping and counting those that aren't one. Every field of a mention is optional,
+// but one without a tweet ID or author can't be answered
+fn parse_mentions<'a>(source: &str, payloads: impl Iterator<Item = &'a [u8]>) -> Vec<ExtractedTweet> {
+    payloads
+        .filter_map(|payload| {
+            let problem = match serde_json::from_slice::<ExtractedTweet>(payload) {
+                Ok(tweet) if is_blank(&tweet.id) => "no id".to_string(),
+                Ok(tweet) if is_blank(&tweet.username) => "no username".to_string(),
+                Ok(tweet) => return Some(tweet),
+                Err(err) => err.to_string(),
+            };
+            warn!(source, error = %problem, "Skipping a message that isn't a mention");
+            metrics().increment(&format!("source.invalid.{}", source), 1);
+            None
+        })
+        .collect()
+}
+
+// Check whether a field of a mention is missing or empty
+fn is_blank(field: &Option<String>) -> bool {
+    field.as_deref().is_none_or(str::is_empty)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Messages counted as invalid so far
+    fn invalid() -> u64 {
+        metrics()
+            .snapshot()
+            .counters
+            .get("source.invalid.test")
+            .copied()
+            .unwrap_or_default()
+    }
+
+    // Parse messages given as strings
+    fn parse(payloads: &[&str]) -> Vec<ExtractedTweet> {
+        parse_mentions("test", payloads.iter().map(|payload| payload.as_bytes()))
+    }
+
+    #[test]
+    fn mentions_with_an_id_and_author_are_taken() {
+        let tweets = parse(&[r#"{"id": "1", "username": "alice", "text": "@clara draw me"}"#]);
+
+        assert_eq!(tweets.len(), 1);
+        assert_eq!(tweets[0].username.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn mentions_without_an_id_or_author_are_skipped() {
+        let before = invalid();
+
+        let tweets = parse(&[
+            r#"{"username": "alice"}"#,
+            r#"{"id": "2"}"#,
+            r#"{"id": "3", "username": ""}"#,
+            r#"{}"#,
+            "not json",
+            r#"{"id": "4", "username": "bob"}"#,
+        ]);
+
+        assert_eq!(tweets.len(), 1);
+        assert_eq!(tweets[0].id.as_deref(), Some("4"));
+        assert_eq!(invalid(), before + 5);
+    }
+}
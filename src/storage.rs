@@ -45,7 +45,7 @@ impl Storage {
         // Create buffered writer for efficient writing
         let writer = BufWriter::new(file);
         // Serialize and write storage to file
-        serde_json::to_writer(writer, &self).map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        serde_json::to_writer(writer, &self).map_err(io::Error::other)
     }
 
     // Insert new item into storage
@@ -60,6 +60,11 @@ impl Storage {
         self.items.contains(&tweet)
     }
 
+    // Iterate over stored items
+    pub fn items(&self) -> impl Iterator<Item = &String> {
+        self.items.iter()
+    }
+
     // Remove item from storage
     pub fn remove(&mut self, tweet: String) -> bool {
         // Returns true if item was present and removed
@@ -0,0 +1,161 @@
+// Import panic payload, future and time handling
+use std::{
+    any::Any,
+    future::Future,
+    time::{Duration, Instant},
+};
+
+// Import error handling
+use anyhow::Result;
+// Import tasks and sleep
+use tokio::{
+    task::{JoinError, JoinHandle},
+    time::sleep,
+};
+// Import logging
+use tracing::{error, info};
+
+// Import metrics
+use crate::metrics::metrics;
+
+// How long to wait before restarting a stopped task
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RestartPolicy {
+    // Delay before the first restart, doubled for every further one
+    pub base_delay: Duration,
+    // Largest delay between restarts
+    pub max_delay: Duration,
+    // A task running at least this long counts as recovered, its next restart waits the base delay again
+    pub reset_after: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5 * 60),
+            reset_after: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+impl RestartPolicy {
+    // Delay before the given restart, counted from 1
+    pub fn delay(&self, restart: u32) -> Duration {
+        self.base_delay
+            .saturating_mul(2u32.saturating_pow(restart.saturating_sub(1)))
+            .min(self.max_delay)
+    }
+}
+
+// Why a supervised task stopped
+#[derive(Debug)]
+pub enum Stopped {
+    // The task returned without an error, which long running tasks shouldn't
+    Exited,
+    // The task returned an error
+    Failed(anyhow::Error),
+    // The task panicked, with the panic message if there is one
+    Panicked(String),
+}
+
+impl Stopped {
+    // Tell why a task stopped from its join result
+    pub fn from_join(result: Result<Result<()>, JoinError>) -> Self {
+        match result {
+            Ok(Ok(())) => Stopped::Exited,
+            Ok(Err(err)) => Stopped::Failed(err),
+            Err(err) if err.is_panic() => Stopped::Panicked(panic_message(err.into_panic())),
+            Err(err) => Stopped::Panicked(err.to_string()),
+        }
+    }
+
+    // Metric and log name of the cause
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Stopped::Exited => "exited",
+            Stopped::Failed(_) => "failed",
+            Stopped::Panicked(_) => "panicked",
+        }
+    }
+}
+
+// Long running tasks of the bot, each restarted with backoff when it panics, fails or exits, so a stopped task
+// doesn't silently leave the bot with less capacity
+pub struct Supervisor {
+    // Backoff between restarts
+    policy: RestartPolicy,
+    // Supervising tasks, one per supervised task
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl Supervisor {
+    // Create a supervisor restarting tasks with the given backoff
+    pub fn new(policy: RestartPolicy) -> Self {
+        Self {
+            policy,
+            tasks: Vec::new(),
+        }
+    }
+
+    // Run the task made by `start`, making a new one each time it stops. Restarts are counted per task, e.g.
+    // "supervisor.restarts.poller", and per cause, e.g. "supervisor.panicked"
+    pub fn spawn<F, Fut>(&mut self, name: impl Into<String>, mut start: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<()>> + Send + 'static,
+    {
+        let name = name.into();
+        let policy = self.policy;
+
+        self.tasks.push(tokio::spawn(async move {
+            let mut restart = 0;
+            loop {
+                // Each run is its own task so a panic is caught here instead of ending the supervision
+                let started = Instant::now();
+                let stopped = Stopped::from_join(tokio::spawn(start()).await);
+
+                restart = if started.elapsed() >= policy.reset_after {
+                    1
+                } else {
+                    restart + 1
+                };
+                let delay = policy.delay(restart);
+                metrics().increment(&format!("supervisor.{}", stopped.kind()), 1);
+                metrics().increment(&format!("supervisor.restarts.{}", name), 1);
+                match &stopped {
+                    Stopped::Exited => error!(task = %name, delay_ms = delay.as_millis() as u64, "Task exited"),
+                    Stopped::Failed(err) => {
+                        error!(task = %name, delay_ms = delay.as_millis() as u64, error = ?err, "Task failed")
+                    }
+                    Stopped::Panicked(message) => {
+                        error!(task = %name, delay_ms = delay.as_millis() as u64, panic = %message, "Task panicked")
+                    }
+                }
+
+                sleep(delay).await;
+                info!(task = %name, restart, "Restarting task");
+            }
+        }));
+    }
+
+    // Wait on the supervised tasks, which only return if the runtime shuts down
+    pub async fn wait(self) -> Result<()> {
+        for task in self.tasks {
+            task.await?;
+        }
+
+        Ok(())
+    }
+}
+
+// Message of a panic, which is a string unless the code panicked with another value
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "Panicked with a value that isn't a message".to_string(),
+        },
+    }
+}
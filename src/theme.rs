@@ -0,0 +1,235 @@
+// Import date handling
+use chrono::{Datelike, Local, NaiveDate};
+// Import error handling
+use anyhow::{anyhow, Result};
+// Import serialization traits
+use serde::{Deserialize, Serialize};
+// Import file handling
+use std::fs;
+
+// Import settings
+use crate::config;
+
+// Hashtag that disables seasonal theming for a single request
+const NO_THEME_TAG: &str = "#notheme";
+
+// Structure describing a seasonal theme and the dates it applies to
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Theme {
+    // Theme name, also usable as a hashtag to force the theme
+    pub name: String,
+    // First day of the theme in MM-DD format
+    pub start: String,
+    // Last day of the theme in MM-DD format (may wrap around the new year)
+    pub end: String,
+    // Optional year the theme is limited to, for holidays on a moving date
+    #[serde(default)]
+    pub year: Option<i32>,
+    // Text appended to the image prompt while the theme is active
+    pub prompt: String,
+}
+
+// Calendar of seasonal themes, checked in order
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ThemeCalendar {
+    pub themes: Vec<Theme>,
+}
+
+impl Theme {
+    // Check whether the theme is active on the given date
+    pub fn is_active(&self, date: NaiveDate) -> Result<bool> {
+        if self.year.is_some_and(|year| year != date.year()) {
+            return Ok(false);
+        }
+
+        let start = parse_month_day(&self.start)?;
+        let end = parse_month_day(&self.end)?;
+        let today = (date.month(), date.day());
+
+        // Ranges such as 12-01..02-28 wrap around the end of the year
+        if start <= end {
+            Ok(start <= today && today <= end)
+        } else {
+            Ok(today >= start || today <= end)
+        }
+    }
+}
+
+impl ThemeCalendar {
+    // Load calendar from the file in THEME_CALENDAR, or use the built-in one
+    pub fn load() -> Result<Self> {
+        match config::var("THEME_CALENDAR").ok().filter(|path| !path.is_empty()) {
+            Some(path) => Ok(serde_json::from_str(&fs::read_to_string(path)?)?),
+            None => Ok(Self::default()),
+        }
+    }
+
+    // Resolve the theme for a request, honouring hashtag overrides in the mention text
+    pub fn resolve(&self, text: &str, date: NaiveDate) -> Result<Option<&Theme>> {
+        let tags = hashtags(text);
+
+        // Explicit opt-out wins over everything else
+        if tags.iter().any(|tag| tag == NO_THEME_TAG) {
+            return Ok(None);
+        }
+
+        // A theme hashtag forces that theme regardless of the date
+        if let Some(theme) = self
+            .themes
+            .iter()
+            .find(|theme| tags.contains(&format!("#{}", theme.name.to_lowercase())))
+        {
+            return Ok(Some(theme));
+        }
+
+        for theme in &self.themes {
+            if theme.is_active(date)? {
+                return Ok(Some(theme));
+            }
+        }
+
+        Ok(None)
+    }
+
+    // Resolve the theme for a request made today
+    pub fn resolve_today(&self, text: &str) -> Result<Option<&Theme>> {
+        self.resolve(text, Local::now().date_naive())
+    }
+}
+
+impl Default for ThemeCalendar {
+    // Built-in calendar, more specific themes listed first
+    fn default() -> Self {
+        let theme = |name: &str, start: &str, end: &str, prompt: &str| Theme {
+            name: name.into(),
+            start: start.into(),
+            end: end.into(),
+            year: None,
+            prompt: prompt.into(),
+        };
+        // Lunar New Year moves with the lunar calendar, so each year has its own week from New Year's Day
+        let lunar_new_year = |year: i32, start: &str, end: &str| Theme {
+            year: Some(year),
+            ..theme(
+                "lunarnewyear",
+                start,
+                end,
+                "Give the scene a festive Lunar New Year theme with red lanterns, paper cuttings and golden coins.",
+            )
+        };
+
+        Self {
+            themes: vec![
+                theme(
+                    "halloween",
+                    "10-20",
+                    "10-31",
+                    "Give the scene a cute Halloween theme with pumpkins, autumn leaves and a little witch hat.",
+                ),
+                theme(
+                    "valentines",
+                    "02-10",
+                    "02-14",
+                    "Give the scene a sweet Valentine's Day theme with hearts and soft pink tones.",
+                ),
+                lunar_new_year(2025, "01-29", "02-04"),
+                lunar_new_year(2026, "02-17", "02-23"),
+                lunar_new_year(2027, "02-06", "02-12"),
+                lunar_new_year(2028, "01-26", "02-01"),
+                lunar_new_year(2029, "02-13", "02-19"),
+                lunar_new_year(2030, "02-03", "02-09"),
+                theme(
+                    "christmas",
+                    "12-18",
+                    "12-26",
+                    "Give the scene a cozy Christmas theme with a scarf, twinkling lights and presents.",
+                ),
+                // 02-29 ends winter on the last day of February in leap years too
+                theme(
+                    "winter",
+                    "12-01",
+                    "02-29",
+                    "Give the scene a snowy winter theme with gentle snowflakes and warm knitwear.",
+                ),
+            ],
+        }
+    }
+}
+
+// Hashtags of a text in lowercase, each one a whole token so #christmasiscancelled isn't #christmas
+fn hashtags(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '#'))
+        .filter(|token| token.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+// Parse a MM-DD string into a (month, day) pair
+fn parse_month_day(value: &str) -> Result<(u32, u32)> {
+    let (month, day) = value
+        .split_once('-')
+        .ok_or_else(|| anyhow!("Invalid theme date {}, expected MM-DD", value))?;
+    let month: u32 = month.parse()?;
+    let day: u32 = day.parse()?;
+
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(anyhow!("Invalid theme date {}, expected MM-DD", value));
+    }
+
+    Ok((month, day))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(value: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(value, "%Y-%m-%d").unwrap()
+    }
+
+    // Name of the theme resolved for a mention on a date
+    fn resolved(text: &str, on: &str) -> Option<String> {
+        ThemeCalendar::default()
+            .resolve(text, date(on))
+            .unwrap()
+            .map(|theme| theme.name.clone())
+    }
+
+    #[test]
+    fn lunar_new_year_follows_the_lunar_calendar() {
+        assert_eq!(resolved("", "2026-02-17").as_deref(), Some("lunarnewyear"));
+        assert_eq!(resolved("", "2026-02-23").as_deref(), Some("lunarnewyear"));
+        assert_eq!(resolved("", "2026-02-24").as_deref(), Some("winter"));
+        assert_eq!(resolved("", "2028-01-26").as_deref(), Some("lunarnewyear"));
+        assert_eq!(resolved("", "2027-01-26").as_deref(), Some("winter"));
+    }
+
+    #[test]
+    fn winter_lasts_until_the_end_of_february() {
+        assert_eq!(resolved("", "2027-02-28").as_deref(), Some("winter"));
+        assert_eq!(resolved("", "2028-02-29").as_deref(), Some("winter"));
+        assert_eq!(resolved("", "2027-03-01"), None);
+        assert_eq!(resolved("", "2028-03-01"), None);
+    }
+
+    #[test]
+    fn hashtags_must_match_whole() {
+        assert_eq!(
+            resolved("Draw me! #Christmas", "2026-07-01").as_deref(),
+            Some("christmas")
+        );
+        assert_eq!(
+            resolved("#christmas, please", "2026-07-01").as_deref(),
+            Some("christmas")
+        );
+        assert_eq!(resolved("#christmasiscancelled", "2026-07-01"), None);
+        assert_eq!(resolved("#lunarnewyear", "2026-07-01").as_deref(), Some("lunarnewyear"));
+    }
+
+    #[test]
+    fn notheme_opts_out_as_a_whole_hashtag() {
+        assert_eq!(resolved("spooky #NoTheme.", "2026-10-31"), None);
+        assert_eq!(resolved("#nothemeplease", "2026-10-31").as_deref(), Some("halloween"));
+    }
+}
@@ -1,8 +1,12 @@
 // Import standard library modules
-use std::{env, process};
+use std::process;
 
 // Import Twitter client related dependencies
-use agent_twitter_client::{models::Profile, scraper::Scraper, search::SearchMode};
+use agent_twitter_client::{
+    models::{Profile, Tweet},
+    scraper::Scraper,
+    search::SearchMode,
+};
 // Import logging and error handling
 use log::error;
 // Import serialization/deserialization traits
@@ -10,6 +14,12 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use anyhow::Result;
 
+// Import secrets for account credentials
+use crate::secrets::secrets;
+
+// Path segment shared by all of Twitter's default profile images
+const DEFAULT_AVATAR_PATH: &str = "/default_profile_images/";
+
 // Main Twitter client struct
 pub struct Twitter {
     // Twitter account username
@@ -23,7 +33,7 @@ pub struct Twitter {
 }
 
 // Structure representing extracted tweet data
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ExtractedTweet {
     // User's display name
     pub name: Option<String>,
@@ -39,23 +49,26 @@ pub struct ExtractedTweet {
     pub permanent_url: Option<String>,
     // Unique tweet identifier
     pub id: Option<String>,
+    // URLs of photos attached to the tweet
+    #[serde(default)]
+    pub photos: Vec<String>,
 }
 
 impl Twitter {
     // Initialize new Twitter client instance
     pub async fn new() -> Result<Self> {
-        // Get Twitter credentials from environment variables
-        let username = env::var("TWITTER_USERNAME").unwrap_or_else(|err| {
+        // Get Twitter credentials from the secrets provider
+        let username = secrets().get("TWITTER_USERNAME").unwrap_or_else(|err| {
             error!("Missing TWITTER_USERNAME {}", err);
             process::exit(1);
         });
 
-        let password = env::var("TWITTER_PASSWORD").unwrap_or_else(|err| {
+        let password = secrets().get("TWITTER_PASSWORD").unwrap_or_else(|err| {
             error!("Missing TWITTER_PASSWORD {}", err);
             process::exit(1);
         });
 
-        let email = env::var("TWITTER_EMAIL").unwrap_or_else(|err| {
+        let email = secrets().get("TWITTER_EMAIL").unwrap_or_else(|err| {
             error!("Missing TWITTER_EMAIL {}", err);
             process::exit(1);
         });
@@ -83,6 +96,18 @@ impl Twitter {
         search_mode: Option<SearchMode>,
         cursor: Option<String>,
     ) -> Result<Vec<ExtractedTweet>> {
+        let (tweets, _) = self.search_tweets_page(query, max_tweets, search_mode, cursor).await?;
+        Ok(tweets)
+    }
+
+    // Search for tweets matching query, with the cursor of the next page if there is one
+    pub async fn search_tweets_page(
+        &self,
+        query: &str,
+        max_tweets: i32,
+        search_mode: Option<SearchMode>,
+        cursor: Option<String>,
+    ) -> Result<(Vec<ExtractedTweet>, Option<String>)> {
         // Perform tweet search
         let tweets = self
             .scraper
@@ -90,21 +115,15 @@ impl Twitter {
             .await?;
 
         // Convert tweets to ExtractedTweet format
-        let extracted_tweets: Vec<ExtractedTweet> = tweets
-            .tweets
-            .iter()
-            .map(|tweet| ExtractedTweet {
-                name: tweet.name.clone(),
-                username: tweet.username.clone(),
-                user_id: tweet.user_id.clone(),
-                text: tweet.text.clone(),
-                timestamp: tweet.timestamp,
-                permanent_url: tweet.permanent_url.clone(),
-                id: tweet.id.clone(),
-            })
-            .collect();
-
-        Ok(extracted_tweets)
+        let extracted_tweets: Vec<ExtractedTweet> = tweets.tweets.iter().map(extract).collect();
+
+        Ok((extracted_tweets, tweets.next))
+    }
+
+    // Get a single tweet by its ID
+    pub async fn get_tweet(&self, id: &str) -> Result<ExtractedTweet> {
+        let tweet = self.scraper.get_tweet(id).await?;
+        Ok(extract(&tweet))
     }
 
     // Get user profile information
@@ -129,3 +148,22 @@ impl Twitter {
         Ok(tweet_with_media)
     }
 }
+
+// Convert a tweet to ExtractedTweet format
+fn extract(tweet: &Tweet) -> ExtractedTweet {
+    ExtractedTweet {
+        name: tweet.name.clone(),
+        username: tweet.username.clone(),
+        user_id: tweet.user_id.clone(),
+        text: tweet.text.clone(),
+        timestamp: tweet.timestamp,
+        permanent_url: tweet.permanent_url.clone(),
+        id: tweet.id.clone(),
+        photos: tweet.photos.iter().map(|photo| photo.url.clone()).collect(),
+    }
+}
+
+// Check whether the avatar URL points to one of Twitter's default profile images
+pub fn is_default_avatar(url: &str) -> bool {
+    url.contains(DEFAULT_AVATAR_PATH)
+}
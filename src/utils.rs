@@ -1,8 +1,33 @@
-use std::{env, fs, path::PathBuf};
+use std::{
+    collections::HashMap,
+    env,
+    fs::{self, File, OpenOptions},
+    future::Future,
+    io::{self, BufReader, BufWriter},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
+use agent_twitter_client::error::TwitterError;
+use anyhow::{anyhow, Error, Result};
 use directories_next::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    task,
+    time::{sleep, timeout},
+};
+use tracing::warn;
 use uuid::Uuid;
 
+use crate::{
+    breaker::BreakerOpen,
+    clock::{system_clock, Clock},
+    config,
+    downloader::RefusedHost,
+    metrics::metrics,
+};
+
 // Generate custom image path in current directory
 pub fn custom_image_path() -> PathBuf {
     // Get current working directory
@@ -16,9 +41,7 @@ pub fn custom_image_path() -> PathBuf {
 
     // Generate unique filename using UUID
     let unique_file_name = format!("image-{}.png", Uuid::new_v4());
-    let unique_path = image_dir.join(unique_file_name);
-
-    unique_path
+    image_dir.join(unique_file_name)
 }
 
 // Generate image path in application data directory
@@ -32,7 +55,813 @@ pub fn generate_image_path() -> PathBuf {
 
     // Generate unique filename using UUID
     let unique_file_name = format!("image-{}.png", Uuid::new_v4());
-    let unique_path = image_dir.join(unique_file_name);
+    image_dir.join(unique_file_name)
+}
+
+// Maximum length of a single keyword in characters
+const MAX_KEYWORD_CHARS: usize = 40;
+// Maximum number of words in a single keyword
+const MAX_KEYWORD_WORDS: usize = 5;
+// Phrases that read as instructions to a model rather than image labels
+const INSTRUCTION_PHRASES: &[&str] = &[
+    "ignore",
+    "disregard",
+    "instruction",
+    "prompt",
+    "system",
+    "assistant",
+    "you are",
+    "pretend",
+    "jailbreak",
+    "override",
+    "forget",
+    "respond",
+    "reply with",
+];
+
+// Sanitize a keyword before it enters any model prompt, None when it must be dropped
+pub fn sanitize_keyword(keyword: &str) -> Option<String> {
+    // Keep letters, digits and word separators only
+    let cleaned: String = keyword
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '\'' {
+                c
+            } else {
+                ' '
+            }
+        })
+        .collect();
+    let cleaned = cleaned.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    if cleaned.is_empty()
+        || cleaned.chars().count() > MAX_KEYWORD_CHARS
+        || cleaned.split(' ').count() > MAX_KEYWORD_WORDS
+    {
+        return None;
+    }
+
+    // Drop anything that looks like an instruction rather than a label
+    let lower = cleaned.to_lowercase();
+    if INSTRUCTION_PHRASES.iter().any(|phrase| lower.contains(phrase)) {
+        return None;
+    }
+
+    Some(cleaned)
+}
+
+// Default OpenAI calls per minute shared by all mentions
+pub const DEFAULT_OPENAI_RATE_LIMIT: u32 = 60;
+// Default mentions handled per user per day
+pub const DEFAULT_USER_RATE_LIMIT: u32 = 5;
+// Default days a handled mention is remembered to avoid answering it twice
+pub const DEFAULT_MENTION_TTL_DAYS: u32 = 7;
+// Default days the analysis of an unchanged avatar is reused instead of calling the vision provider
+pub const DEFAULT_VISION_TTL_DAYS: u32 = 30;
+
+// How often something may happen
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateStrategy {
+    // No limit
+    Unlimited,
+    // Bursts of up to `capacity`, refilled continuously at `per_second`
+    TokenBucket { capacity: f64, per_second: f64 },
+    // Up to `limit` per `window`, counted from the first call of the window
+    FixedWindow { limit: u32, window: Duration },
+}
+
+// Outcome of asking a limiter for one call
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateDecision {
+    // The call may go ahead and was counted
+    Allowed,
+    // The call must wait this long
+    Limited(Duration),
+}
+
+// Limiter state of one key
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct RateState {
+    // Tokens left (token bucket) or calls made in the current window (fixed window)
+    pub value: f64,
+    // Unix timestamp in milliseconds of the last refill (token bucket) or the window start (fixed window)
+    pub since_ms: i64,
+}
+
+// Trait for keeping limiter state, e.g. in memory or on disk to survive restarts
+pub trait RateStore: Send + Sync {
+    // Get the state of a key, None when it was never limited
+    fn load(&self, key: &str) -> Result<Option<RateState>>;
+
+    // Replace the state of a key
+    fn save(&self, key: &str, state: RateState) -> Result<()>;
+}
+
+// Limiter state kept in memory only
+#[derive(Default)]
+pub struct MemoryRateStore {
+    states: Mutex<HashMap<String, RateState>>,
+}
 
-    unique_path
+// Limiter state kept in a JSON file
+#[derive(Serialize, Deserialize)]
+pub struct FileRateStore {
+    // Path to storage file
+    file_path: String,
+    // State keyed by limited key, e.g. "twitter:12345"
+    states: Mutex<HashMap<String, RateState>>,
+}
+
+// Limits calls per key, e.g. per user or per provider
+pub struct RateLimiter {
+    // Current strategy
+    strategy: RateStrategy,
+    // Where state is kept
+    store: Box<dyn RateStore>,
+    // Serializes read-modify-write of the state
+    lock: Mutex<()>,
+    // Time source for refills and window resets
+    clock: Arc<dyn Clock>,
+}
+
+impl RateStrategy {
+    // Token bucket allowing `limit` calls per minute, unlimited for 0
+    pub fn per_minute(limit: u32) -> Self {
+        match limit {
+            0 => RateStrategy::Unlimited,
+            limit => RateStrategy::TokenBucket {
+                capacity: limit as f64,
+                per_second: limit as f64 / 60.0,
+            },
+        }
+    }
+
+    // Fixed window allowing `limit` calls per day, unlimited for 0
+    pub fn per_day(limit: u32) -> Self {
+        match limit {
+            0 => RateStrategy::Unlimited,
+            limit => RateStrategy::FixedWindow {
+                limit,
+                window: Duration::from_secs(24 * 60 * 60),
+            },
+        }
+    }
+
+    // Count `amount` against a state at `now_ms`, returning the new state and the decision. Amounts larger than
+    // the limit count as the whole limit, so they still go through once nothing else was counted
+    fn apply(&self, state: Option<RateState>, now_ms: i64, amount: f64) -> (RateState, RateDecision) {
+        match *self {
+            RateStrategy::Unlimited => (state.unwrap_or_default(), RateDecision::Allowed),
+            RateStrategy::TokenBucket { capacity, per_second } => {
+                let mut state = state.unwrap_or(RateState {
+                    value: capacity,
+                    since_ms: now_ms,
+                });
+                let elapsed = (now_ms - state.since_ms).max(0) as f64 / 1000.0;
+                state.value = (state.value + elapsed * per_second).min(capacity);
+                state.since_ms = now_ms;
+
+                let amount = amount.min(capacity);
+                if state.value >= amount {
+                    state.value -= amount;
+                    (state, RateDecision::Allowed)
+                } else {
+                    let wait = Duration::from_secs_f64((amount - state.value) / per_second);
+                    (state, RateDecision::Limited(wait))
+                }
+            }
+            RateStrategy::FixedWindow { limit, window } => {
+                let window_ms = window.as_millis() as i64;
+                let mut state = state
+                    .filter(|state| now_ms - state.since_ms < window_ms)
+                    .unwrap_or(RateState {
+                        value: 0.0,
+                        since_ms: now_ms,
+                    });
+
+                let amount = amount.min(limit as f64);
+                if state.value + amount <= limit as f64 {
+                    state.value += amount;
+                    (state, RateDecision::Allowed)
+                } else {
+                    let wait = Duration::from_millis((state.since_ms + window_ms - now_ms).max(0) as u64);
+                    (state, RateDecision::Limited(wait))
+                }
+            }
+        }
+    }
+
+    // Give `amount` back to a state, as if it had never been counted
+    fn refund(&self, mut state: RateState, amount: f64) -> RateState {
+        match *self {
+            RateStrategy::Unlimited => {}
+            RateStrategy::TokenBucket { capacity, .. } => state.value = (state.value + amount).min(capacity),
+            RateStrategy::FixedWindow { .. } => state.value = (state.value - amount).max(0.0),
+        }
+        state
+    }
+}
+
+impl RateStore for MemoryRateStore {
+    fn load(&self, key: &str) -> Result<Option<RateState>> {
+        Ok(self.states.lock().unwrap().get(key).copied())
+    }
+
+    fn save(&self, key: &str, state: RateState) -> Result<()> {
+        self.states.lock().unwrap().insert(key.to_string(), state);
+        Ok(())
+    }
+}
+
+// Share one store between limiters, e.g. the per-user and daily reply limits
+impl<S: RateStore> RateStore for Arc<S> {
+    fn load(&self, key: &str) -> Result<Option<RateState>> {
+        (**self).load(key)
+    }
+
+    fn save(&self, key: &str, state: RateState) -> Result<()> {
+        (**self).save(key, state)
+    }
+}
+
+impl FileRateStore {
+    // Load storage from file, create new if file doesn't exist
+    pub fn load_from_file(file_path: &str) -> Result<Self> {
+        // Open existing file or create new one
+        let file = File::open(file_path).or_else(|_| File::create(file_path))?;
+        let reader = BufReader::new(file);
+        // Try to deserialize existing data or create empty storage
+        Ok(serde_json::from_reader(reader).unwrap_or_else(|_| FileRateStore {
+            file_path: file_path.to_string(),
+            states: Mutex::new(HashMap::new()),
+        }))
+    }
+
+    // Save current storage state to file
+    fn save_to_file(&self) -> Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.file_path)?;
+        let writer = BufWriter::new(file);
+        serde_json::to_writer(writer, &self)?;
+
+        Ok(())
+    }
+}
+
+impl RateStore for FileRateStore {
+    fn load(&self, key: &str) -> Result<Option<RateState>> {
+        Ok(self.states.lock().unwrap().get(key).copied())
+    }
+
+    fn save(&self, key: &str, state: RateState) -> Result<()> {
+        self.states.lock().unwrap().insert(key.to_string(), state);
+        self.save_to_file()
+    }
+}
+
+impl RateLimiter {
+    // Create limiter keeping its state in the given store
+    pub fn new(strategy: RateStrategy, store: Box<dyn RateStore>) -> Self {
+        Self {
+            strategy,
+            store,
+            lock: Mutex::new(()),
+            clock: system_clock(),
+        }
+    }
+
+    // Use another time source, e.g. a mock clock in tests
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    // Create limiter keeping its state in memory
+    pub fn in_memory(strategy: RateStrategy) -> Self {
+        Self::new(strategy, Box::new(MemoryRateStore::default()))
+    }
+
+    // Change the strategy, keeping the state counted so far
+    pub fn set_strategy(&mut self, strategy: RateStrategy) {
+        self.strategy = strategy;
+    }
+
+    // Count one call for a key if the limit allows it
+    pub fn try_acquire(&self, key: &str) -> Result<RateDecision> {
+        self.try_acquire_many(key, 1.0)
+    }
+
+    // Count `amount` units for a key if the limit allows it, e.g. the tokens of a request
+    pub fn try_acquire_many(&self, key: &str, amount: f64) -> Result<RateDecision> {
+        if self.strategy == RateStrategy::Unlimited {
+            return Ok(RateDecision::Allowed);
+        }
+
+        let _guard = self.lock.lock().unwrap();
+        let state = self.store.load(key)?;
+        let (state, decision) = self.strategy.apply(state, self.clock.now().timestamp_millis(), amount);
+        self.store.save(key, state)?;
+
+        Ok(decision)
+    }
+
+    // Give back one call counted for a key, e.g. when what it was counted for didn't happen after all
+    pub fn release(&self, key: &str) -> Result<()> {
+        if self.strategy == RateStrategy::Unlimited {
+            return Ok(());
+        }
+
+        let _guard = self.lock.lock().unwrap();
+        if let Some(state) = self.store.load(key)? {
+            self.store.save(key, self.strategy.refund(state, 1.0))?;
+        }
+
+        Ok(())
+    }
+
+    // Wait until the limit allows one more call for a key, then count it
+    pub async fn acquire(&self, key: &str) -> Result<()> {
+        self.acquire_many(key, 1.0).await
+    }
+
+    // Wait until the limit allows `amount` more units for a key, then count them
+    pub async fn acquire_many(&self, key: &str, amount: f64) -> Result<()> {
+        loop {
+            match self.try_acquire_many(key, amount)? {
+                RateDecision::Allowed => return Ok(()),
+                RateDecision::Limited(wait) => sleep(wait).await,
+            }
+        }
+    }
+}
+
+// Read a rate limit from an environment variable, `default` when unset and 0 for unlimited
+pub fn rate_limit_from_env(name: &str, default: u32) -> Result<u32> {
+    match config::var(name) {
+        Ok(limit) if !limit.is_empty() => limit
+            .parse()
+            .map_err(|err| anyhow!("{} must be a whole number: {}", name, err)),
+        _ => Ok(default),
+    }
+}
+
+// Read a time to live in days from an environment variable, `default_days` when unset and None (0) for forever
+pub fn ttl_from_env(name: &str, default_days: u32) -> Result<Option<Duration>> {
+    let days = match config::var(name) {
+        Ok(days) if !days.is_empty() => days
+            .parse()
+            .map_err(|err| anyhow!("{} must be a whole number of days: {}", name, err))?,
+        _ => default_days,
+    };
+
+    Ok((days > 0).then(|| Duration::from_secs(u64::from(days) * 24 * 60 * 60)))
+}
+
+// Whether a failed call is worth trying again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    // Timeouts, dropped connections, rate limits and server errors
+    Transient,
+    // Anything a retry won't fix, e.g. bad requests or refused downloads
+    Permanent,
+}
+
+// How often and how long to retry a failed call
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    // Attempts including the first one
+    pub max_attempts: u32,
+    // Delay cap before the second attempt, doubled for every further one
+    pub base_delay: Duration,
+    // Largest delay cap between attempts
+    pub max_delay: Duration,
+    // No attempt starts after this much time since the first one
+    pub max_elapsed: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            max_elapsed: Duration::from_secs(2 * 60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Delay after a failed attempt, random up to the exponential cap so parallel callers spread out
+    fn delay(&self, attempt: u32) -> Duration {
+        let cap = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+            .min(self.max_delay);
+
+        cap.mul_f64(rand::random::<f64>())
+    }
+}
+
+// Tell transient errors from permanent ones by the HTTP, Twitter or IO error behind them
+pub fn classify_error(err: &Error) -> ErrorClass {
+    for cause in err.chain() {
+        // Fail fast while a provider is down instead of waiting for its breaker
+        if cause.downcast_ref::<BreakerOpen>().is_some() {
+            return ErrorClass::Permanent;
+        }
+        // Hosts refused by the downloader stay refused
+        if cause.downcast_ref::<RefusedHost>().is_some() {
+            return ErrorClass::Permanent;
+        }
+        if let Some(err) = cause.downcast_ref::<ureq::Error>() {
+            return match err {
+                ureq::Error::Status(status, _) => status_class(*status),
+                ureq::Error::Transport(_) => ErrorClass::Transient,
+            };
+        }
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            return match err.status() {
+                Some(status) => status_class(status.as_u16()),
+                None if err.is_timeout() || err.is_connect() || err.is_request() => ErrorClass::Transient,
+                None => ErrorClass::Permanent,
+            };
+        }
+        if let Some(err) = cause.downcast_ref::<TwitterError>() {
+            match err {
+                TwitterError::RateLimit => return ErrorClass::Transient,
+                // The network error itself comes next in the chain
+                TwitterError::Network(_) | TwitterError::Io(_) => continue,
+                _ => return ErrorClass::Permanent,
+            }
+        }
+        if cause.downcast_ref::<io::Error>().is_some() {
+            return ErrorClass::Transient;
+        }
+    }
+
+    ErrorClass::Permanent
+}
+
+// How long the server asked to wait before trying again, from the Retry-After header of a 429 or 503 response
+pub fn retry_after(err: &Error) -> Option<Duration> {
+    err.chain().find_map(|cause| match cause.downcast_ref::<ureq::Error>() {
+        Some(ureq::Error::Status(429 | 503, response)) => response
+            .header("Retry-After")
+            .and_then(|value| value.trim().parse::<f64>().ok())
+            .filter(|secs| secs.is_finite() && *secs >= 0.0)
+            .map(Duration::from_secs_f64),
+        _ => None,
+    })
+}
+
+// Class of an HTTP error status
+fn status_class(status: u16) -> ErrorClass {
+    match status {
+        408 | 429 | 500..=599 => ErrorClass::Transient,
+        _ => ErrorClass::Permanent,
+    }
+}
+
+// Run an operation, retrying transient errors with jittered exponential backoff. Attempts, recoveries and
+// final failures are counted per operation, e.g. "retry.attempts.openai.image"
+pub async fn retry<T, F, Fut>(name: &str, policy: &RetryPolicy, mut op: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let started = Instant::now();
+    let mut attempt = 1;
+    loop {
+        metrics().increment(&format!("retry.attempts.{}", name), 1);
+        let err = match op().await {
+            Ok(value) => {
+                if attempt > 1 {
+                    metrics().increment(&format!("retry.recovered.{}", name), 1);
+                }
+                return Ok(value);
+            }
+            Err(err) => err,
+        };
+
+        // Wait at least as long as the server asked for
+        let delay = retry_after(&err).map_or(policy.delay(attempt), |wait| wait.max(policy.delay(attempt)));
+        if attempt >= policy.max_attempts
+            || classify_error(&err) == ErrorClass::Permanent
+            || started.elapsed() + delay > policy.max_elapsed
+        {
+            metrics().increment(&format!("retry.failures.{}", name), 1);
+            return Err(err);
+        }
+
+        warn!(operation = name, attempt, delay_ms = delay.as_millis() as u64, error = ?err, "Retrying");
+        sleep(delay).await;
+        attempt += 1;
+    }
+}
+
+// Default time limits of the stages of handling a mention in seconds, long enough for their retries
+const DEFAULT_STAGE_TIMEOUTS: &[(&str, u64)] = &[
+    ("profile", 60),
+    ("avatar", 60),
+    ("vision", 120),
+    ("embedding", 60),
+    ("prompt", 180),
+    ("image", 360),
+    ("post", 120),
+];
+
+// Error returned when a stage runs past its time limit
+#[derive(Debug, thiserror::Error)]
+#[error("{stage} stage timed out after {after:?}")]
+pub struct StageTimedOut {
+    pub stage: String,
+    pub after: Duration,
+}
+
+// Time limits of the stages of handling a mention
+#[derive(Debug, Clone, PartialEq)]
+pub struct StageTimeouts {
+    // Limit per stage, stages without one may run forever
+    limits: HashMap<String, Duration>,
+}
+
+impl StageTimeouts {
+    // Read the limits from STAGE_TIMEOUTS, comma-separated stage=seconds overriding the defaults, e.g.
+    // "vision=30,image=600". 0 removes the limit of a stage
+    pub fn from_env() -> Result<Self> {
+        let mut limits: HashMap<String, Duration> = DEFAULT_STAGE_TIMEOUTS
+            .iter()
+            .map(|(stage, secs)| (stage.to_string(), Duration::from_secs(*secs)))
+            .collect();
+
+        let overrides = config::var("STAGE_TIMEOUTS").unwrap_or_default();
+        for entry in overrides.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (stage, secs) = entry
+                .split_once('=')
+                .ok_or_else(|| anyhow!("STAGE_TIMEOUTS entry {} must look like stage=seconds", entry))?;
+            let stage = stage.trim();
+            if !DEFAULT_STAGE_TIMEOUTS.iter().any(|(name, _)| *name == stage) {
+                return Err(anyhow!("Unknown STAGE_TIMEOUTS stage {}", stage));
+            }
+            let secs: u64 = secs
+                .trim()
+                .parse()
+                .map_err(|err| anyhow!("STAGE_TIMEOUTS {} must be a whole number of seconds: {}", stage, err))?;
+
+            match secs {
+                0 => limits.remove(stage),
+                secs => limits.insert(stage.to_string(), Duration::from_secs(secs)),
+            };
+        }
+
+        Ok(Self { limits })
+    }
+
+    // Time limit of a stage, None for no limit
+    pub fn get(&self, stage: &str) -> Option<Duration> {
+        self.limits.get(stage).copied()
+    }
+
+    // Run a stage within its time limit, failing it with StageTimedOut once it runs past the limit. The limit can
+    // only cut in while the stage waits, so blocking calls in it must go through `blocking`
+    pub async fn run<T>(&self, stage: &str, future: impl Future<Output = Result<T>>) -> Result<T> {
+        let limit = match self.get(stage) {
+            Some(limit) => limit,
+            None => return future.await,
+        };
+
+        timeout(limit, future).await.unwrap_or_else(|_| {
+            warn!(stage, timeout_s = limit.as_secs(), "Stage timed out");
+            metrics().increment(&format!("stage.timeouts.{}", stage), 1);
+            Err(StageTimedOut {
+                stage: stage.to_string(),
+                after: limit,
+            }
+            .into())
+        })
+    }
+}
+
+// Run a blocking call, e.g. over ureq, on the blocking thread pool so it neither holds up an async worker thread
+// nor keeps a stage from timing out. A call given up on keeps running in the background until it returns
+pub async fn blocking<T, F>(op: F) -> Result<T>
+where
+    F: FnOnce() -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    task::spawn_blocking(op).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use chrono::Utc;
+
+    use super::*;
+    use crate::clock::MockClock;
+
+    // Limits of a single stage
+    fn timeouts(stage: &str, limit: Duration) -> StageTimeouts {
+        StageTimeouts {
+            limits: HashMap::from([(stage.to_string(), limit)]),
+        }
+    }
+
+    #[tokio::test]
+    async fn hanging_blocking_stage_times_out() {
+        let timeouts = timeouts("vision", Duration::from_millis(50));
+        let started = Instant::now();
+
+        // The single-threaded test runtime would hang here if the blocking call ran on it
+        let hang = blocking(|| {
+            thread::sleep(Duration::from_secs(1));
+            Ok(())
+        });
+        let err = timeouts.run("vision", hang).await.unwrap_err();
+
+        let timed_out = err.downcast_ref::<StageTimedOut>().unwrap();
+        assert_eq!(timed_out.stage, "vision");
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn stage_within_its_limit_returns_its_output() {
+        let timeouts = timeouts("vision", Duration::from_secs(5));
+
+        let output = timeouts.run("vision", blocking(|| Ok(42))).await.unwrap();
+
+        assert_eq!(output, 42);
+    }
+
+    #[tokio::test]
+    async fn stage_without_a_limit_runs_to_the_end() {
+        let timeouts = timeouts("vision", Duration::from_millis(1));
+
+        let output = timeouts
+            .run("image", async {
+                sleep(Duration::from_millis(20)).await;
+                Ok("done")
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(output, "done");
+    }
+
+    #[tokio::test]
+    async fn panicking_blocking_call_fails() {
+        let result: Result<()> = blocking(|| panic!("provider client panicked")).await;
+
+        assert!(result.is_err());
+    }
+
+    // Limiter driven by a mock clock
+    fn limiter(strategy: RateStrategy) -> (RateLimiter, Arc<MockClock>) {
+        let clock = Arc::new(MockClock::new(Utc::now()));
+        (RateLimiter::in_memory(strategy).with_clock(clock.clone()), clock)
+    }
+
+    #[test]
+    fn fixed_window_resets_once_the_window_is_over() {
+        let (limiter, clock) = limiter(RateStrategy::per_day(2));
+
+        assert_eq!(limiter.try_acquire("user").unwrap(), RateDecision::Allowed);
+        clock.advance(Duration::from_secs(60 * 60));
+        assert_eq!(limiter.try_acquire("user").unwrap(), RateDecision::Allowed);
+        assert_eq!(
+            limiter.try_acquire("user").unwrap(),
+            RateDecision::Limited(Duration::from_secs(23 * 60 * 60))
+        );
+        // Other keys have windows of their own
+        assert_eq!(limiter.try_acquire("other").unwrap(), RateDecision::Allowed);
+
+        clock.advance(Duration::from_secs(23 * 60 * 60 - 1));
+        assert_eq!(
+            limiter.try_acquire("user").unwrap(),
+            RateDecision::Limited(Duration::from_secs(1))
+        );
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limiter.try_acquire("user").unwrap(), RateDecision::Allowed);
+    }
+
+    #[test]
+    fn token_bucket_refills_with_time() {
+        let (limiter, clock) = limiter(RateStrategy::per_minute(60));
+
+        assert_eq!(limiter.try_acquire_many("openai", 60.0).unwrap(), RateDecision::Allowed);
+        assert_eq!(
+            limiter.try_acquire("openai").unwrap(),
+            RateDecision::Limited(Duration::from_secs(1))
+        );
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(limiter.try_acquire("openai").unwrap(), RateDecision::Allowed);
+        assert!(matches!(
+            limiter.try_acquire("openai").unwrap(),
+            RateDecision::Limited(_)
+        ));
+    }
+
+    #[test]
+    fn released_call_can_be_made_again() {
+        let (limiter, _) = limiter(RateStrategy::per_day(1));
+
+        assert_eq!(limiter.try_acquire("replies").unwrap(), RateDecision::Allowed);
+        limiter.release("replies").unwrap();
+        assert_eq!(limiter.try_acquire("replies").unwrap(), RateDecision::Allowed);
+        assert!(matches!(
+            limiter.try_acquire("replies").unwrap(),
+            RateDecision::Limited(_)
+        ));
+    }
+
+    #[test]
+    fn retry_delay_stays_under_its_doubling_cap() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            max_elapsed: Duration::from_secs(60),
+        };
+
+        for (attempt, cap_ms) in [(1, 100), (2, 200), (3, 400), (4, 800), (5, 1000), (9, 1000)] {
+            let delays: Vec<Duration> = (0..200).map(|_| policy.delay(attempt)).collect();
+            assert!(delays.iter().all(|delay| *delay <= Duration::from_millis(cap_ms)));
+            // Jittered, not a fixed delay
+            assert!(delays.iter().any(|delay| *delay != delays[0]));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_waits_between_transient_failures() {
+        let policy = RetryPolicy {
+            max_attempts: 4,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(200),
+            max_elapsed: Duration::from_secs(60),
+        };
+        let started = tokio::time::Instant::now();
+        let mut attempts = 0;
+
+        let result = retry("test", &policy, || {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                match attempt {
+                    1..=3 => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out").into()),
+                    _ => Ok(attempt),
+                }
+            }
+        })
+        .await;
+
+        assert_eq!(result.unwrap(), 4);
+        // Three waits capped at 100, 200 and 200 milliseconds
+        assert!(started.elapsed() <= Duration::from_millis(500));
+    }
+
+    // Error of an HTTP response with the given status line and headers, as a failed chat call returns it
+    fn status_error(status: u16, head: &str) -> Error {
+        let response: ureq::Response = format!("HTTP/1.1 {} Status\r\n{}\r\n", status, head).parse().unwrap();
+        ureq::Error::Status(status, response).into()
+    }
+
+    #[test]
+    fn rate_limits_and_server_errors_are_transient() {
+        for status in [408, 429, 500, 502, 503] {
+            let class = classify_error(&status_error(status, ""));
+            assert_eq!(class, ErrorClass::Transient, "{}", status);
+        }
+        for status in [400, 401, 404] {
+            let class = classify_error(&status_error(status, ""));
+            assert_eq!(class, ErrorClass::Permanent, "{}", status);
+        }
+    }
+
+    #[test]
+    fn retry_after_is_read_from_rate_limit_responses() {
+        let err = status_error(429, "Retry-After: 2.5\r\n").context("GPT-4 prompt failed");
+        assert_eq!(retry_after(&err), Some(Duration::from_millis(2500)));
+
+        assert_eq!(retry_after(&status_error(429, "")), None);
+        assert_eq!(retry_after(&status_error(400, "Retry-After: 2\r\n")), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_gives_up_on_permanent_errors() {
+        let mut attempts = 0;
+
+        let result: Result<()> = retry("test", &RetryPolicy::default(), || {
+            attempts += 1;
+            async { Err(anyhow!("bad request")) }
+        })
+        .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
 }
@@ -1,22 +1,78 @@
 // Import required dependencies
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+// Import logging
+use log::warn;
 // Import JWT related modules
 use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
 // Import serialization traits
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-// Import time related modules
-use std::time::{SystemTime, UNIX_EPOCH};
+// Import threading and time related modules
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
 // Import JSON macro
 use ureq::json;
 
 // Import local modules
-use crate::{http_client::HttpClient, image::Image};
+use crate::{
+    config,
+    http_client::HttpClient,
+    image::Image,
+    metrics::metrics,
+    rekognition::Rekognition,
+    utils::sanitize_keyword,
+};
 
 // Constants for API endpoints and scopes
-const VISION_API_URL: &'static str = "https://vision.googleapis.com/v1/images:annotate";
-const CLOULD_PLATFORM_URL: &'static str = "https://www.googleapis.com/auth/cloud-platform";
-const CLOULD_TOKEN_URL: &'static str = "https://oauth2.googleapis.com/token";
+const VISION_API_URL: &str = "https://vision.googleapis.com/v1/images:annotate";
+const CLOULD_PLATFORM_URL: &str = "https://www.googleapis.com/auth/cloud-platform";
+const CLOULD_TOKEN_URL: &str = "https://oauth2.googleapis.com/token";
+// Maximum number of images analyzed at the same time by analyze_batch and analyze_many
+const BATCH_CONCURRENCY: usize = 4;
+// Maximum number of OCR words passed on to prompts
+const MAX_TEXT_WORDS: usize = 8;
+// Label detection model used when VISION_MODEL is not set
+const DEFAULT_VISION_MODEL: &str = "builtin/stable";
+// Google service account credentials
+pub const SERVICE_ACCOUNT_FILE: &str = "service_account.json";
+// Number of labels requested when VISION_MAX_RESULTS is not set
+const DEFAULT_VISION_MAX_RESULTS: u8 = 10;
+// Longest image edge in pixels sent to the provider when VISION_MAX_EDGE is not set
+const DEFAULT_VISION_MAX_EDGE: u32 = 768;
+
+// Safe search likelihoods at which an avatar is flagged
+const UNSAFE_LIKELIHOODS: &[&str] = &["LIKELY", "VERY_LIKELY"];
+
+// Label words describing an artistic style
+const STYLE_WORDS: &[&str] = &[
+    "cartoon", "illustration", "art", "arts", "artwork", "drawing", "painting", "sketch", "anime", "pixel", "graphic",
+    "graphics", "design", "logo", "font", "clip", "animation", "animated", "fictional", "comic", "comics", "poster",
+    "pattern", "3d", "digital", "watercolor", "photography", "manga",
+];
+// Label words describing a color
+const COLOR_WORDS: &[&str] = &[
+    "red", "orange", "yellow", "green", "blue", "purple", "violet", "pink", "magenta", "brown", "black", "white",
+    "gray", "grey", "beige", "gold", "silver", "teal", "turquoise", "cyan", "maroon", "navy", "pastel", "colorfulness",
+    "tints",
+];
+// Label words describing a mood
+const MOOD_WORDS: &[&str] = &[
+    "happy", "happiness", "fun", "cute", "calm", "joy", "cool", "love", "peaceful", "dark", "darkness", "sad",
+    "angry", "playful", "cheerful", "mystery", "fear", "emotion", "cuteness",
+];
+
+// Label words describing a person's likeness, dropped when anonymizing
+const PERSON_WORDS: &[&str] = &[
+    "person", "human", "face", "facial", "hair", "hairstyle", "beard", "moustache", "eyebrow", "eyelash", "eye",
+    "nose", "lip", "mouth", "chin", "jaw", "cheek", "forehead", "skin", "smile", "selfie", "portrait", "head",
+    "neck", "ear", "tooth", "man", "woman", "boy", "girl", "child", "gentleman", "lady",
+];
 
 // JWT claims structure for Google authentication
 #[derive(Debug, Serialize)]
@@ -42,30 +98,739 @@ pub struct Response {
 // Structure for label annotations response
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LabelAnnotationsResponse {
-    #[serde(rename = "labelAnnotations")]
+    // Omitted by the API when no labels were found
+    #[serde(rename = "labelAnnotations", default)]
     pub label_annotations: Vec<LabelAnnotation>,
+    // Dominant colors, present when IMAGE_PROPERTIES was requested
+    #[serde(rename = "imagePropertiesAnnotation", default)]
+    pub image_properties: Option<ImagePropertiesAnnotation>,
+    // Detected text, the first entry holds the full text, present when TEXT_DETECTION was requested
+    #[serde(rename = "textAnnotations", default)]
+    pub text_annotations: Vec<TextAnnotation>,
+    // Detected faces, present when FACE_DETECTION was requested
+    #[serde(rename = "faceAnnotations", default)]
+    pub face_annotations: Vec<Value>,
+    // Unsafe content likelihoods, present when SAFE_SEARCH_DETECTION was requested
+    #[serde(rename = "safeSearchAnnotation", default)]
+    pub safe_search: Option<SafeSearchAnnotation>,
+    // Per-image error reported by the API
+    #[serde(default)]
+    pub error: Option<Status>,
+}
+
+// Structure for safe search annotation, each field is a likelihood such as VERY_UNLIKELY
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct SafeSearchAnnotation {
+    #[serde(default)]
+    pub adult: String,
+    #[serde(default)]
+    pub spoof: String,
+    #[serde(default)]
+    pub medical: String,
+    #[serde(default)]
+    pub violence: String,
+    #[serde(default)]
+    pub racy: String,
+}
+
+// Structure for individual text annotation
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TextAnnotation {
+    // Detected text
+    #[serde(default)]
+    pub description: String,
+}
+
+// Structure for image properties annotation
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ImagePropertiesAnnotation {
+    #[serde(rename = "dominantColors", default)]
+    pub dominant_colors: DominantColorsAnnotation,
+}
+
+// Structure for dominant colors annotation
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct DominantColorsAnnotation {
+    #[serde(default)]
+    pub colors: Vec<ColorInfo>,
+}
+
+// Structure for individual color annotation
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ColorInfo {
+    // RGB components, omitted by the API when zero
+    #[serde(default)]
+    pub color: Value,
+    // Confidence score
+    #[serde(default)]
+    pub score: f64,
+    // Fraction of pixels with this color
+    #[serde(rename = "pixelFraction", default)]
+    pub pixel_fraction: f64,
+}
+
+// Structure for an error status returned by the Vision API
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Status {
+    // gRPC status code
+    #[serde(default)]
+    pub code: i32,
+    // Developer-facing error message
+    #[serde(default)]
+    pub message: String,
 }
 
 // Structure for individual label annotation
 #[derive(Debug, Serialize, Deserialize)]
 pub struct LabelAnnotation {
     // Machine-generated ID
+    #[serde(default)]
     pub mid: String,
     // Human-readable description
     pub description: String,
     // Confidence score
     pub score: f64,
     // Relevance to the overall image
+    #[serde(default)]
     pub topicality: f64,
 }
 
-// Structure for Vision API request
+// Structure for vision request, shared by all providers
 #[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct GoogleVisionRequest {
+pub struct VisionRequest {
     // Image to analyze
     pub image: Image,
     // Maximum number of results to return
     pub max_results: u8,
+    // Whether to extract text present in the image
+    #[serde(default)]
+    pub detect_text: bool,
+}
+
+// Structure for a dominant color of the analyzed image
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DominantColor {
+    pub red: u8,
+    pub green: u8,
+    pub blue: u8,
+    // Fraction of the image covered by the color
+    pub fraction: f64,
+}
+
+// Overall tone of the image derived from its dominant colors
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Mood {
+    Dark,
+    Pastel,
+    Vibrant,
+    Muted,
+    Unknown,
+}
+
+// Category of an image label, used to fill separate prompt slots
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Category {
+    #[default]
+    Subject,
+    Style,
+    Color,
+    Mood,
+}
+
+// Structure for an image label with its confidence
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Keyword {
+    // Human-readable label
+    pub label: String,
+    // Confidence score between 0 and 1
+    pub score: f64,
+    // What the label describes
+    #[serde(default)]
+    pub category: Category,
+}
+
+// Structure for the result of a full image analysis
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct VisionReport {
+    // Labels sorted by confidence
+    pub keywords: Vec<Keyword>,
+    // Dominant colors sorted by coverage
+    pub colors: Vec<DominantColor>,
+    // Overall tone of the image
+    pub mood: Mood,
+    // Number of real human faces detected
+    #[serde(default)]
+    pub faces: u32,
+    // Unsafe content categories the avatar was flagged for
+    #[serde(default)]
+    pub unsafe_categories: Vec<String>,
+    // Words of text found in the image, when text detection was requested
+    #[serde(default)]
+    pub text: Vec<String>,
+    // Name of the provider that produced the report
+    #[serde(default)]
+    pub provider: String,
+}
+
+// Policy for text found in the avatar, such as handles or slogans
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum TextPolicy {
+    // Do not run text detection
+    Off,
+    // Echo the text in the prompt
+    Include,
+    // Keep labels that merely repeat the text out of the prompt
+    Exclude,
+}
+
+// Policy applied when an avatar shows a real human face
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum FacePolicy {
+    // Use the avatar as-is
+    Allow,
+    // Describe style and colors only, never the person
+    Anonymize,
+    // Decline the request
+    Reject,
+}
+
+// Safety assessment of an analyzed image, for deciding whether to proceed, anonymize or refuse
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum SafetyVerdict {
+    // Nothing to act on
+    Safe,
+    // Shows this many real human faces
+    Faces(u32),
+    // Flagged for these unsafe content categories
+    Unsafe(Vec<String>),
+}
+
+// Extra image analyzed together with the avatar
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ContextSource {
+    // The user's profile banner
+    Banner,
+    // Photos attached to the mention
+    Photos,
+}
+
+// Trait for image description functionality
+pub trait VisionService: Send + Sync {
+    // Short provider name used in logs and reports
+    fn name(&self) -> &str;
+
+    // Create descriptions sorted by confidence from request parameters
+    fn create_desc(&self, request: VisionRequest) -> Result<Vec<String>>;
+
+    // Create labels together with colors and mood, providers without color support report keywords only
+    fn create_report(&self, request: VisionRequest) -> Result<VisionReport> {
+        // Without scores every label is treated as certain, in the provider's order
+        let keywords = self
+            .create_desc(request)?
+            .into_iter()
+            .map(|label| Keyword::new(label, 1.0))
+            .collect();
+        let mut report = VisionReport::from_keywords(keywords);
+        report.provider = self.name().to_string();
+        Ok(report)
+    }
+
+    // Download and analyze each URL with bounded concurrency, returning results in input order. Images are
+    // downscaled to `max_edge` like the avatar, 0 keeps their size
+    fn analyze_batch(&self, urls: &[String], max_results: u8, max_edge: u32) -> Vec<Result<VisionReport>> {
+        in_parallel(urls, |url| {
+            Image::from_url(url)
+                .and_then(|image| image.normalized())
+                .and_then(|image| image.downscaled(max_edge))
+                .and_then(|image| {
+                    self.create_report(VisionRequest {
+                        image,
+                        max_results,
+                        detect_text: false,
+                    })
+                })
+        })
+    }
+
+    // Download and analyze several images of one request and fuse them into a single report, the first image
+    // leading colors and mood. Images that fail are skipped as long as one succeeds
+    fn analyze_many(&self, urls: &[String], max_results: u8, max_edge: u32) -> Result<VisionReport> {
+        fuse_results(self.analyze_batch(urls, max_results, max_edge))
+    }
+}
+
+// Run `analyze` on each item with bounded concurrency, returning results in input order
+fn in_parallel<T: Sync>(items: &[T], analyze: impl Fn(&T) -> Result<VisionReport> + Sync) -> Vec<Result<VisionReport>> {
+    let next = AtomicUsize::new(0);
+    let slots: Vec<Mutex<Option<Result<VisionReport>>>> = items.iter().map(|_| Mutex::new(None)).collect();
+
+    thread::scope(|scope| {
+        for _ in 0..BATCH_CONCURRENCY.min(items.len()) {
+            scope.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                let Some(item) = items.get(index) else {
+                    break;
+                };
+
+                *slots[index].lock().unwrap() = Some(analyze(item));
+            });
+        }
+    });
+
+    slots
+        .into_iter()
+        .map(|slot| {
+            slot.into_inner()
+                .unwrap()
+                .unwrap_or_else(|| Err(anyhow!("Image was not analyzed")))
+        })
+        .collect()
+}
+
+// Fuse the reports that succeeded in order, failing with the first error only when none did
+fn fuse_results(results: Vec<Result<VisionReport>>) -> Result<VisionReport> {
+    let mut reports = Vec::new();
+    let mut first_error = None;
+    for result in results {
+        match result {
+            Ok(report) => reports.push(report),
+            Err(err) => {
+                first_error.get_or_insert(err);
+            }
+        }
+    }
+
+    VisionReport::fuse(reports).ok_or_else(|| first_error.unwrap_or_else(|| anyhow!("No images to analyze")))
+}
+
+// Ordered list of vision providers, each tried when the previous one fails
+pub struct VisionChain {
+    providers: Vec<Box<dyn VisionService>>,
+}
+
+impl DominantColor {
+    // Create color from RGB components
+    pub fn new(red: f64, green: f64, blue: f64, fraction: f64) -> Self {
+        Self {
+            red: red.clamp(0.0, 255.0) as u8,
+            green: green.clamp(0.0, 255.0) as u8,
+            blue: blue.clamp(0.0, 255.0) as u8,
+            fraction,
+        }
+    }
+
+    // Get color as a hex string
+    pub fn hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.red, self.green, self.blue)
+    }
+
+    // Get a plain color name usable in prompts
+    pub fn name(&self) -> &'static str {
+        let (hue, saturation, lightness) = self.hsl();
+
+        if lightness < 0.15 {
+            return "black";
+        }
+        if lightness > 0.9 {
+            return "white";
+        }
+        if saturation < 0.15 {
+            return "gray";
+        }
+
+        match hue {
+            h if !(15.0..335.0).contains(&h) && lightness > 0.7 => "pink",
+            h if h < 15.0 => "red",
+            h if h < 45.0 && lightness < 0.4 => "brown",
+            h if h < 45.0 => "orange",
+            h if h < 70.0 => "yellow",
+            h if h < 165.0 => "green",
+            h if h < 195.0 => "teal",
+            h if h < 255.0 => "blue",
+            h if h < 290.0 => "purple",
+            h if h < 335.0 => "pink",
+            _ => "red",
+        }
+    }
+
+    // Convert to hue (degrees), saturation and lightness (0..1)
+    fn hsl(&self) -> (f64, f64, f64) {
+        let red = self.red as f64 / 255.0;
+        let green = self.green as f64 / 255.0;
+        let blue = self.blue as f64 / 255.0;
+
+        let max = red.max(green).max(blue);
+        let min = red.min(green).min(blue);
+        let lightness = (max + min) / 2.0;
+        let delta = max - min;
+
+        if delta == 0.0 {
+            return (0.0, 0.0, lightness);
+        }
+
+        let saturation = delta / (1.0 - (2.0 * lightness - 1.0).abs());
+        let hue = if max == red {
+            60.0 * (((green - blue) / delta).rem_euclid(6.0))
+        } else if max == green {
+            60.0 * ((blue - red) / delta + 2.0)
+        } else {
+            60.0 * ((red - green) / delta + 4.0)
+        };
+
+        (hue, saturation, lightness)
+    }
+}
+
+impl Category {
+    // Classify a label by the words it contains, anything unrecognized is a subject
+    pub fn classify(label: &str) -> Self {
+        let lower = label.to_lowercase();
+        let words: Vec<&str> = lower.split(|c: char| !c.is_alphanumeric()).collect();
+        let matches = |vocabulary: &[&str]| words.iter().any(|word| vocabulary.contains(word));
+
+        if matches(STYLE_WORDS) {
+            Category::Style
+        } else if matches(COLOR_WORDS) {
+            Category::Color
+        } else if matches(MOOD_WORDS) {
+            Category::Mood
+        } else {
+            Category::Subject
+        }
+    }
+}
+
+impl Keyword {
+    // Create keyword and classify its label
+    pub fn new(label: String, score: f64) -> Self {
+        let category = Category::classify(&label);
+        Self { label, score, category }
+    }
+}
+
+impl Mood {
+    // Derive the mood from coverage-weighted lightness and saturation
+    pub fn from_colors(colors: &[DominantColor]) -> Self {
+        let total: f64 = colors.iter().map(|color| color.fraction).sum();
+        if total <= 0.0 {
+            return Mood::Unknown;
+        }
+
+        let (saturation, lightness) = colors.iter().fold((0.0, 0.0), |(saturation, lightness), color| {
+            let (_, s, l) = color.hsl();
+            (saturation + s * color.fraction, lightness + l * color.fraction)
+        });
+        let saturation = saturation / total;
+        let lightness = lightness / total;
+
+        if lightness < 0.3 {
+            Mood::Dark
+        } else if lightness > 0.7 && saturation > 0.2 {
+            Mood::Pastel
+        } else if saturation > 0.55 {
+            Mood::Vibrant
+        } else {
+            Mood::Muted
+        }
+    }
+
+    // Get mood as a lowercase word
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Mood::Dark => "dark",
+            Mood::Pastel => "pastel",
+            Mood::Vibrant => "vibrant",
+            Mood::Muted => "muted",
+            Mood::Unknown => "unknown",
+        }
+    }
+}
+
+impl VisionReport {
+    // Create report with keywords only
+    pub fn from_keywords(keywords: Vec<Keyword>) -> Self {
+        Self {
+            keywords,
+            colors: Vec::new(),
+            mood: Mood::Unknown,
+            faces: 0,
+            unsafe_categories: Vec::new(),
+            text: Vec::new(),
+            provider: String::new(),
+        }
+    }
+
+    // Create report from keywords and colors, keeping the five most prominent colors
+    pub fn from_colors(keywords: Vec<Keyword>, mut colors: Vec<DominantColor>) -> Self {
+        colors.sort_by(|a, b| b.fraction.total_cmp(&a.fraction));
+        colors.truncate(5);
+        let mood = Mood::from_colors(&colors);
+
+        Self {
+            keywords,
+            colors,
+            mood,
+            faces: 0,
+            unsafe_categories: Vec::new(),
+            text: Vec::new(),
+            provider: String::new(),
+        }
+    }
+
+    // Fuse reports of several images from one request. The first report leads colors and mood, labels found
+    // in several images are merged and ranked higher, and safety findings of any image carry over
+    pub fn fuse(reports: Vec<VisionReport>) -> Option<VisionReport> {
+        let mut reports = reports.into_iter();
+        let mut fused = reports.next()?;
+        let limit = fused.keywords.len();
+
+        for report in reports {
+            for keyword in report.keywords {
+                match fused
+                    .keywords
+                    .iter_mut()
+                    .find(|existing| existing.label.eq_ignore_ascii_case(&keyword.label))
+                {
+                    // Combine as independent evidence, so two 0.6 sightings beat one 0.8
+                    Some(existing) => existing.score = 1.0 - (1.0 - existing.score) * (1.0 - keyword.score),
+                    None => fused.keywords.push(keyword),
+                }
+            }
+
+            fused.faces += report.faces;
+            for category in report.unsafe_categories {
+                if !fused.unsafe_categories.contains(&category) {
+                    fused.unsafe_categories.push(category);
+                }
+            }
+            for word in report.text {
+                if !fused.text.contains(&word) {
+                    fused.text.push(word);
+                }
+            }
+        }
+
+        // Keep the prompt as long as a single image's would be
+        fused.keywords.sort_by(|a, b| b.score.total_cmp(&a.score));
+        fused.keywords.truncate(limit.max(1));
+
+        Some(fused)
+    }
+
+    // Get the safety verdict, unsafe content outranks faces
+    pub fn safety(&self) -> SafetyVerdict {
+        if self.is_unsafe() {
+            SafetyVerdict::Unsafe(self.unsafe_categories.clone())
+        } else if self.has_faces() {
+            SafetyVerdict::Faces(self.faces)
+        } else {
+            SafetyVerdict::Safe
+        }
+    }
+
+    // Check whether a real human face was detected
+    pub fn has_faces(&self) -> bool {
+        self.faces > 0
+    }
+
+    // Check whether the avatar was flagged as unsafe
+    pub fn is_unsafe(&self) -> bool {
+        !self.unsafe_categories.is_empty()
+    }
+
+    // Get keywords that are safe to place in a model prompt, sorted by confidence
+    pub fn sanitized_keywords(&self) -> Vec<Keyword> {
+        let mut sanitized: Vec<Keyword> = Vec::new();
+
+        for keyword in &self.keywords {
+            let Some(label) = sanitize_keyword(&keyword.label) else {
+                continue;
+            };
+            if !sanitized.iter().any(|existing| existing.label.eq_ignore_ascii_case(&label)) {
+                sanitized.push(Keyword {
+                    label,
+                    score: keyword.score,
+                    category: keyword.category,
+                });
+            }
+        }
+
+        sanitized
+    }
+
+    // Get text words that are safe to place in a model prompt
+    pub fn sanitized_text(&self) -> Vec<String> {
+        self.text
+            .iter()
+            .filter_map(|word| sanitize_keyword(word))
+            .take(MAX_TEXT_WORDS)
+            .collect()
+    }
+
+    // Drop every keyword that repeats text found in the image
+    pub fn without_text(mut self) -> Self {
+        let words: Vec<String> = self.text.iter().map(|word| word.to_lowercase()).collect();
+        self.keywords
+            .retain(|keyword| !words.contains(&keyword.label.to_lowercase()));
+        self
+    }
+
+    // Drop every keyword describing the person, keeping style, objects and colors
+    pub fn anonymized(mut self) -> Self {
+        self.keywords.retain(|keyword| {
+            !keyword
+                .label
+                .to_lowercase()
+                .split(|c: char| !c.is_alphanumeric())
+                .any(|word| PERSON_WORDS.contains(&word))
+        });
+        self
+    }
+
+    // Describe the palette for the image prompt, e.g. "pastel palette of pink, blue and white"
+    pub fn palette(&self) -> Option<String> {
+        if self.mood == Mood::Unknown {
+            return None;
+        }
+
+        let mut names: Vec<&str> = Vec::new();
+        for color in &self.colors {
+            if !names.contains(&color.name()) {
+                names.push(color.name());
+            }
+        }
+        names.truncate(3);
+
+        let names = match names.split_last() {
+            Some((last, rest)) if !rest.is_empty() => format!("{} and {}", rest.join(", "), last),
+            Some((last, _)) => last.to_string(),
+            None => return None,
+        };
+
+        Some(format!("{} palette of {}", self.mood.as_str(), names))
+    }
+}
+
+impl TextPolicy {
+    // Read the policy from VISION_OCR (off by default)
+    pub fn from_env() -> Result<Self> {
+        let policy = config::var("VISION_OCR").unwrap_or_default();
+
+        match policy.as_str() {
+            "" | "off" => Ok(TextPolicy::Off),
+            "include" => Ok(TextPolicy::Include),
+            "exclude" => Ok(TextPolicy::Exclude),
+            other => Err(anyhow!("Unknown VISION_OCR {}", other)),
+        }
+    }
+}
+
+impl FacePolicy {
+    // Read the policy from FACE_POLICY (anonymize by default)
+    pub fn from_env() -> Result<Self> {
+        let policy = config::var("FACE_POLICY").unwrap_or_default();
+
+        match policy.as_str() {
+            "allow" => Ok(FacePolicy::Allow),
+            "" | "anonymize" => Ok(FacePolicy::Anonymize),
+            "reject" => Ok(FacePolicy::Reject),
+            other => Err(anyhow!("Unknown FACE_POLICY {}", other)),
+        }
+    }
+}
+
+impl ContextSource {
+    // Read the extra images from VISION_CONTEXT, comma-separated (none by default)
+    pub fn from_env() -> Result<Vec<Self>> {
+        let sources = config::var("VISION_CONTEXT").unwrap_or_default();
+
+        sources
+            .split(',')
+            .map(str::trim)
+            .filter(|source| !source.is_empty())
+            .map(|source| match source {
+                "banner" => Ok(ContextSource::Banner),
+                "photos" => Ok(ContextSource::Photos),
+                other => Err(anyhow!("Unknown VISION_CONTEXT {}", other)),
+            })
+            .collect()
+    }
+}
+
+impl VisionChain {
+    // Create chain from providers in order of preference
+    pub fn new(providers: Vec<Box<dyn VisionService>>) -> Self {
+        Self { providers }
+    }
+
+    // Run the call against each provider until one succeeds
+    fn first_success<T>(&self, call: impl Fn(&dyn VisionService) -> Result<T>) -> Result<T> {
+        let mut last_error = anyhow!("No vision providers configured");
+
+        for provider in &self.providers {
+            match call(provider.as_ref()) {
+                Ok(result) => return Ok(result),
+                Err(err) => {
+                    warn!("Vision provider {} failed: {:?}", provider.name(), err);
+                    metrics().increment(&format!("vision.failures.{}", provider.name()), 1);
+                    last_error = err;
+                }
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+// Implementation of VisionService trait for the fallback chain
+impl VisionService for VisionChain {
+    fn name(&self) -> &str {
+        "chain"
+    }
+
+    fn create_desc(&self, request: VisionRequest) -> Result<Vec<String>> {
+        self.first_success(|provider| provider.create_desc(request.clone()))
+    }
+
+    fn create_report(&self, request: VisionRequest) -> Result<VisionReport> {
+        self.first_success(|provider| provider.create_report(request.clone()))
+    }
+}
+
+// Read the number of labels requested per image from VISION_MAX_RESULTS (10 by default)
+pub fn max_results_from_env() -> Result<u8> {
+    match config::var("VISION_MAX_RESULTS") {
+        Ok(value) if !value.is_empty() => Ok(value.parse()?),
+        _ => Ok(DEFAULT_VISION_MAX_RESULTS),
+    }
+}
+
+// Read the longest image edge sent to the provider from VISION_MAX_EDGE (768 by default, 0 disables downscaling)
+pub fn max_edge_from_env() -> Result<u32> {
+    match config::var("VISION_MAX_EDGE") {
+        Ok(value) if !value.is_empty() => Ok(value.parse()?),
+        _ => Ok(DEFAULT_VISION_MAX_EDGE),
+    }
+}
+
+// Create the vision providers listed in VISION_PROVIDER (google by default), comma-separated in fallback order
+pub fn create_vision_service() -> Result<Box<dyn VisionService>> {
+    let providers = config::var("VISION_PROVIDER").unwrap_or_default();
+
+    let mut services: Vec<Box<dyn VisionService>> = Vec::new();
+    for provider in providers.split(',').map(str::trim) {
+        match provider {
+            "" | "google" => services.push(Box::new(GoogleVision::new()?)),
+            "rekognition" => services.push(Box::new(Rekognition::new()?)),
+            other => return Err(anyhow!("Unknown VISION_PROVIDER {}", other)),
+        }
+    }
+
+    if services.len() == 1 {
+        return Ok(services.remove(0));
+    }
+
+    Ok(Box::new(VisionChain::new(services)))
 }
 
 // Main Google Vision API client
@@ -75,6 +840,8 @@ pub struct GoogleVision {
     client_email: String,
     // Private key for authentication
     private_key: String,
+    // Label detection model, builtin/stable or builtin/latest
+    model: String,
     // HTTP client instance
     http_client: HttpClient,
 }
@@ -83,22 +850,29 @@ impl GoogleVision {
     // Initialize new Vision API client
     pub fn new() -> Result<Self> {
         // Load service account credentials
-        let service_account_key: Value = serde_json::from_str(&std::fs::read_to_string("service_account.json")?)?;
+        let service_account_key: Value = serde_json::from_str(&std::fs::read_to_string(SERVICE_ACCOUNT_FILE)?)?;
         let client_email = service_account_key["client_email"].as_str().unwrap();
         let private_key = service_account_key["private_key"].as_str().unwrap();
+        let model = config::var("VISION_MODEL")
+            .ok()
+            .filter(|model| !model.is_empty())
+            .unwrap_or_else(|| DEFAULT_VISION_MODEL.to_string());
 
         Ok(Self {
             client_email: client_email.into(),
             private_key: private_key.into(),
+            model,
             http_client: HttpClient::new(),
         })
     }
+}
 
-    // Generate image descriptions using Vision API
-    pub fn create_desc(&self, request: GoogleVisionRequest) -> Result<Vec<String>> {
+impl GoogleVision {
+    // Annotate a single image with the given Vision API features
+    fn annotate(&self, image: &Image, features: Value) -> Result<LabelAnnotationsResponse> {
         // Get current timestamp
         let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as usize;
-        
+
         // Create JWT claims
         let claims = Claims {
             iss: self.client_email.clone(),
@@ -122,39 +896,362 @@ impl GoogleVision {
             }),
         )?;
 
-        let access_token = response["access_token"].to_string();
+        let access_token = response["access_token"]
+            .as_str()
+            .ok_or_else(|| anyhow!("Missing access_token in token response: {}", response))?;
 
         // Make Vision API request
         let response = self.http_client.post_with_auth(
             VISION_API_URL,
-            &access_token,
+            access_token,
             json!({
               "requests": [
                 {
                   "image": {
-                    "content": request.image.base64
+                    "content": image.base64
                   },
-                  "features": [
-                    {
-                      "type": "LABEL_DETECTION",
-                      "maxResults": request.max_results
-                    }
-                  ]
+                  "features": features
                 }
               ]
             }),
         )?;
 
-        // Parse and sort response
+        // Parse response
         let response: Response = serde_json::from_str(&response)?;
-        let mut descriptions: Vec<(&str, f64)> = response.responses[0]
-            .label_annotations
+        let annotations = response
+            .responses
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("Vision API returned no responses"))?;
+
+        if let Some(error) = &annotations.error {
+            return Err(anyhow!("Vision API error {}: {}", error.code, error.message));
+        }
+
+        Ok(annotations)
+    }
+}
+
+// Implementation of VisionService trait for Google Vision
+impl VisionService for GoogleVision {
+    fn name(&self) -> &str {
+        "google"
+    }
+
+    // Generate image descriptions using Vision API
+    fn create_desc(&self, request: VisionRequest) -> Result<Vec<String>> {
+        let annotations = self.annotate(
+            &request.image,
+            json!([{ "type": "LABEL_DETECTION", "maxResults": request.max_results, "model": self.model }]),
+        )?;
+
+        Ok(sorted_keywords(&annotations)
+            .into_iter()
+            .map(|keyword| keyword.label)
+            .collect())
+    }
+
+    // Generate image descriptions and dominant colors in a single Vision API call
+    fn create_report(&self, request: VisionRequest) -> Result<VisionReport> {
+        let mut features = json!([
+            { "type": "LABEL_DETECTION", "maxResults": request.max_results, "model": self.model },
+            { "type": "IMAGE_PROPERTIES" },
+            { "type": "FACE_DETECTION" },
+            { "type": "SAFE_SEARCH_DETECTION" }
+        ]);
+        if request.detect_text {
+            features.as_array_mut().unwrap().push(json!({ "type": "TEXT_DETECTION" }));
+        }
+        let annotations = self.annotate(&request.image, features)?;
+
+        let colors = annotations
+            .image_properties
+            .iter()
+            .flat_map(|properties| &properties.dominant_colors.colors)
+            .map(|info| {
+                let channel = |name: &str| info.color[name].as_f64().unwrap_or(0.0);
+                DominantColor::new(channel("red"), channel("green"), channel("blue"), info.pixel_fraction)
+            })
+            .collect();
+
+        let mut report = VisionReport::from_colors(sorted_keywords(&annotations), colors);
+        report.faces = annotations.face_annotations.len() as u32;
+        report.provider = self.name().to_string();
+
+        if let Some(text) = annotations.text_annotations.first() {
+            report.text = text.description.split_whitespace().map(str::to_string).collect();
+        }
+
+        if let Some(safe_search) = &annotations.safe_search {
+            let categories = [
+                ("adult", &safe_search.adult),
+                ("violence", &safe_search.violence),
+                ("racy", &safe_search.racy),
+            ];
+            report.unsafe_categories = categories
+                .iter()
+                .filter(|(_, likelihood)| UNSAFE_LIKELIHOODS.contains(&likelihood.as_str()))
+                .map(|(category, _)| category.to_string())
+                .collect();
+        }
+
+        Ok(report)
+    }
+}
+
+// Get label descriptions with their scores, sorted by score
+fn sorted_keywords(annotations: &LabelAnnotationsResponse) -> Vec<Keyword> {
+    let mut keywords: Vec<Keyword> = annotations
+        .label_annotations
+        .iter()
+        .map(|annotation| Keyword::new(annotation.description.clone(), annotation.score))
+        .collect();
+    keywords.sort_by(|a, b| b.score.total_cmp(&a.score));
+
+    keywords
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    // Provider returning fixed labels, or failing when it has none, and noting each call
+    struct FakeProvider {
+        name: &'static str,
+        labels: Option<Vec<&'static str>>,
+        calls: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    impl VisionService for FakeProvider {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn create_desc(&self, _request: VisionRequest) -> Result<Vec<String>> {
+            self.calls.lock().unwrap().push(self.name);
+            match &self.labels {
+                Some(labels) => Ok(labels.iter().map(|label| label.to_string()).collect()),
+                None => Err(anyhow!("{} is down", self.name)),
+            }
+        }
+    }
+
+    // Chain of providers, each given as its name and labels, with the log of calls made to them
+    fn chain(providers: &[(&'static str, Option<Vec<&'static str>>)]) -> (VisionChain, Arc<Mutex<Vec<&'static str>>>) {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let providers = providers
+            .iter()
+            .map(|(name, labels)| {
+                Box::new(FakeProvider {
+                    name,
+                    labels: labels.clone(),
+                    calls: calls.clone(),
+                }) as Box<dyn VisionService>
+            })
+            .collect();
+
+        (VisionChain::new(providers), calls)
+    }
+
+    fn request() -> VisionRequest {
+        VisionRequest {
+            image: Image::from_base64(String::new()),
+            max_results: 10,
+            detect_text: false,
+        }
+    }
+
+    fn color(red: u8, green: u8, blue: u8, fraction: f64) -> DominantColor {
+        DominantColor::new(red as f64, green as f64, blue as f64, fraction)
+    }
+
+    fn scored(label: &str, score: f64) -> Keyword {
+        Keyword::new(label.to_string(), score)
+    }
+
+    fn report(labels: &[&str]) -> VisionReport {
+        let keyword = |label: &&str| Keyword::new(label.to_string(), 0.9);
+        VisionReport::from_keywords(labels.iter().map(keyword).collect())
+    }
+
+    fn labels(report: &VisionReport) -> Vec<&str> {
+        report.keywords.iter().map(|keyword| keyword.label.as_str()).collect()
+    }
+
+    #[test]
+    fn batch_results_keep_input_order_around_failures() {
+        let items: Vec<u64> = (0..10).collect();
+
+        // Earlier items take longer, so they finish after later ones
+        let results = in_parallel(&items, |&item| {
+            thread::sleep(std::time::Duration::from_millis(20 - 2 * item));
+            match item % 3 {
+                0 => Err(anyhow!("image {} failed", item)),
+                _ => Ok(report(&[&format!("cat{}", item)])),
+            }
+        });
+
+        assert_eq!(results.len(), items.len());
+        for (item, result) in items.iter().zip(&results) {
+            match result {
+                Ok(report) => assert_eq!(labels(report), [format!("cat{}", item)]),
+                Err(err) => assert_eq!(err.to_string(), format!("image {} failed", item)),
+            }
+        }
+    }
+
+    #[test]
+    fn failed_images_are_skipped_when_fusing() {
+        let results = vec![
+            Err(anyhow!("banner failed")),
+            Ok(report(&["cat", "hat"])),
+            Err(anyhow!("photo failed")),
+            Ok(report(&["hat", "beach"])),
+        ];
+
+        let fused = fuse_results(results).unwrap();
+
+        // Seen in both images, the hat outranks the cat and the fused report keeps the first one's size
+        assert_eq!(labels(&fused), ["hat", "cat"]);
+    }
+
+    #[test]
+    fn first_error_is_kept_when_every_image_fails() {
+        let results = vec![Err(anyhow!("banner failed")), Err(anyhow!("photo failed"))];
+
+        let err = fuse_results(results).unwrap_err();
+
+        assert_eq!(err.to_string(), "banner failed");
+        assert!(fuse_results(Vec::new()).is_err());
+    }
+
+    #[test]
+    fn labels_are_classified_by_their_words() {
+        assert_eq!(Category::classify("Cartoon"), Category::Style);
+        assert_eq!(Category::classify("Watercolor painting of roses"), Category::Style);
+        assert_eq!(Category::classify("Blue sky"), Category::Color);
+        assert_eq!(Category::classify("dark-blue"), Category::Color);
+        assert_eq!(Category::classify("Cute"), Category::Mood);
+        assert_eq!(Category::classify("Cat"), Category::Subject);
+        // Whole words only, "artichoke" is no art
+        assert_eq!(Category::classify("Artichoke"), Category::Subject);
+    }
+
+    #[test]
+    fn colors_are_named_by_hue_and_lightness() {
+        assert_eq!(color(0, 0, 0, 1.0).name(), "black");
+        assert_eq!(color(255, 255, 255, 1.0).name(), "white");
+        assert_eq!(color(128, 128, 128, 1.0).name(), "gray");
+        assert_eq!(color(255, 0, 0, 1.0).name(), "red");
+        assert_eq!(color(255, 165, 0, 1.0).name(), "orange");
+        assert_eq!(color(139, 69, 19, 1.0).name(), "brown");
+        assert_eq!(color(0, 128, 0, 1.0).name(), "green");
+        assert_eq!(color(0, 0, 255, 1.0).name(), "blue");
+        assert_eq!(color(128, 0, 255, 1.0).name(), "purple");
+        assert_eq!(color(255, 182, 193, 1.0).name(), "pink");
+        assert_eq!(color(255, 0, 0, 1.0).hex(), "#ff0000");
+    }
+
+    #[test]
+    fn mood_follows_the_weighted_colors() {
+        assert_eq!(Mood::from_colors(&[]), Mood::Unknown);
+        assert_eq!(Mood::from_colors(&[color(10, 10, 10, 1.0)]), Mood::Dark);
+        assert_eq!(Mood::from_colors(&[color(255, 182, 193, 1.0)]), Mood::Pastel);
+        assert_eq!(Mood::from_colors(&[color(255, 0, 0, 1.0)]), Mood::Vibrant);
+        assert_eq!(Mood::from_colors(&[color(128, 128, 128, 1.0)]), Mood::Muted);
+        // Mostly black with a little white is still dark
+        let colors = [color(0, 0, 0, 0.8), color(255, 255, 255, 0.2)];
+        assert_eq!(Mood::from_colors(&colors), Mood::Dark);
+    }
+
+    #[test]
+    fn fused_report_merges_labels_and_keeps_every_finding() {
+        let mut avatar = VisionReport::from_colors(
+            vec![scored("Cat", 0.8), scored("Hat", 0.6), scored("Grass", 0.5)],
+            vec![color(255, 0, 0, 1.0)],
+        );
+        avatar.text = vec!["clara".to_string()];
+        let mut banner = VisionReport::from_colors(vec![scored("hat", 0.6), scored("Beach", 0.7)], Vec::new());
+        banner.faces = 1;
+        banner.unsafe_categories = vec!["violence".to_string()];
+        banner.text = vec!["clara".to_string(), "2026".to_string()];
+
+        let fused = VisionReport::fuse(vec![avatar, banner]).unwrap();
+
+        // Seen twice, the hat outranks the cat, and the report keeps the avatar's three labels
+        let labels: Vec<&str> = fused.keywords.iter().map(|keyword| keyword.label.as_str()).collect();
+        assert_eq!(labels, ["Hat", "Cat", "Beach"]);
+        assert!((fused.keywords[0].score - 0.84).abs() < 1e-9);
+        assert_eq!(fused.mood, Mood::Vibrant);
+        assert_eq!(fused.colors.len(), 1);
+        assert_eq!(fused.faces, 1);
+        assert_eq!(fused.unsafe_categories, ["violence"]);
+        assert_eq!(fused.text, ["clara", "2026"]);
+        assert!(VisionReport::fuse(Vec::new()).is_none());
+    }
+
+    #[test]
+    fn unsafe_content_outranks_faces() {
+        let mut report = VisionReport::from_keywords(Vec::new());
+        assert_eq!(report.safety(), SafetyVerdict::Safe);
+
+        report.faces = 2;
+        assert_eq!(report.safety(), SafetyVerdict::Faces(2));
+
+        report.unsafe_categories = vec!["adult".to_string()];
+        assert_eq!(report.safety(), SafetyVerdict::Unsafe(vec!["adult".to_string()]));
+    }
+
+    #[test]
+    fn anonymized_report_drops_the_person() {
+        let keywords = ["Smiling man", "Cartoon", "Red hat", "Human face", "Eyewear"]
             .iter()
-            .map(|annotation| (&annotation.description[..], annotation.score))
+            .map(|label| scored(label, 0.9))
             .collect();
-        descriptions.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-        let sorted_descriptions: Vec<String> = descriptions.into_iter().map(|(desc, _)| desc.to_string()).collect();
 
-        Ok(sorted_descriptions)
+        let report = VisionReport::from_keywords(keywords).anonymized();
+
+        let labels: Vec<&str> = report.keywords.iter().map(|keyword| keyword.label.as_str()).collect();
+        assert_eq!(labels, ["Cartoon", "Red hat", "Eyewear"]);
+    }
+
+    #[test]
+    fn labels_repeating_the_text_are_dropped() {
+        let keywords = vec![scored("Clara", 0.9), scored("Cat", 0.8), scored("2026", 0.7)];
+        let mut report = VisionReport::from_keywords(keywords);
+        report.text = vec!["clara".to_string(), "2026".to_string()];
+
+        let report = report.without_text();
+
+        let labels: Vec<&str> = report.keywords.iter().map(|keyword| keyword.label.as_str()).collect();
+        assert_eq!(labels, ["Cat"]);
+        assert_eq!(report.text, ["clara", "2026"]);
+    }
+
+    #[test]
+    fn chain_falls_back_to_the_next_provider() {
+        let (chain, calls) = chain(&[
+            ("first", None),
+            ("second", Some(vec!["cat"])),
+            ("third", Some(vec!["dog"])),
+        ]);
+
+        let report = chain.create_report(request()).unwrap();
+
+        assert_eq!(report.provider, "second");
+        assert_eq!(report.keywords[0].label, "cat");
+        assert_eq!(*calls.lock().unwrap(), ["first", "second"]);
+    }
+
+    #[test]
+    fn chain_reports_the_last_error_when_every_provider_fails() {
+        let (chain, calls) = chain(&[("first", None), ("second", None)]);
+
+        let err = chain.create_desc(request()).unwrap_err();
+
+        assert_eq!(err.to_string(), "second is down");
+        assert_eq!(*calls.lock().unwrap(), ["first", "second"]);
+        assert!(VisionChain::new(Vec::new()).create_desc(request()).is_err());
     }
 }
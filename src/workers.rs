@@ -0,0 +1,114 @@
+// Import collections and synchronization handling
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
+
+// Import error handling
+use anyhow::{anyhow, Result};
+// Import async channels and locks
+use tokio::sync::{mpsc, Mutex as AsyncMutex, RwLock};
+// Import logging
+use tracing::{error, info};
+
+// Import the handler, metrics, supervisor and mention type
+use crate::{config, handler::Handler, metrics::metrics, supervisor::Supervisor, twitter::ExtractedTweet};
+
+// Default number of mentions handled at the same time
+pub const DEFAULT_MENTION_WORKERS: usize = 2;
+
+// Fixed set of workers handling mentions from a bounded channel. Submitting waits while every worker is busy, so
+// the poller never runs ahead of the workers. A worker that stops is restarted by the supervisor
+pub struct WorkerPool {
+    // Sending side of the channel the workers take mentions from
+    sender: mpsc::Sender<ExtractedTweet>,
+    // Tweet IDs submitted and not finished yet, so a mention is never handled twice at once
+    in_flight: Arc<Mutex<HashSet<String>>>,
+}
+
+impl WorkerPool {
+    // Start `size` workers sharing the handler under the supervisor
+    pub fn start(handler: Arc<RwLock<Handler>>, size: usize, supervisor: &mut Supervisor) -> Self {
+        let (sender, receiver) = mpsc::channel(size);
+        let receiver = Arc::new(AsyncMutex::new(receiver));
+        let in_flight = Arc::new(Mutex::new(HashSet::new()));
+        for worker in 0..size {
+            let (handler, receiver, in_flight) = (handler.clone(), receiver.clone(), in_flight.clone());
+            supervisor.spawn(format!("worker.{}", worker), move || {
+                work(worker, handler.clone(), receiver.clone(), in_flight.clone())
+            });
+        }
+        info!(workers = size, "Started mention workers");
+
+        Self { sender, in_flight }
+    }
+
+    // Hand a mention to the workers, waiting while all of them are busy. Mentions already being handled are
+    // skipped
+    pub async fn submit(&self, tweet: ExtractedTweet) -> Result<()> {
+        let id = match &tweet.id {
+            Some(id) => id.clone(),
+            None => return Ok(()),
+        };
+        if !self.in_flight.lock().unwrap().insert(id) {
+            return Ok(());
+        }
+
+        self.sender
+            .send(tweet)
+            .await
+            .map_err(|_| anyhow!("Mention workers stopped"))
+    }
+}
+
+// Read the number of workers from MENTION_WORKERS
+pub fn workers_from_env() -> Result<usize> {
+    let workers = match config::var("MENTION_WORKERS") {
+        Ok(value) if !value.is_empty() => value
+            .parse()
+            .map_err(|err| anyhow!("MENTION_WORKERS must be a whole number: {}", err))?,
+        _ => DEFAULT_MENTION_WORKERS,
+    };
+    if workers == 0 {
+        return Err(anyhow!("MENTION_WORKERS must be at least 1"));
+    }
+
+    Ok(workers)
+}
+
+// Handle mentions from the channel until it closes
+async fn work(
+    worker: usize,
+    handler: Arc<RwLock<Handler>>,
+    receiver: Arc<AsyncMutex<mpsc::Receiver<ExtractedTweet>>>,
+    in_flight: Arc<Mutex<HashSet<String>>>,
+) -> Result<()> {
+    loop {
+        let tweet = match receiver.lock().await.recv().await {
+            Some(tweet) => tweet,
+            None => return Ok(()),
+        };
+        let id = tweet.id.clone().unwrap_or_default();
+
+        // Each mention runs in its own task so a panic fails the mention instead of the worker
+        let job = {
+            let handler = handler.clone();
+            let tweet = tweet.clone();
+            tokio::spawn(async move { handler.read().await.process_mention(&tweet).await })
+        };
+        let result = match job.await {
+            Ok(result) => result,
+            Err(err) => {
+                metrics().increment("workers.panicked", 1);
+                let message = format!("Worker panicked: {}", err);
+                error!(worker, request_id = %id, "{}", message);
+                handler.read().await.mention_failed(&tweet, &message).await
+            }
+        };
+        if let Err(err) = result {
+            error!(worker, request_id = %id, error = ?err, "Failed to handle mention");
+        }
+
+        in_flight.lock().unwrap().remove(&id);
+    }
+}